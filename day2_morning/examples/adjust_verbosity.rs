@@ -0,0 +1,53 @@
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use day2_morning::logger::{AdjustableFilter, Logger, StdoutLogger, VerbosityHandle};
+
+// Usage: adjust_verbosity [CONTROL_FILE] [SECONDS]. Logs at every verbosity from 0 to 5 twice a second for SECONDS
+// (30 by default), while watching CONTROL_FILE (`verbosity` in the temp directory by default) and setting the
+// maximum verbosity to the number in it whenever it changes. Try `echo 5 > $TMPDIR/verbosity` from another shell.
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let control = args.next().map_or_else(|| std::env::temp_dir().join("verbosity"), PathBuf::from);
+    let seconds = args.next().map_or(30, |arg| arg.parse().expect("SECONDS is a number"));
+    let level = VerbosityHandle::new(2);
+    let logger = AdjustableFilter { max_verbosity: level.clone(), inner: StdoutLogger };
+    println!("watching {} for a new verbosity, starting at {}", control.display(), level.get());
+
+    let deadline = Instant::now() + Duration::from_secs(seconds);
+    thread::scope(|s| {
+        let logger = &logger;
+        s.spawn(move || {
+            // polling the modification time is crude, but needs nothing beyond std and works everywhere
+            let modified = || std::fs::metadata(&control).and_then(|m| m.modified()).ok();
+            let mut seen: Option<SystemTime> = modified();
+            while Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(200));
+                let now = modified();
+                if now == seen {
+                    continue;
+                }
+                seen = now;
+                match std::fs::read_to_string(&control).map(|s| s.trim().parse::<u8>()) {
+                    Ok(Ok(max_verbosity)) => {
+                        let previous = level.set(max_verbosity);
+                        logger.log(0, &format!("verbosity changed from {previous} to {max_verbosity}"));
+                    }
+                    Ok(Err(_)) => logger.log(1, "the control file should hold a number from 0 to 255"),
+                    Err(error) => logger.log(1, &format!("cannot read {}: {error}", control.display())),
+                }
+            }
+        });
+        s.spawn(move || {
+            let mut tick = 0;
+            while Instant::now() < deadline {
+                for verbosity in 0..=5 {
+                    logger.log_with(verbosity, "working", &[("tick", tick.into())]);
+                }
+                tick += 1;
+                thread::sleep(Duration::from_millis(500));
+            }
+        });
+    });
+}
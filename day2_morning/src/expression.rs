@@ -0,0 +1,85 @@
+#[derive(Debug)]
+pub enum Operation {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug)]
+pub enum Expression {
+    Op { op: Operation, left: Box<Expression>, right: Box<Expression> },
+    Value(i64),
+}
+
+// Deep trees would overflow the stack if dropped recursively, so subtrees are detached onto a
+// heap stack and dropped one shallow node at a time.
+impl Drop for Expression {
+    fn drop(&mut self) {
+        fn take_children(e: &mut Expression, pending: &mut Vec<Expression>) {
+            if let Expression::Op { left, right, .. } = e {
+                for child in [left, right] {
+                    if let Expression::Op { .. } = **child {
+                        pending.push(std::mem::replace(&mut **child, Expression::Value(0)));
+                    }
+                }
+            }
+        }
+        let mut pending = Vec::new();
+        take_children(self, &mut pending);
+        while let Some(mut e) = pending.pop() {
+            take_children(&mut e, &mut pending);
+        }
+    }
+}
+
+/// Panics on division by zero, and on overflow when overflow checks are enabled.
+pub fn eval(e: &Expression) -> i64 {
+    // an explicit work stack instead of recursion, so a million-deep chain doesn't overflow
+    enum Task<'a> {
+        Eval(&'a Expression),
+        Apply(&'a Operation),
+    }
+    let mut tasks = vec![Task::Eval(e)];
+    let mut values = Vec::new();
+    while let Some(task) = tasks.pop() {
+        match task {
+            Task::Eval(Expression::Value(v)) => values.push(*v),
+            Task::Eval(Expression::Op { op, left, right }) => {
+                tasks.push(Task::Apply(op));
+                tasks.push(Task::Eval(right));
+                tasks.push(Task::Eval(left));
+            }
+            Task::Apply(op) => {
+                let right_val = values.pop().unwrap();
+                let left_val = values.pop().unwrap();
+                values.push(match op {
+                    Operation::Add => left_val + right_val,
+                    Operation::Sub => left_val - right_val,
+                    Operation::Mul => left_val * right_val,
+                    Operation::Div => {
+                        if right_val == 0 {
+                            panic!("Division by zero!");
+                        }
+                        left_val / right_val
+                    }
+                });
+            }
+        }
+    }
+    values.pop().unwrap()
+}
+
+#[test]
+fn test_deep_expression() {
+    let mut expr = Expression::Value(0);
+    for i in 0..1_000_000 {
+        expr = Expression::Op {
+            op: if i % 2 == 0 { Operation::Add } else { Operation::Sub },
+            left: Box::new(expr),
+            right: Box::new(Expression::Value(i)),
+        };
+    }
+    assert_eq!(eval(&expr), -500_000);
+    drop(expr);
+}
@@ -0,0 +1,475 @@
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+
+pub mod background;
+pub mod capture;
+pub mod file;
+mod gzip;
+pub mod json;
+pub mod rate_limit;
+#[cfg(unix)]
+pub mod syslog;
+pub mod target;
+
+/// A value attached to a log record by key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value<'a> {
+    Str(&'a str),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+impl<'a> From<&'a str> for Value<'a> {
+    fn from(s: &'a str) -> Self {
+        Value::Str(s)
+    }
+}
+
+impl From<i64> for Value<'_> {
+    fn from(n: i64) -> Self {
+        Value::Int(n)
+    }
+}
+
+impl From<i32> for Value<'_> {
+    fn from(n: i32) -> Self {
+        Value::Int(n.into())
+    }
+}
+
+impl From<u32> for Value<'_> {
+    fn from(n: u32) -> Self {
+        Value::Int(n.into())
+    }
+}
+
+impl From<f64> for Value<'_> {
+    fn from(x: f64) -> Self {
+        Value::Float(x)
+    }
+}
+
+impl From<bool> for Value<'_> {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+// Strings are quoted only when they would otherwise be ambiguous in a `key=value` list.
+impl fmt::Display for Value<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Str(s) if s.is_empty() || s.contains(|c: char| c.is_whitespace() || c.is_control() || c == '=' || c == '"') => {
+                write!(f, "{s:?}")
+            }
+            Value::Str(s) => f.write_str(s),
+            Value::Int(n) => write!(f, "{n}"),
+            Value::Float(x) => write!(f, "{x}"),
+            Value::Bool(b) => write!(f, "{b}"),
+        }
+    }
+}
+
+pub type Field<'a> = (&'a str, Value<'a>);
+
+/// A `Value` that owns its string, to keep after the call that logged it returns.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OwnedValue {
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+impl OwnedValue {
+    pub fn as_value(&self) -> Value<'_> {
+        match self {
+            OwnedValue::Str(s) => Value::Str(s),
+            OwnedValue::Int(n) => Value::Int(*n),
+            OwnedValue::Float(x) => Value::Float(*x),
+            OwnedValue::Bool(b) => Value::Bool(*b),
+        }
+    }
+}
+
+impl From<Value<'_>> for OwnedValue {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Str(s) => OwnedValue::Str(s.to_string()),
+            Value::Int(n) => OwnedValue::Int(n),
+            Value::Float(x) => OwnedValue::Float(x),
+            Value::Bool(b) => OwnedValue::Bool(b),
+        }
+    }
+}
+
+/// One call to a logger: the message, how verbose it is, and any fields attached to it. The target says where
+/// it comes from, usually a module path like `net::tcp`, and is empty unless set.
+#[derive(Debug, Clone, Copy)]
+pub struct Record<'a> {
+    pub verbosity: u8,
+    pub target: &'a str,
+    pub message: &'a str,
+    pub fields: &'a [Field<'a>],
+}
+
+impl<'a> Record<'a> {
+    pub fn new(verbosity: u8, message: &'a str) -> Self {
+        Record { verbosity, target: "", message, fields: &[] }
+    }
+
+    pub fn with_target(self, target: &'a str) -> Self {
+        Record { target, ..self }
+    }
+
+    pub fn with_fields(self, fields: &'a [Field<'a>]) -> Self {
+        Record { fields, ..self }
+    }
+
+    pub fn field(&self, key: &str) -> Option<Value<'a>> {
+        self.fields.iter().find(|(k, _)| *k == key).map(|&(_, value)| value)
+    }
+}
+
+impl fmt::Display for Record<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "verbosity={}", self.verbosity)?;
+        if !self.target.is_empty() {
+            write!(f, " target={}", self.target)?;
+        }
+        write!(f, ": {}", self.message)?;
+        for (key, value) in self.fields {
+            write!(f, " {key}={value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A `Record` that owns its message and fields.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OwnedRecord {
+    pub verbosity: u8,
+    pub target: String,
+    pub message: String,
+    pub fields: Vec<(String, OwnedValue)>,
+}
+
+impl OwnedRecord {
+    pub fn field(&self, key: &str) -> Option<Value<'_>> {
+        self.fields.iter().find(|(k, _)| k == key).map(|(_, value)| value.as_value())
+    }
+
+    /// Borrows the record back as a `Record`, which needs its fields in a slice of their own.
+    pub fn with_record<T>(&self, f: impl FnOnce(&Record) -> T) -> T {
+        let fields: Vec<Field> = self.fields.iter().map(|(key, value)| (key.as_str(), value.as_value())).collect();
+        f(&Record { verbosity: self.verbosity, target: &self.target, message: &self.message, fields: &fields })
+    }
+}
+
+impl From<&Record<'_>> for OwnedRecord {
+    fn from(record: &Record) -> Self {
+        OwnedRecord {
+            verbosity: record.verbosity,
+            target: record.target.to_string(),
+            message: record.message.to_string(),
+            fields: record.fields.iter().map(|&(key, value)| (key.to_string(), value.into())).collect(),
+        }
+    }
+}
+
+impl fmt::Display for OwnedRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.with_record(|record| record.fmt(f))
+    }
+}
+
+/// Implementations provide `log_record`, or just `log` if they only need the verbosity and message, in which case
+/// targets and fields are dropped. Each defaults to calling the other, so one of them must be implemented.
+pub trait Logger {
+    fn log_record(&self, record: &Record) {
+        self.log(record.verbosity, record.message);
+    }
+
+    fn log(&self, verbosity: u8, message: &str) {
+        self.log_record(&Record::new(verbosity, message));
+    }
+
+    fn log_with(&self, verbosity: u8, message: &str, fields: &[Field]) {
+        self.log_record(&Record::new(verbosity, message).with_fields(fields));
+    }
+}
+
+pub struct StdoutLogger;
+
+impl Logger for StdoutLogger {
+    fn log_record(&self, record: &Record) {
+        println!("{record}");
+    }
+}
+
+pub struct VerbosityFilter<L: Logger> {
+    pub max_verbosity: u8,
+    pub inner: L,
+}
+
+impl<L: Logger> Logger for VerbosityFilter<L> {
+    fn log_record(&self, record: &Record) {
+        if record.verbosity <= self.max_verbosity {
+            self.inner.log_record(record);
+        }
+    }
+}
+
+/// A maximum verbosity that can be changed while loggers are using it. Clones share the same level, so one can be
+/// handed to any number of filters and another kept to adjust them all, from any thread.
+#[derive(Debug, Clone)]
+pub struct VerbosityHandle(Arc<AtomicU8>);
+
+impl VerbosityHandle {
+    pub fn new(max_verbosity: u8) -> Self {
+        VerbosityHandle(Arc::new(AtomicU8::new(max_verbosity)))
+    }
+
+    // Relaxed is enough: the level is the only thing shared, and filters that see a change a little late are fine.
+    pub fn get(&self) -> u8 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Sets the level and returns the previous one.
+    pub fn set(&self, max_verbosity: u8) -> u8 {
+        self.0.swap(max_verbosity, Ordering::Relaxed)
+    }
+}
+
+/// `VerbosityFilter` with a maximum verbosity that can change at runtime.
+pub struct AdjustableFilter<L: Logger> {
+    pub max_verbosity: VerbosityHandle,
+    pub inner: L,
+}
+
+impl<L: Logger> Logger for AdjustableFilter<L> {
+    fn log_record(&self, record: &Record) {
+        if record.verbosity <= self.max_verbosity.get() {
+            self.inner.log_record(record);
+        }
+    }
+}
+
+impl<L: Logger + ?Sized> Logger for &L {
+    fn log_record(&self, record: &Record) {
+        (**self).log_record(record);
+    }
+}
+
+impl<L: Logger + ?Sized> Logger for Box<L> {
+    fn log_record(&self, record: &Record) {
+        (**self).log_record(record);
+    }
+}
+
+impl<L: Logger + ?Sized> Logger for Rc<L> {
+    fn log_record(&self, record: &Record) {
+        (**self).log_record(record);
+    }
+}
+
+impl<L: Logger + ?Sized> Logger for Arc<L> {
+    fn log_record(&self, record: &Record) {
+        (**self).log_record(record);
+    }
+}
+
+/// Logs every record to both loggers, first to the one on the left.
+pub struct Tee<A: Logger, B: Logger>(pub A, pub B);
+
+impl<A: Logger, B: Logger> Logger for Tee<A, B> {
+    fn log_record(&self, record: &Record) {
+        self.0.log_record(record);
+        self.1.log_record(record);
+    }
+}
+
+/// Logs every record to each of a list of loggers chosen at runtime, in order.
+pub struct FanOut(pub Vec<Box<dyn Logger>>);
+
+impl Logger for FanOut {
+    fn log_record(&self, record: &Record) {
+        for logger in &self.0 {
+            logger.log_record(record);
+        }
+    }
+}
+
+type Route = (Box<dyn Fn(&Record) -> bool>, Box<dyn Logger>);
+
+/// Sends each record to the first logger whose route it matches, or to the fallback if it matches none:
+///
+/// ```text
+/// let router = Router::new().verbosity(0..=1, alerts).target("db", db_log).otherwise(StdoutLogger);
+/// ```
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    fallback: Option<Box<dyn Logger>>,
+}
+
+impl Router {
+    /// Without routes or a fallback, every record is dropped.
+    pub fn new() -> Self {
+        Router::default()
+    }
+
+    pub fn when(mut self, matches: impl Fn(&Record) -> bool + 'static, logger: impl Logger + 'static) -> Self {
+        self.routes.push((Box::new(matches), Box::new(logger)));
+        self
+    }
+
+    pub fn verbosity(self, verbosities: RangeInclusive<u8>, logger: impl Logger + 'static) -> Self {
+        self.when(move |record| verbosities.contains(&record.verbosity), logger)
+    }
+
+    /// Routes records whose target is `prefix` or below it, as in `TargetFilter`.
+    pub fn target(self, prefix: &str, logger: impl Logger + 'static) -> Self {
+        let prefix = prefix.to_string();
+        self.when(move |record| target::is_under(record.target, &prefix), logger)
+    }
+
+    pub fn otherwise(mut self, logger: impl Logger + 'static) -> Self {
+        self.fallback = Some(Box::new(logger));
+        self
+    }
+}
+
+impl Logger for Router {
+    fn log_record(&self, record: &Record) {
+        match self.routes.iter().find(|(matches, _)| matches(record)) {
+            Some((_, logger)) => logger.log_record(record),
+            None => {
+                if let Some(logger) = &self.fallback {
+                    logger.log_record(record);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct Lines(std::cell::RefCell<Vec<String>>);
+
+#[cfg(test)]
+impl Logger for Lines {
+    fn log_record(&self, record: &Record) {
+        self.0.borrow_mut().push(record.to_string());
+    }
+}
+
+#[test]
+fn test_fields() {
+    let record = Record {
+        verbosity: 2,
+        target: "",
+        message: "request failed",
+        fields: &[
+            ("path", "/index.html".into()),
+            ("status", 503.into()),
+            ("elapsed", 0.25.into()),
+            ("retry", true.into()),
+            ("reason", "upstream timed out".into()),
+            ("empty", "".into()),
+        ],
+    };
+    assert_eq!(
+        record.to_string(),
+        r#"verbosity=2: request failed path=/index.html status=503 elapsed=0.25 retry=true reason="upstream timed out" empty="""#
+    );
+    assert_eq!(record.field("status"), Some(Value::Int(503)));
+    assert_eq!(record.field("missing"), None);
+    assert_eq!(Record::new(5, "FYI").to_string(), "verbosity=5: FYI");
+    assert_eq!(Record::new(5, "FYI").with_target("net::tcp").to_string(), "verbosity=5 target=net::tcp: FYI");
+    assert_eq!(Value::Str("a=b\n").to_string(), r#""a=b\n""#);
+}
+
+#[test]
+fn test_message_only_loggers() {
+    #[derive(Default)]
+    struct Messages(std::cell::RefCell<Vec<String>>);
+    impl Logger for Messages {
+        fn log(&self, verbosity: u8, message: &str) {
+            self.0.borrow_mut().push(format!("{verbosity} {message}"));
+        }
+    }
+    let logger = VerbosityFilter { max_verbosity: 3, inner: Messages::default() };
+    logger.log(2, "Uhoh");
+    logger.log_with(3, "retrying", &[("attempt", 2.into())]);
+    logger.log_record(&Record::new(5, "FYI"));
+    assert_eq!(*logger.inner.0.borrow(), ["2 Uhoh", "3 retrying"]);
+}
+
+#[test]
+fn test_filter_passes_fields_through() {
+    let logger = VerbosityFilter { max_verbosity: 3, inner: Lines::default() };
+    logger.log_with(5, "FYI", &[("ignored", true.into())]);
+    logger.log_with(2, "Uhoh", &[("attempt", 3.into()), ("host", "db1".into())]);
+    logger.log(3, "plain");
+    assert_eq!(*logger.inner.0.borrow(), ["verbosity=2: Uhoh attempt=3 host=db1", "verbosity=3: plain"]);
+}
+
+#[test]
+fn test_combinators() {
+    let (errors, everything) = (Lines::default(), Lines::default());
+    let tee = Tee(VerbosityFilter { max_verbosity: 1, inner: &errors }, &everything);
+    tee.log(1, "Uhoh");
+    tee.log(3, "FYI");
+    assert_eq!(*errors.0.borrow(), ["verbosity=1: Uhoh"]);
+    assert_eq!(*everything.0.borrow(), ["verbosity=1: Uhoh", "verbosity=3: FYI"]);
+
+    let sinks: Vec<Rc<Lines>> = (0..3).map(|_| Rc::default()).collect();
+    let fan_out = FanOut(sinks.iter().map(|sink| Box::new(Rc::clone(sink)) as Box<dyn Logger>).collect());
+    fan_out.log_with(2, "to all", &[("n", 3.into())]);
+    assert!(sinks.iter().all(|sink| *sink.0.borrow() == ["verbosity=2: to all n=3"]));
+    FanOut(Vec::new()).log(1, "to none");
+
+    let (alerts, db, rest) = (Rc::new(Lines::default()), Rc::new(Lines::default()), Rc::new(Lines::default()));
+    let router = Router::new()
+        .verbosity(0..=1, Rc::clone(&alerts))
+        .target("db", Rc::clone(&db))
+        .when(|record| record.message.starts_with("drop"), FanOut(Vec::new()))
+        .otherwise(Rc::clone(&rest));
+    router.log_record(&Record::new(0, "down").with_target("db::pool"));
+    router.log_record(&Record::new(3, "slow query").with_target("db::pool"));
+    router.log_record(&Record::new(3, "timeout").with_target("dbx"));
+    router.log(4, "dropped");
+    router.log(2, "kept");
+    assert_eq!(*alerts.0.borrow(), ["verbosity=0 target=db::pool: down"]);
+    assert_eq!(*db.0.borrow(), ["verbosity=3 target=db::pool: slow query"]);
+    assert_eq!(*rest.0.borrow(), ["verbosity=3 target=dbx: timeout", "verbosity=2: kept"]);
+    Router::new().log(1, "nowhere");
+}
+
+#[test]
+fn test_adjustable_filter() {
+    let level = VerbosityHandle::new(1);
+    let sink = capture::CapturingLogger::new();
+    let filters: Vec<_> = (0..4).map(|_| AdjustableFilter { max_verbosity: level.clone(), inner: &sink }).collect();
+    filters[0].log(2, "hidden");
+    assert_eq!(level.set(3), 1);
+    filters[1].log(2, "shown");
+    std::thread::scope(|s| {
+        for filter in &filters {
+            s.spawn(move || {
+                for verbosity in 0..6 {
+                    filter.log(verbosity, "from a thread");
+                }
+            });
+        }
+    });
+    assert_eq!(sink.records().len(), 1 + 4 * 4);
+    level.set(0);
+    filters[3].log(1, "hidden again");
+    assert_eq!((level.get(), sink.containing("hidden").len()), (0, 0));
+}
@@ -0,0 +1,301 @@
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+
+use super::{Logger, OwnedRecord, Record};
+
+/// What `BackgroundLogger` does with a record when its queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Wait for the writer to make room, so nothing is lost but logging can stall.
+    Block,
+    /// Drop the record being logged.
+    DropNewest,
+    /// Drop the oldest queued record to make room for this one, or this one if every record counted against the
+    /// capacity is already being written.
+    DropOldest,
+}
+
+struct Queue {
+    records: VecDeque<OwnedRecord>,
+    // taken off the queue but not written yet, so `flush` waits for them too
+    writing: usize,
+    shutdown: bool,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    // signalled when records are queued or on shutdown, when room is made, and when everything is written
+    queued: Condvar,
+    room: Condvar,
+    idle: Condvar,
+    capacity: usize,
+    overflow: Overflow,
+    dropped: AtomicU64,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Hands records to `inner` on a background thread, through a queue of at most `capacity` records, so that
+/// logging doesn't wait on slow output unless the queue fills up and the overflow policy says to. Records the
+/// writer has taken but not finished writing count towards the capacity, so at most `capacity` are ever held.
+///
+/// Dropping the logger, like `shutdown`, writes out everything queued before returning.
+pub struct BackgroundLogger<L: Logger + Send + 'static> {
+    shared: Arc<Shared>,
+    writer: Option<JoinHandle<L>>,
+}
+
+impl<L: Logger + Send + 'static> BackgroundLogger<L> {
+    /// A capacity of 0 is treated as 1.
+    pub fn new(inner: L, capacity: usize, overflow: Overflow) -> Self {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue { records: VecDeque::new(), writing: 0, shutdown: false }),
+            queued: Condvar::new(),
+            room: Condvar::new(),
+            idle: Condvar::new(),
+            capacity: capacity.max(1),
+            overflow,
+            dropped: AtomicU64::new(0),
+        });
+        let writer = {
+            let shared = Arc::clone(&shared);
+            thread::spawn(move || write_all(&shared, inner))
+        };
+        BackgroundLogger { shared, writer: Some(writer) }
+    }
+
+    /// How many records have been dropped because the queue was full, because they were logged after shutdown, or
+    /// because the inner logger panicked writing them.
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Waits until every record logged so far has been written.
+    pub fn flush(&self) {
+        let mut queue = self.shared.lock();
+        while !queue.records.is_empty() || queue.writing > 0 {
+            queue = self.shared.idle.wait(queue).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Writes out everything queued, stops the background thread and gives back the inner logger.
+    pub fn shutdown(mut self) -> L {
+        let writer = self.stop().expect("only stopped once");
+        writer.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    }
+
+    fn stop(&mut self) -> Option<JoinHandle<L>> {
+        self.shared.lock().shutdown = true;
+        self.shared.queued.notify_all();
+        self.shared.room.notify_all();
+        self.writer.take()
+    }
+}
+
+fn write_all<L: Logger>(shared: &Shared, inner: L) -> L {
+    let mut queue = shared.lock();
+    loop {
+        if queue.records.is_empty() {
+            if queue.shutdown {
+                return inner;
+            }
+            queue = shared.queued.wait(queue).unwrap_or_else(PoisonError::into_inner);
+            continue;
+        }
+        // write in batches, so loggers wait on the lock once per batch rather than once per record
+        let batch = std::mem::take(&mut queue.records);
+        queue.writing = batch.len();
+        drop(queue);
+        for record in batch {
+            // a panic loses the record but not the writer, which `flush` and blocked loggers are waiting on
+            let written = panic::catch_unwind(AssertUnwindSafe(|| record.with_record(|record| inner.log_record(record))));
+            if written.is_err() {
+                shared.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        queue = shared.lock();
+        queue.writing = 0;
+        shared.room.notify_all();
+        if queue.records.is_empty() {
+            shared.idle.notify_all();
+        }
+    }
+}
+
+impl<L: Logger + Send + 'static> Logger for BackgroundLogger<L> {
+    fn log_record(&self, record: &Record) {
+        let record = OwnedRecord::from(record);
+        let mut queue = self.shared.lock();
+        while queue.records.len() + queue.writing >= self.shared.capacity && !queue.shutdown {
+            match self.shared.overflow {
+                Overflow::Block => queue = self.shared.room.wait(queue).unwrap_or_else(PoisonError::into_inner),
+                Overflow::DropNewest => {
+                    self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+                Overflow::DropOldest => {
+                    self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+                    if queue.records.pop_front().is_none() {
+                        return;
+                    }
+                }
+            }
+        }
+        if queue.shutdown {
+            self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        queue.records.push_back(record);
+        drop(queue);
+        self.shared.queued.notify_one();
+    }
+}
+
+impl<L: Logger + Send + 'static> Drop for BackgroundLogger<L> {
+    fn drop(&mut self) {
+        if let Some(writer) = self.stop() {
+            // the writer catches panics from the inner logger, so this only fails if it couldn't return
+            let _ = writer.join();
+        }
+    }
+}
+
+// Records messages, and holds each one until `gate` is free, telling `started` it's waiting.
+#[cfg(test)]
+struct Gated {
+    lines: Arc<Mutex<Vec<String>>>,
+    gate: Arc<Mutex<()>>,
+    started: std::sync::mpsc::Sender<()>,
+}
+
+#[cfg(test)]
+impl Logger for Gated {
+    fn log_record(&self, record: &Record) {
+        let _ = self.started.send(());
+        drop(self.gate.lock().unwrap());
+        self.lines.lock().unwrap().push(record.message.to_string());
+    }
+}
+
+// Logs "0" and waits until the writer is stuck on it, then fills the capacity of 3 with "1" and "2" and logs "3"
+// from another thread, giving back the lines written and the count of dropped records once everything is out.
+#[cfg(test)]
+fn overflow(policy: Overflow) -> (Vec<String>, u64, bool) {
+    let (lines, gate) = (Arc::new(Mutex::new(Vec::new())), Arc::new(Mutex::new(())));
+    let (started, start) = std::sync::mpsc::channel();
+    let held = gate.lock().unwrap();
+    let logger = BackgroundLogger::new(Gated { lines: Arc::clone(&lines), gate: Arc::clone(&gate), started }, 3, policy);
+    logger.log(1, "0");
+    start.recv().unwrap();
+    logger.log(1, "1");
+    logger.log(1, "2");
+    let returned = std::sync::atomic::AtomicBool::new(false);
+    let waited = thread::scope(|s| {
+        s.spawn(|| {
+            logger.log(1, "3");
+            returned.store(true, Ordering::SeqCst);
+        });
+        thread::sleep(std::time::Duration::from_millis(50));
+        let waited = !returned.load(Ordering::SeqCst);
+        drop(held);
+        waited
+    });
+    logger.flush();
+    let dropped = logger.dropped();
+    let lines = lines.lock().unwrap().clone();
+    (lines, dropped, waited)
+}
+
+#[test]
+fn test_overflow_policies() {
+    let (lines, dropped, waited) = overflow(Overflow::Block);
+    assert_eq!((lines, dropped), (vec!["0".into(), "1".into(), "2".into(), "3".into()], 0));
+    assert!(waited);
+    let (lines, dropped, _) = overflow(Overflow::DropNewest);
+    assert_eq!((lines, dropped), (vec!["0".into(), "1".into(), "2".into()], 1));
+    let (lines, dropped, _) = overflow(Overflow::DropOldest);
+    assert_eq!((lines, dropped), (vec!["0".into(), "2".into(), "3".into()], 1));
+}
+
+#[test]
+fn test_records_being_written_count_towards_capacity() {
+    for policy in [Overflow::DropNewest, Overflow::DropOldest] {
+        let (lines, gate) = (Arc::new(Mutex::new(Vec::new())), Arc::new(Mutex::new(())));
+        let (started, start) = std::sync::mpsc::channel();
+        let held = gate.lock().unwrap();
+        let logger = BackgroundLogger::new(Gated { lines: Arc::clone(&lines), gate: Arc::clone(&gate), started }, 1, policy);
+        logger.log(1, "0");
+        start.recv().unwrap();
+        // the queue is empty, but "0" fills the capacity until it's written
+        logger.log(1, "1");
+        drop(held);
+        logger.flush();
+        logger.log(1, "2");
+        logger.flush();
+        assert_eq!((lines.lock().unwrap().clone(), logger.dropped()), (vec!["0".into(), "2".into()], 1), "{policy:?}");
+    }
+}
+
+#[test]
+fn test_shutdown_drains_the_queue() {
+    let lines = Arc::new(Mutex::new(Vec::new()));
+    let (started, _start) = std::sync::mpsc::channel();
+    let inner = Gated { lines: Arc::clone(&lines), gate: Arc::new(Mutex::new(())), started };
+    let logger = BackgroundLogger::new(inner, 1000, Overflow::DropNewest);
+    thread::scope(|s| {
+        for t in 0..4 {
+            let logger = &logger;
+            s.spawn(move || {
+                for i in 0..100 {
+                    logger.log_with(2, &format!("{t} {i}"), &[("thread", t.into())]);
+                }
+            });
+        }
+    });
+    let inner = logger.shutdown();
+    assert_eq!(inner.lines.lock().unwrap().len(), 400);
+    // each thread's records stay in order
+    let lines = lines.lock().unwrap();
+    for t in 0..4 {
+        let mine: Vec<_> = lines.iter().filter(|line| line.starts_with(&format!("{t} "))).cloned().collect();
+        assert_eq!(mine, (0..100).map(|i| format!("{t} {i}")).collect::<Vec<_>>());
+    }
+
+    // dropping also writes everything out
+    let lines = Arc::new(Mutex::new(Vec::new()));
+    let (started, _start) = std::sync::mpsc::channel();
+    let logger = BackgroundLogger::new(Gated { lines: Arc::clone(&lines), gate: Arc::new(Mutex::new(())), started }, 10, Overflow::Block);
+    for i in 0..50 {
+        logger.log(1, &i.to_string());
+    }
+    drop(logger);
+    assert_eq!(lines.lock().unwrap().len(), 50);
+}
+
+#[test]
+fn test_inner_logger_panics() {
+    struct Fragile(Arc<Mutex<Vec<String>>>);
+    impl Logger for Fragile {
+        fn log_record(&self, record: &Record) {
+            assert_ne!(record.message, "boom", "cannot log this");
+            self.0.lock().unwrap().push(record.message.to_string());
+        }
+    }
+    let lines = Arc::new(Mutex::new(Vec::new()));
+    let logger = BackgroundLogger::new(Fragile(Arc::clone(&lines)), 1, Overflow::Block);
+    // with a queue of 1, these wait for the writer to get past the panic
+    for message in ["before", "boom", "after", "boom", "last"] {
+        logger.log(1, message);
+    }
+    logger.flush();
+    assert_eq!(*lines.lock().unwrap(), ["before", "after", "last"]);
+    assert_eq!(logger.dropped(), 2);
+    assert_eq!(logger.shutdown().0.lock().unwrap().len(), 3);
+}
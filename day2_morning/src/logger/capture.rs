@@ -0,0 +1,143 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use super::{Logger, OwnedRecord, Record};
+
+/// Keeps records in memory, for tests to assert on what was logged, or for a long-running process to dump its
+/// most recent logs when something fails.
+#[derive(Debug, Default)]
+pub struct CapturingLogger {
+    records: Mutex<VecDeque<OwnedRecord>>,
+    capacity: Option<usize>,
+    evicted: AtomicU64,
+}
+
+impl CapturingLogger {
+    /// Keeps every record.
+    pub fn new() -> Self {
+        CapturingLogger::default()
+    }
+
+    /// Keeps only the latest `capacity` records, evicting the oldest to make room.
+    pub fn bounded(capacity: usize) -> Self {
+        CapturingLogger { capacity: Some(capacity), ..CapturingLogger::default() }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, VecDeque<OwnedRecord>> {
+        self.records.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Every record kept, oldest first.
+    pub fn records(&self) -> Vec<OwnedRecord> {
+        self.lock().iter().cloned().collect()
+    }
+
+    pub fn messages(&self) -> Vec<String> {
+        self.lock().iter().map(|record| record.message.clone()).collect()
+    }
+
+    pub fn at_verbosity(&self, verbosity: u8) -> Vec<OwnedRecord> {
+        self.lock().iter().filter(|record| record.verbosity == verbosity).cloned().collect()
+    }
+
+    /// The records whose message contains `text`.
+    pub fn containing(&self, text: &str) -> Vec<OwnedRecord> {
+        self.lock().iter().filter(|record| record.message.contains(text)).cloned().collect()
+    }
+
+    /// Panics unless the messages kept are exactly `expected`, in order, listing what was logged instead.
+    #[track_caller]
+    pub fn expect_messages(&self, expected: &[&str]) {
+        let messages = self.messages();
+        if messages != expected {
+            let mut report = format!("expected {} messages:\n", expected.len());
+            for message in expected {
+                report.push_str(&format!("    {message:?}\n"));
+            }
+            report.push_str(&format!("but {} were logged:\n", messages.len()));
+            for message in &messages {
+                report.push_str(&format!("    {message:?}\n"));
+            }
+            panic!("{report}");
+        }
+    }
+
+    /// How many records were evicted to stay within the capacity.
+    pub fn evicted(&self) -> u64 {
+        self.evicted.load(Ordering::Relaxed)
+    }
+
+    /// Logs every record kept to `logger`, oldest first, and forgets them.
+    pub fn drain_into(&self, logger: &impl Logger) {
+        let records = std::mem::take(&mut *self.lock());
+        for record in records {
+            record.with_record(|record| logger.log_record(record));
+        }
+    }
+
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+}
+
+impl Logger for CapturingLogger {
+    fn log_record(&self, record: &Record) {
+        let mut records = self.lock();
+        if self.capacity == Some(0) {
+            self.evicted.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        if self.capacity.is_some_and(|capacity| records.len() >= capacity) {
+            records.pop_front();
+            self.evicted.fetch_add(1, Ordering::Relaxed);
+        }
+        records.push_back(record.into());
+    }
+}
+
+#[test]
+fn test_queries() {
+    let logger = CapturingLogger::new();
+    let filter = super::VerbosityFilter { max_verbosity: 3, inner: &logger };
+    filter.log(5, "FYI");
+    filter.log(2, "Uhoh");
+    filter.log_with(3, "retrying after Uhoh", &[("attempt", 2.into())]);
+    filter.log_record(&Record::new(2, "connected").with_target("db"));
+    logger.expect_messages(&["Uhoh", "retrying after Uhoh", "connected"]);
+    assert_eq!(logger.at_verbosity(2).len(), 2);
+    assert!(logger.at_verbosity(5).is_empty());
+    let retries = logger.containing("Uhoh");
+    assert_eq!(retries.iter().map(|r| r.verbosity).collect::<Vec<_>>(), [2, 3]);
+    assert_eq!(retries[1].field("attempt"), Some(super::Value::Int(2)));
+    assert_eq!(logger.records()[2].target, "db");
+    logger.clear();
+    logger.expect_messages(&[]);
+}
+
+#[test]
+#[should_panic(expected = "expected 1 messages:\n    \"Uhoh\"\nbut 2 were logged:\n    \"FYI\"\n    \"Uhoh\"\n")]
+fn test_expect_messages_reports_what_was_logged() {
+    let logger = CapturingLogger::new();
+    logger.log(5, "FYI");
+    logger.log(2, "Uhoh");
+    logger.expect_messages(&["Uhoh"]);
+}
+
+#[test]
+fn test_ring_buffer() {
+    let logger = CapturingLogger::bounded(3);
+    for i in 0..10 {
+        logger.log(1, &format!("step {i}"));
+    }
+    logger.expect_messages(&["step 7", "step 8", "step 9"]);
+    assert_eq!(logger.evicted(), 7);
+    let dump = CapturingLogger::new();
+    logger.drain_into(&dump);
+    dump.expect_messages(&["step 7", "step 8", "step 9"]);
+    logger.expect_messages(&[]);
+
+    let none = CapturingLogger::bounded(0);
+    none.log(1, "gone");
+    assert_eq!((none.records(), none.evicted()), (Vec::new(), 1));
+}
@@ -0,0 +1,258 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use super::{gzip, Logger, Record};
+
+struct State {
+    file: Option<File>,
+    written: u64,
+    opened: Instant,
+    failing: bool,
+}
+
+/// Appends records to a file, one line each, and rotates it once it reaches `max_bytes` or has been open for
+/// `interval`, whichever comes first. The current file is `path`; older ones are `path.1` (the newest) up to
+/// `path.{keep}`, gzipped to `path.1.gz` and so on with compression enabled.
+///
+/// Logging never panics: if the file can't be opened, written or rotated, the error and the record go to stderr
+/// instead, and the file is tried again on the next record.
+pub struct FileLogger {
+    path: PathBuf,
+    max_bytes: Option<u64>,
+    interval: Option<Duration>,
+    keep: usize,
+    compress: bool,
+    state: Mutex<State>,
+}
+
+impl FileLogger {
+    /// Logs to `path`, appending if it exists, and never rotates until configured to.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FileLogger {
+            path: path.into(),
+            max_bytes: None,
+            interval: None,
+            keep: 5,
+            compress: false,
+            state: Mutex::new(State { file: None, written: 0, opened: Instant::now(), failing: false }),
+        }
+    }
+
+    /// Starts a new file before a record would take the current one past `max_bytes`. A record longer than that on
+    /// its own still gets written, alone in its file.
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Starts a new file for the first record logged `interval` or more after the current one was opened.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = Some(interval);
+        self
+    }
+
+    /// How many rotated files to keep, 5 by default. With 0, rotated files are deleted.
+    pub fn with_keep(mut self, keep: usize) -> Self {
+        self.keep = keep;
+        self
+    }
+
+    pub fn with_compression(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The path of the `n`th newest rotated file, counting from 1.
+    pub fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{n}{}", if self.compress { ".gz" } else { "" }));
+        PathBuf::from(name)
+    }
+
+    fn open(&self, state: &mut State, now: Instant) -> io::Result<()> {
+        let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        state.written = file.metadata()?.len();
+        state.opened = now;
+        state.file = Some(file);
+        Ok(())
+    }
+
+    fn write(&self, state: &mut State, line: &str, now: Instant) -> io::Result<()> {
+        let len = line.len() as u64;
+        if state.file.is_none() {
+            self.open(state, now)?;
+        }
+        let too_big = self.max_bytes.is_some_and(|max| state.written + len > max);
+        let too_old = self.interval.is_some_and(|interval| now.duration_since(state.opened) >= interval);
+        if state.written > 0 && (too_big || too_old) {
+            state.file = None;
+            self.rotate()?;
+            self.open(state, now)?;
+        }
+        let result = state.file.as_mut().expect("just opened").write_all(line.as_bytes());
+        if result.is_err() {
+            // reopen next time, in case the file was removed or the disk was full
+            state.file = None;
+        }
+        result?;
+        state.written += len;
+        Ok(())
+    }
+
+    fn rotate(&self) -> io::Result<()> {
+        if self.keep == 0 {
+            return fs::remove_file(&self.path);
+        }
+        remove_if_exists(&self.rotated_path(self.keep))?;
+        for n in (1..self.keep).rev() {
+            let from = self.rotated_path(n);
+            if from.exists() {
+                fs::rename(from, self.rotated_path(n + 1))?;
+            }
+        }
+        if self.compress {
+            let compressed = gzip::compress(&fs::read(&self.path)?);
+            // written beside the target first, so a failure never leaves a truncated archive under its real name
+            let partial = self.rotated_path(1).with_extension("gz.partial");
+            fs::write(&partial, compressed)?;
+            fs::rename(partial, self.rotated_path(1))?;
+            fs::remove_file(&self.path)
+        } else {
+            fs::rename(&self.path, self.rotated_path(1))
+        }
+    }
+
+    fn log_at(&self, record: &Record, now: Instant) {
+        let line = format!("{record}\n");
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        match self.write(&mut state, &line, now) {
+            Ok(()) => state.failing = false,
+            Err(error) => {
+                // report the error once, rather than before every record while it lasts
+                if !state.failing {
+                    eprintln!("cannot log to {}: {error}", self.path.display());
+                    state.failing = true;
+                }
+                eprint!("{line}");
+            }
+        }
+    }
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+        _ => Ok(()),
+    }
+}
+
+impl Logger for FileLogger {
+    fn log_record(&self, record: &Record) {
+        self.log_at(record, Instant::now());
+    }
+}
+
+// A fresh, empty directory for each test.
+#[cfg(test)]
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("day2_morning_{name}_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn test_size_rotation() {
+    let dir = test_dir("size_rotation");
+    // each record is "verbosity=1: record N\n", 22 bytes, so two fit in 44 bytes but not in 43
+    let logger = FileLogger::new(dir.join("app.log")).with_max_bytes(44).with_keep(2);
+    for i in 0..4 {
+        logger.log(1, &format!("record {i}"));
+    }
+    assert_eq!(fs::read_to_string(logger.path()).unwrap(), "verbosity=1: record 2\nverbosity=1: record 3\n");
+    assert_eq!(fs::read_to_string(logger.rotated_path(1)).unwrap(), "verbosity=1: record 0\nverbosity=1: record 1\n");
+    logger.log(1, "record 4");
+    logger.log(1, "a record much longer than the limit on its own");
+    logger.log(1, "record 6");
+    // only two rotated files are kept, the newest first
+    assert_eq!(fs::read_to_string(logger.path()).unwrap(), "verbosity=1: record 6\n");
+    assert_eq!(fs::read_to_string(logger.rotated_path(1)).unwrap(), "verbosity=1: a record much longer than the limit on its own\n");
+    assert_eq!(fs::read_to_string(logger.rotated_path(2)).unwrap(), "verbosity=1: record 4\n");
+    assert!(!logger.rotated_path(3).exists());
+
+    // a new logger appends to what is there and counts it towards the limit
+    let logger = FileLogger::new(dir.join("app.log")).with_max_bytes(43).with_keep(0);
+    logger.log(1, "record 7");
+    assert_eq!(fs::read_to_string(logger.path()).unwrap(), "verbosity=1: record 7\n");
+    assert!(fs::read_to_string(dir.join("app.log.1")).unwrap().contains("longer"));
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_time_rotation() {
+    let dir = test_dir("time_rotation");
+    let logger = FileLogger::new(dir.join("app.log")).with_interval(Duration::from_secs(60)).with_compression(true);
+    let start = Instant::now();
+    let record = |message| Record::new(2, message);
+    logger.log_at(&record("first"), start);
+    logger.log_at(&record("just before the interval"), start + Duration::from_secs(59));
+    assert!(!logger.rotated_path(1).exists());
+    logger.log_at(&record("on the interval"), start + Duration::from_secs(60));
+    logger.log_at(&record("a while after"), start + Duration::from_secs(100));
+    logger.log_at(&record("much later"), start + Duration::from_secs(1000));
+    assert_eq!(fs::read_to_string(logger.path()).unwrap(), "verbosity=2: much later\n");
+    let rotated = |n| String::from_utf8(gzip::decompress(&fs::read(logger.rotated_path(n)).unwrap())).unwrap();
+    assert_eq!(rotated(1), "verbosity=2: on the interval\nverbosity=2: a while after\n");
+    assert_eq!(rotated(2), "verbosity=2: first\nverbosity=2: just before the interval\n");
+    assert!(!dir.join("app.log.1").exists());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_concurrent_writers() {
+    let dir = test_dir("concurrent_writers");
+    let logger = FileLogger::new(dir.join("app.log")).with_max_bytes(4096).with_keep(1000);
+    std::thread::scope(|s| {
+        for thread in 0..8 {
+            let logger = &logger;
+            s.spawn(move || {
+                for i in 0..500 {
+                    logger.log_with(3, "tick", &[("thread", thread.into()), ("i", i.into())]);
+                }
+            });
+        }
+    });
+    let mut lines = Vec::new();
+    for entry in fs::read_dir(&dir).unwrap() {
+        let path = entry.unwrap().path();
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.len() <= 4096, "{} has {} bytes", path.display(), contents.len());
+        lines.extend(contents.lines().map(String::from));
+    }
+    // every record arrives whole, exactly once
+    lines.sort();
+    let mut expected: Vec<_> = (0..8).flat_map(|t| (0..500).map(move |i| format!("verbosity=3: tick thread={t} i={i}"))).collect();
+    expected.sort();
+    assert_eq!(lines, expected);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_falls_back_to_stderr() {
+    let dir = test_dir("falls_back_to_stderr");
+    // a directory can't be opened for appending
+    let logger = FileLogger::new(&dir);
+    logger.log(1, "to stderr");
+    assert!(logger.state.lock().unwrap().failing);
+    let logger = FileLogger::new(dir.join("app.log"));
+    logger.log(1, "to the file");
+    assert!(!logger.state.lock().unwrap().failing);
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,210 @@
+// A small gzip encoder for rotated log files: LZ77 with hash chains, written as a single deflate block with the
+// fixed Huffman codes (RFC 1951, 3.2.6). Log files are repetitive enough that this gets most of the benefit of
+// a full encoder without the dynamic tables.
+
+const WINDOW: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: u32 = 15;
+
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut table = [0u32; 256];
+    for (n, entry) in table.iter_mut().enumerate() {
+        let mut c = n as u32;
+        for _ in 0..8 {
+            c = if c & 1 == 1 { 0xedb8_8320 ^ (c >> 1) } else { c >> 1 };
+        }
+        *entry = c;
+    }
+    !data.iter().fold(!0u32, |c, &b| table[((c ^ u32::from(b)) & 0xff) as usize] ^ (c >> 8))
+}
+
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    // Extra bits and headers go least significant bit first.
+    fn write(&mut self, value: u32, count: u32) {
+        self.bits |= u64::from(value) << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    // Huffman codes go most significant bit first.
+    fn write_code(&mut self, code: u32, count: u32) {
+        self.write(code.reverse_bits() >> (32 - count), count);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+}
+
+fn literal_or_length(w: &mut BitWriter, symbol: u32) {
+    match symbol {
+        0..=143 => w.write_code(0x30 + symbol, 8),
+        144..=255 => w.write_code(0x190 + symbol - 144, 9),
+        256..=279 => w.write_code(symbol - 256, 7),
+        _ => w.write_code(0xc0 + symbol - 280, 8),
+    }
+}
+
+fn matched(w: &mut BitWriter, length: usize, distance: usize) {
+    let code = LENGTH_BASE.iter().rposition(|&base| usize::from(base) <= length).expect("length is at least 3");
+    literal_or_length(w, 257 + code as u32);
+    w.write((length - usize::from(LENGTH_BASE[code])) as u32, LENGTH_EXTRA[code].into());
+    let code = DISTANCE_BASE.iter().rposition(|&base| usize::from(base) <= distance).expect("distance is at least 1");
+    w.write_code(code as u32, 5);
+    w.write((distance - usize::from(DISTANCE_BASE[code])) as u32, DISTANCE_EXTRA[code].into());
+}
+
+fn hash(data: &[u8]) -> usize {
+    let key = u32::from(data[0]) << 16 | u32::from(data[1]) << 8 | u32::from(data[2]);
+    (key.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+}
+
+fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut w = BitWriter { out: Vec::new(), bits: 0, count: 0 };
+    w.write(1, 1); // the final block
+    w.write(1, 2); // with fixed Huffman codes
+    // head[h] is the latest position + 1 whose next three bytes hash to h, and prev[i] the one before i
+    let mut head = vec![0usize; 1 << HASH_BITS];
+    let mut prev = vec![0usize; data.len()];
+    let insert = |i: usize, head: &mut [usize], prev: &mut [usize]| {
+        if i + MIN_MATCH <= data.len() {
+            let h = hash(&data[i..]);
+            prev[i] = head[h];
+            head[h] = i + 1;
+        }
+    };
+    let mut i = 0;
+    while i < data.len() {
+        let (mut best_length, mut best_distance) = (0, 0);
+        if i + MIN_MATCH <= data.len() {
+            let mut candidate = head[hash(&data[i..])];
+            let limit = (data.len() - i).min(MAX_MATCH);
+            for _ in 0..MAX_CHAIN {
+                if candidate == 0 || i - (candidate - 1) > WINDOW {
+                    break;
+                }
+                let start = candidate - 1;
+                let length = data[start..].iter().zip(&data[i..i + limit]).take_while(|(a, b)| a == b).count();
+                if length > best_length {
+                    (best_length, best_distance) = (length, i - start);
+                    if length == limit {
+                        break;
+                    }
+                }
+                candidate = prev[start];
+            }
+        }
+        if best_length >= MIN_MATCH {
+            matched(&mut w, best_length, best_distance);
+            for j in i..i + best_length {
+                insert(j, &mut head, &mut prev);
+            }
+            i += best_length;
+        } else {
+            literal_or_length(&mut w, data[i].into());
+            insert(i, &mut head, &mut prev);
+            i += 1;
+        }
+    }
+    literal_or_length(&mut w, 256);
+    w.finish()
+}
+
+/// `data` as a gzip member (RFC 1952), readable by `gzip -d`.
+pub(crate) fn compress(data: &[u8]) -> Vec<u8> {
+    // magic, deflate, no flags, no modification time, no extra flags, unknown OS
+    let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255];
+    out.extend(deflate(data));
+    out.extend(crc32(data).to_le_bytes());
+    out.extend((data.len() as u32).to_le_bytes());
+    out
+}
+
+// Decodes what `compress` produces, to check it round trips.
+#[cfg(test)]
+pub(crate) fn decompress(gz: &[u8]) -> Vec<u8> {
+    assert_eq!(gz[..4], [0x1f, 0x8b, 8, 0]);
+    let (mut pos, mut bits, mut count) = (10, 0u64, 0);
+    let mut read = |n: u32| {
+        while count < n {
+            bits |= u64::from(gz[pos]) << count;
+            pos += 1;
+            count += 8;
+        }
+        let value = (bits & ((1 << n) - 1)) as usize;
+        (bits, count) = (bits >> n, count - n);
+        value
+    };
+    assert_eq!((read(1), read(2)), (1, 1));
+    let mut out: Vec<u8> = Vec::new();
+    loop {
+        let mut code = (0..7).fold(0, |code, _| code << 1 | read(1));
+        let symbol = if code <= 23 {
+            256 + code
+        } else {
+            code = code << 1 | read(1);
+            match code {
+                0x30..=0xbf => code - 0x30,
+                0xc0..=0xc7 => 280 + code - 0xc0,
+                _ => 144 + (code << 1 | read(1)) - 0x190,
+            }
+        };
+        match symbol {
+            0..=255 => out.push(symbol as u8),
+            256 => break,
+            _ => {
+                let code = symbol - 257;
+                let length = usize::from(LENGTH_BASE[code]) + read(LENGTH_EXTRA[code].into());
+                let code = (0..5).fold(0, |code, _| code << 1 | read(1));
+                let distance = usize::from(DISTANCE_BASE[code]) + read(DISTANCE_EXTRA[code].into());
+                for _ in 0..length {
+                    out.push(out[out.len() - distance]);
+                }
+            }
+        }
+    }
+    out
+}
+
+#[test]
+fn test_gzip() {
+    use std::hash::{BuildHasher, BuildHasherDefault, DefaultHasher};
+
+    assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    assert_eq!(compress(b""), [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    let mut log = Vec::new();
+    for i in 0..2000 {
+        log.extend(format!("verbosity={}: request {i} served in {}ms\n", i % 4, i * 7 % 300).bytes());
+    }
+    // bytes that don't compress, from std's hasher rather than yet another hand-rolled generator
+    let hasher = BuildHasherDefault::<DefaultHasher>::default();
+    let noise: Vec<u8> = (0..5000).map(|i| hasher.hash_one(i) as u8).collect();
+    for data in [&log[..], &noise, b"a", b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa", &[0; 100_000]] {
+        let gz = compress(data);
+        assert_eq!(decompress(&gz), data);
+        assert_eq!(gz[gz.len() - 8..gz.len() - 4], crc32(data).to_le_bytes());
+    }
+    assert!(compress(&log).len() * 4 < log.len());
+}
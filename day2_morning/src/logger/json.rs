@@ -0,0 +1,358 @@
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{Logger, OwnedRecord, OwnedValue, Record, Value};
+
+/// Writes each record to stdout as one line of JSON, for log pipelines that ingest newline-delimited JSON:
+///
+/// ```text
+/// {"timestamp":"2024-05-01T12:30:00.250Z","verbosity":2,"message":"Uhoh","fields":{"attempt":3}}
+/// ```
+///
+/// `target` and `fields` are left out when empty. Floats that JSON can't represent are written as strings, like `"NaN"`.
+pub struct JsonLogger;
+
+impl Logger for JsonLogger {
+    fn log_record(&self, record: &Record) {
+        println!("{}", to_json_line(record, SystemTime::now()));
+    }
+}
+
+fn escape(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+// `time` in RFC 3339 form, in UTC to the millisecond; times before 1970 are clamped to it.
+pub(crate) fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, rest) = (secs / 86_400, secs % 86_400);
+    // the proleptic Gregorian calendar from a day count, in 400-year eras starting on March 1st
+    let days = days + 719_468;
+    let (era, day_of_era) = (days / 146_097, days % 146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        rest / 3600,
+        rest / 60 % 60,
+        rest % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// One record as a line of JSON, without the newline.
+pub fn to_json_line(record: &Record, time: SystemTime) -> String {
+    let mut out = format!("{{\"timestamp\":\"{}\",\"verbosity\":{},", timestamp(time), record.verbosity);
+    if !record.target.is_empty() {
+        out.push_str("\"target\":");
+        escape(record.target, &mut out);
+        out.push(',');
+    }
+    out.push_str("\"message\":");
+    escape(record.message, &mut out);
+    if !record.fields.is_empty() {
+        out.push_str(",\"fields\":{");
+        for (i, (key, value)) in record.fields.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            escape(key, &mut out);
+            out.push(':');
+            match value {
+                Value::Str(s) => escape(s, &mut out),
+                Value::Int(n) => {
+                    let _ = write!(out, "{n}");
+                }
+                // `{:?}` always has a '.' or an exponent, so the reader can tell 1.0 from 1
+                Value::Float(x) if x.is_finite() => {
+                    let _ = write!(out, "{x:?}");
+                }
+                Value::Float(x) => escape(&x.to_string(), &mut out),
+                Value::Bool(b) => {
+                    let _ = write!(out, "{b}");
+                }
+            }
+        }
+        out.push('}');
+    }
+    out.push('}');
+    out
+}
+
+/// A record read back from a JSON line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonRecord {
+    pub timestamp: Option<String>,
+    pub record: OwnedRecord,
+}
+
+#[derive(Debug)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    // arrays only ever get skipped, so their items aren't kept
+    Array,
+    Object(Vec<(String, Json)>),
+}
+
+// Deeper lines are rejected, so that a hostile one can't overflow the stack of the recursive reader.
+const MAX_DEPTH: usize = 64;
+
+struct Reader<'a> {
+    rest: &'a str,
+}
+
+impl Reader<'_> {
+    fn skip_whitespace(&mut self) {
+        self.rest = self.rest.trim_start();
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        let ate = self.rest.starts_with(c);
+        if ate {
+            self.rest = &self.rest[1..];
+        }
+        ate
+    }
+
+    // `depth` is how many arrays and objects the value is nested in.
+    fn value(&mut self, depth: usize) -> Option<Json> {
+        self.skip_whitespace();
+        let c = self.rest.chars().next()?;
+        if (c == '{' || c == '[') && depth == MAX_DEPTH {
+            return None;
+        }
+        Some(match c {
+            '{' => {
+                self.rest = &self.rest[1..];
+                let mut fields = Vec::new();
+                if !self.eat('}') {
+                    loop {
+                        self.skip_whitespace();
+                        let Json::String(key) = self.value(depth + 1)? else {
+                            return None;
+                        };
+                        if !self.eat(':') {
+                            return None;
+                        }
+                        fields.push((key, self.value(depth + 1)?));
+                        if self.eat('}') {
+                            break;
+                        }
+                        if !self.eat(',') {
+                            return None;
+                        }
+                    }
+                }
+                Json::Object(fields)
+            }
+            '[' => {
+                self.rest = &self.rest[1..];
+                if !self.eat(']') {
+                    loop {
+                        self.value(depth + 1)?;
+                        if self.eat(']') {
+                            break;
+                        }
+                        if !self.eat(',') {
+                            return None;
+                        }
+                    }
+                }
+                Json::Array
+            }
+            '"' => Json::String(self.string()?),
+            _ => {
+                let len = self.rest.find(|c: char| !(c.is_ascii_alphanumeric() || "+-.".contains(c))).unwrap_or(self.rest.len());
+                let (word, rest) = self.rest.split_at(len);
+                self.rest = rest;
+                match word {
+                    "null" => Json::Null,
+                    "true" => Json::Bool(true),
+                    "false" => Json::Bool(false),
+                    _ if word.parse::<f64>().is_ok() && word.starts_with(|c: char| c == '-' || c.is_ascii_digit()) => {
+                        Json::Number(word.to_string())
+                    }
+                    _ => return None,
+                }
+            }
+        })
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let mut chars = self.rest[1..].char_indices();
+        let mut s = String::new();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.rest = &self.rest[i + 2..];
+                    return Some(s);
+                }
+                '\\' => s.push(match chars.next()?.1 {
+                    'n' => '\n',
+                    'r' => '\r',
+                    't' => '\t',
+                    'b' => '\u{8}',
+                    'f' => '\u{c}',
+                    'u' => {
+                        let hex = |chars: &mut std::str::CharIndices| -> Option<u32> {
+                            let digits: String = (0..4).map(|_| chars.next().map(|(_, c)| c)).collect::<Option<_>>()?;
+                            u32::from_str_radix(&digits, 16).ok()
+                        };
+                        let unit = hex(&mut chars)?;
+                        let code = if (0xd800..0xdc00).contains(&unit) {
+                            if (chars.next()?.1, chars.next()?.1) != ('\\', 'u') {
+                                return None;
+                            }
+                            0x10000 + ((unit - 0xd800) << 10) + (hex(&mut chars)?.checked_sub(0xdc00).filter(|low| *low < 0x400)?)
+                        } else {
+                            unit
+                        };
+                        // a lone surrogate can't be a char; keep the line rather than dropping it
+                        char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER)
+                    }
+                    c => c,
+                }),
+                c => s.push(c),
+            }
+        }
+        None
+    }
+}
+
+fn field_value(json: Json) -> Option<OwnedValue> {
+    Some(match json {
+        Json::String(s) => OwnedValue::Str(s),
+        Json::Bool(b) => OwnedValue::Bool(b),
+        Json::Number(n) if !n.contains(['.', 'e', 'E']) && n.parse::<i64>().is_ok() => OwnedValue::Int(n.parse().ok()?),
+        Json::Number(n) => OwnedValue::Float(n.parse().ok()?),
+        Json::Null | Json::Array | Json::Object(_) => return None,
+    })
+}
+
+/// Reads a line written by `JsonLogger`, leniently: keys may come in any order, unknown keys are ignored, and so
+/// are fields whose values aren't strings, numbers or booleans. Only a `verbosity` from 0 to 255 and a `message`
+/// are required, and anything else gives `None`, as do arrays and objects nested more than 64 deep.
+pub fn parse_line(line: &str) -> Option<JsonRecord> {
+    let mut reader = Reader { rest: line };
+    let Json::Object(object) = reader.value(0)? else {
+        return None;
+    };
+    reader.skip_whitespace();
+    if !reader.rest.is_empty() {
+        return None;
+    }
+    let (mut timestamp, mut verbosity, mut target, mut message, mut fields) = (None, None, String::new(), None, Vec::new());
+    for (key, value) in object {
+        match (key.as_str(), value) {
+            ("timestamp", Json::String(s)) => timestamp = Some(s),
+            ("verbosity", Json::Number(n)) => verbosity = n.parse::<u8>().ok(),
+            ("target", Json::String(s)) => target = s,
+            ("message", Json::String(s)) => message = Some(s),
+            ("fields", Json::Object(object)) => {
+                fields = object.into_iter().filter_map(|(key, value)| Some((key, field_value(value)?))).collect();
+            }
+            _ => {}
+        }
+    }
+    Some(JsonRecord { timestamp, record: OwnedRecord { verbosity: verbosity?, target, message: message?, fields } })
+}
+
+/// Every record in `text`, skipping blank lines and lines that aren't records, such as other output mixed in.
+pub fn read_records(text: &str) -> Vec<JsonRecord> {
+    text.lines().filter_map(parse_line).collect()
+}
+
+#[test]
+fn test_timestamp() {
+    use std::time::Duration;
+    let at = |secs, millis| timestamp(UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_millis(millis));
+    assert_eq!(at(0, 0), "1970-01-01T00:00:00.000Z");
+    assert_eq!(at(951_782_400, 5), "2000-02-29T00:00:00.005Z");
+    assert_eq!(at(1_714_566_600, 250), "2024-05-01T12:30:00.250Z");
+    assert_eq!(at(4_107_542_399, 999), "2100-02-28T23:59:59.999Z");
+    assert_eq!(timestamp(UNIX_EPOCH - Duration::from_secs(1)), "1970-01-01T00:00:00.000Z");
+}
+
+#[test]
+fn test_json_lines() {
+    let time = UNIX_EPOCH + std::time::Duration::from_millis(1_714_566_600_250);
+    let fields = [("attempt", 3.into())];
+    let record = Record::new(2, "Uhoh").with_fields(&fields);
+    assert_eq!(
+        to_json_line(&record, time),
+        r#"{"timestamp":"2024-05-01T12:30:00.250Z","verbosity":2,"message":"Uhoh","fields":{"attempt":3}}"#
+    );
+    assert_eq!(
+        to_json_line(&Record::new(5, "say \"hi\"\n\tC:\\ \u{1} é"), time),
+        r#"{"timestamp":"2024-05-01T12:30:00.250Z","verbosity":5,"message":"say \"hi\"\n\tC:\\ \u0001 é"}"#
+    );
+    let fields = [
+        ("s", "a \"quoted\"\nline".into()),
+        ("n", i64::MIN.into()),
+        ("whole", 1.0.into()),
+        ("x", 1e300.into()),
+        ("nan", f64::NAN.into()),
+        ("b", false.into()),
+    ];
+    let record = Record::new(255, "every kind").with_target("db::pool").with_fields(&fields);
+    let line = to_json_line(&record, time);
+    let read = parse_line(&line).unwrap();
+    assert_eq!(read.timestamp.as_deref(), Some("2024-05-01T12:30:00.250Z"));
+    assert_eq!((read.record.target.as_str(), read.record.message.as_str()), ("db::pool", "every kind"));
+    assert_eq!(read.record.field("s"), Some(Value::Str("a \"quoted\"\nline")));
+    assert_eq!(read.record.field("n"), Some(Value::Int(i64::MIN)));
+    assert_eq!(read.record.field("whole"), Some(Value::Float(1.0)));
+    assert_eq!(read.record.field("x"), Some(Value::Float(1e300)));
+    assert_eq!(read.record.field("nan"), Some(Value::Str("NaN")));
+    assert_eq!(read.record.field("b"), Some(Value::Bool(false)));
+}
+
+#[test]
+fn test_tolerant_reader() {
+    let text = "\
+        {\"verbosity\":2,\"message\":\"Uhoh\"}\n\
+        \n\
+        starting server on port 8080\n\
+        { \"message\" : \"\\u00e9\\ud83d\\ude00\", \"extra\": [1, {\"a\": null}], \"verbosity\" : 4 ,\
+          \"fields\": {\"nested\": {\"a\": 1}, \"ok\": true, \"none\": null} }\r\n\
+        {\"verbosity\":300,\"message\":\"out of range\"}\n\
+        {\"verbosity\":1}\n\
+        {\"verbosity\":1,\"message\":\"truncated\"\n\
+        {\"verbosity\":1,\"message\":\"trailing\"} junk\n";
+    let records = read_records(text);
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].record, OwnedRecord { verbosity: 2, target: String::new(), message: String::from("Uhoh"), fields: Vec::new() });
+    assert_eq!(records[0].timestamp, None);
+    assert_eq!(records[1].record.message, "é😀");
+    assert_eq!(records[1].record.fields, [(String::from("ok"), OwnedValue::Bool(true))]);
+    assert_eq!(parse_line("[]"), None);
+    // nesting is capped rather than overflowing the stack
+    let nested = |depth| format!("{{\"verbosity\":1,\"message\":\"deep\",\"extra\":{}1{}}}", "[".repeat(depth), "]".repeat(depth));
+    assert!(parse_line(&nested(MAX_DEPTH - 1)).is_some());
+    assert_eq!(parse_line(&nested(MAX_DEPTH)), None);
+    assert_eq!(parse_line(&nested(1_000_000)), None);
+    assert_eq!(parse_line(&"{\"a\":".repeat(1_000_000)), None);
+}
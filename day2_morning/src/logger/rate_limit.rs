@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use super::{Logger, Record};
+
+// Records are limited by their verbosity, target and message together; their fields don't count.
+type Key = (u8, String, String);
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+    suppressed: u64,
+    // when the first record suppressed since the last summary was
+    since: Instant,
+    // when a record last came through, let through or not
+    seen: Instant,
+}
+
+struct State {
+    buckets: HashMap<Key, Bucket>,
+    // the earliest a summary can be due, or idle buckets forgotten
+    next_sweep: Instant,
+}
+
+/// Lets through at most `burst` records with the same message at once, refilling at `per_second`, and drops the
+/// rest. What it drops is summarised once `window` has passed since the first of them, as one record with the
+/// message `"Uhoh (repeated 512 times)"`.
+///
+/// A message is forgotten once nothing with it has been dropped for a whole window and it either has its full
+/// burst back or hasn't been logged for a window either, so the limiter's memory doesn't grow with every message
+/// it has ever seen, even when `per_second` is 0. A forgotten message starts again with a full burst.
+///
+/// A summary is logged as soon as something notices its window has closed: the next record to come through, a call
+/// to `tick`, or the thread started by `sweep`. Without the last two, a summary waits for more traffic, so an owner
+/// whose logging may go quiet should use one of them. `flush`, or dropping the limiter, logs any summaries still
+/// pending.
+pub struct RateLimiter<L: Logger> {
+    inner: L,
+    burst: f64,
+    per_second: f64,
+    window: Duration,
+    state: Mutex<State>,
+}
+
+impl<L: Logger> RateLimiter<L> {
+    /// Summarises dropped records every second, until configured otherwise.
+    pub fn new(inner: L, burst: u32, per_second: f64) -> Self {
+        let window = Duration::from_secs(1);
+        let state = State { buckets: HashMap::new(), next_sweep: Instant::now() + window };
+        RateLimiter { inner, burst: burst.into(), per_second, window, state: Mutex::new(state) }
+    }
+
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    pub fn inner(&self) -> &L {
+        &self.inner
+    }
+
+    /// Logs the summaries whose window has closed, without waiting for another record to come through.
+    pub fn tick(&self) {
+        self.log_summaries(Some(Instant::now()));
+    }
+
+    /// Logs a summary for every message dropped since its last one, however recently.
+    pub fn flush(&self) {
+        self.log_summaries(None);
+    }
+
+    fn refill(&self, bucket: &mut Bucket, now: Instant) {
+        let elapsed = now.saturating_duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.burst);
+        bucket.refilled = now;
+    }
+
+    // Logs the summaries that are due by `now`, or all of them, and forgets buckets that have filled up again or
+    // gone a window without a record.
+    fn log_summaries(&self, now: Option<Instant>) {
+        let mut summaries = Vec::new();
+        {
+            let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            let refill_time = now.unwrap_or_else(Instant::now);
+            let mut next_sweep = refill_time + self.window;
+            state.buckets.retain(|(verbosity, target, message), bucket| {
+                if bucket.suppressed > 0 {
+                    if now.is_none_or(|now| now >= bucket.since + self.window) {
+                        let times = if bucket.suppressed == 1 { "time" } else { "times" };
+                        summaries.push((*verbosity, target.clone(), format!("{message} (repeated {} {times})", bucket.suppressed)));
+                        bucket.suppressed = 0;
+                    } else {
+                        next_sweep = next_sweep.min(bucket.since + self.window);
+                    }
+                }
+                self.refill(bucket, refill_time);
+                let idle = refill_time >= bucket.seen + self.window;
+                bucket.suppressed > 0 || (bucket.tokens < self.burst && !idle)
+            });
+            state.next_sweep = next_sweep;
+        }
+        for (verbosity, target, message) in summaries {
+            self.inner.log_record(&Record::new(verbosity, &message).with_target(&target));
+        }
+    }
+
+    fn log_at(&self, record: &Record, now: Instant) {
+        if now >= self.state.lock().unwrap_or_else(PoisonError::into_inner).next_sweep {
+            self.log_summaries(Some(now));
+        }
+        let allowed = {
+            let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            let key = (record.verbosity, record.target.to_string(), record.message.to_string());
+            let bucket = state.buckets.entry(key).or_insert(Bucket { tokens: self.burst, refilled: now, suppressed: 0, since: now, seen: now });
+            self.refill(bucket, now);
+            bucket.seen = now;
+            if bucket.tokens >= 1.0 {
+                bucket.tokens -= 1.0;
+                true
+            } else {
+                if bucket.suppressed == 0 {
+                    bucket.since = now;
+                }
+                bucket.suppressed += 1;
+                let due = bucket.since + self.window;
+                state.next_sweep = state.next_sweep.min(due);
+                false
+            }
+        };
+        if allowed {
+            self.inner.log_record(record);
+        }
+    }
+}
+
+impl<L: Logger + Send + Sync + 'static> RateLimiter<L> {
+    /// Starts a thread that calls `tick` whenever a summary is due, so summaries go out on time however quiet
+    /// logging gets. The thread stops within a window of the last reference to the limiter being dropped.
+    pub fn sweep(limiter: &Arc<Self>) -> JoinHandle<()> {
+        let limiter = Arc::downgrade(limiter);
+        thread::spawn(move || {
+            while let Some(limiter) = limiter.upgrade() {
+                limiter.tick();
+                let next_sweep = limiter.state.lock().unwrap_or_else(PoisonError::into_inner).next_sweep;
+                let wait = next_sweep.saturating_duration_since(Instant::now()).min(limiter.window);
+                // without keeping the limiter alive while waiting
+                drop(limiter);
+                thread::sleep(wait);
+            }
+        })
+    }
+}
+
+impl<L: Logger> Logger for RateLimiter<L> {
+    fn log_record(&self, record: &Record) {
+        self.log_at(record, Instant::now());
+    }
+}
+
+impl<L: Logger> Drop for RateLimiter<L> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[test]
+fn test_token_bucket() {
+    use super::capture::CapturingLogger;
+    let limiter = RateLimiter::new(CapturingLogger::new(), 2, 1.0);
+    let start = Instant::now();
+    let at = |millis| start + Duration::from_millis(millis);
+    for _ in 0..514 {
+        limiter.log_at(&Record::new(2, "Uhoh"), at(0));
+    }
+    limiter.log_at(&Record::new(2, "Uhoh").with_target("db"), at(0));
+    limiter.log_at(&Record::new(3, "Uhoh"), at(0));
+    limiter.log_at(&Record::new(2, "Uhoh"), at(500));
+    limiter.log_at(&Record::new(2, "Uhoh"), at(999));
+    limiter.inner().expect_messages(&["Uhoh", "Uhoh", "Uhoh", "Uhoh"]);
+    // a second after the first was dropped, it's summarised, and a token has come back
+    limiter.log_at(&Record::new(2, "Uhoh"), at(1000));
+    limiter.inner().expect_messages(&["Uhoh", "Uhoh", "Uhoh", "Uhoh", "Uhoh (repeated 514 times)", "Uhoh"]);
+    limiter.log_at(&Record::new(2, "Uhoh"), at(1200));
+    limiter.log_at(&Record::new(2, "Uhoh"), at(1300));
+    let inner = &limiter.inner;
+    let summaries: Vec<_> = inner.records().into_iter().filter(|r| r.message.contains("repeated")).collect();
+    assert_eq!(summaries.len(), 1);
+    assert_eq!((summaries[0].verbosity, summaries[0].target.as_str()), (2, ""));
+    limiter.flush();
+    assert_eq!(inner.messages().last().map(String::as_str), Some("Uhoh (repeated 2 times)"));
+    limiter.flush();
+    assert_eq!(inner.containing("repeated").len(), 2);
+}
+
+#[test]
+fn test_forgets_idle_messages() {
+    use super::capture::CapturingLogger;
+    // nothing refills, so only going idle lets a bucket be forgotten
+    let limiter = RateLimiter::new(CapturingLogger::new(), 1, 0.0);
+    let start = Instant::now();
+    let at = |millis| start + Duration::from_millis(millis);
+    for i in 0..1000 {
+        limiter.log_at(&Record::new(2, &format!("request {i} failed")), at(0));
+    }
+    assert_eq!(limiter.inner().messages().len(), 1000);
+    limiter.log_at(&Record::new(2, "request 0 failed"), at(10));
+    limiter.log_at(&Record::new(2, "still busy"), at(500));
+    limiter.log_at(&Record::new(2, "still busy"), at(900));
+    assert_eq!(limiter.state.lock().unwrap().buckets.len(), 1001);
+    // a window after the last "request 0 failed", its summary goes out and it's forgotten with the rest, apart
+    // from "still busy", which is still holding one back
+    limiter.log_at(&Record::new(2, "request 1 failed"), at(1010));
+    assert_eq!(limiter.state.lock().unwrap().buckets.len(), 2);
+    // so "request 1 failed" had its burst back
+    let latest = |n| limiter.inner().messages().split_off(limiter.inner().messages().len() - n);
+    assert_eq!(latest(3), ["still busy", "request 0 failed (repeated 1 time)", "request 1 failed"]);
+    // and again once it has gone a window without coming through
+    limiter.log_at(&Record::new(2, "request 1 failed"), at(2010));
+    assert_eq!(latest(2), ["still busy (repeated 1 time)", "request 1 failed"]);
+    assert_eq!(limiter.state.lock().unwrap().buckets.len(), 1);
+}
+
+#[test]
+fn test_wraps_any_logger() {
+    use super::capture::CapturingLogger;
+    use super::VerbosityFilter;
+    let sink = CapturingLogger::new();
+    {
+        let limiter = VerbosityFilter { max_verbosity: 3, inner: RateLimiter::new(&sink, 1, 0.0).with_window(Duration::from_secs(3600)) };
+        for _ in 0..1000 {
+            limiter.log(2, "Uhoh");
+            limiter.log(5, "FYI");
+        }
+        let limited = RateLimiter::new(VerbosityFilter { max_verbosity: 3, inner: &sink }, 1, 0.0);
+        limited.log(2, "inside");
+        limited.log(2, "inside");
+        limited.log(5, "FYI");
+        sink.expect_messages(&["Uhoh", "inside"]);
+    }
+    // dropping the limiters logs what they were still holding back
+    sink.expect_messages(&["Uhoh", "inside", "inside (repeated 1 time)", "Uhoh (repeated 999 times)"]);
+}
+
+#[test]
+fn test_summaries_without_further_traffic() {
+    use super::capture::CapturingLogger;
+    let limiter = RateLimiter::new(CapturingLogger::new(), 1, 0.0);
+    let start = Instant::now();
+    for _ in 0..3 {
+        limiter.log_at(&Record::new(2, "Uhoh"), start);
+    }
+    limiter.log_summaries(Some(start + Duration::from_millis(999)));
+    limiter.inner().expect_messages(&["Uhoh"]);
+    // once the window closes, the summary goes out with nothing else logged
+    limiter.log_summaries(Some(start + Duration::from_secs(1)));
+    limiter.inner().expect_messages(&["Uhoh", "Uhoh (repeated 2 times)"]);
+
+    let limiter = Arc::new(RateLimiter::new(CapturingLogger::new(), 1, 0.0).with_window(Duration::from_millis(20)));
+    let sweeper = RateLimiter::sweep(&limiter);
+    for _ in 0..3 {
+        limiter.log(2, "Uhoh");
+    }
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while limiter.inner().containing("repeated").is_empty() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(5));
+    }
+    limiter.inner().expect_messages(&["Uhoh", "Uhoh (repeated 2 times)"]);
+    drop(limiter);
+    sweeper.join().unwrap();
+}
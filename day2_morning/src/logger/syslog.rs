@@ -0,0 +1,197 @@
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+
+use super::json::timestamp;
+use super::{Logger, Record, Value};
+
+// The enterprise number RFC 5612 sets aside for documentation, for the structured data element holding fields.
+const FIELDS_ID: &str = "fields@32473";
+
+/// The syslog severity for a verbosity: 0 is an error, 1 a warning, 2 a notice, 3 informational, and anything more
+/// verbose is debugging. Nothing maps to the emergency, alert or critical severities.
+pub fn severity(verbosity: u8) -> u8 {
+    match verbosity {
+        0 => 3,
+        1 => 4,
+        2 => 5,
+        3 => 6,
+        _ => 7,
+    }
+}
+
+// A header field as RFC 5424 allows it: printable ASCII without spaces, at most `max` characters, or "-" if empty.
+fn header_field(s: &str, max: usize) -> String {
+    let field: String = s.chars().map(|c| if c.is_ascii_graphic() { c } else { '_' }).take(max).collect();
+    if field.is_empty() { String::from("-") } else { field }
+}
+
+/// Sends records to the local syslog daemon as RFC 5424 messages, one datagram each:
+///
+/// ```text
+/// <13>1 2024-05-01T12:30:00.250Z web1 server 4242 db::pool [fields@32473 attempt="3"] Uhoh
+/// ```
+///
+/// The target becomes the MSGID, and the fields a structured data element. If the socket can't be reached, the
+/// error and the record go to stderr instead.
+pub struct SyslogLogger {
+    path: PathBuf,
+    socket: Option<UnixDatagram>,
+    facility: u8,
+    hostname: String,
+    app_name: String,
+    failing: AtomicBool,
+}
+
+impl SyslogLogger {
+    /// Logs to `/dev/log` with the user facility, this machine's hostname, and the executable's name.
+    pub fn new() -> Self {
+        let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname").or_else(|_| std::fs::read_to_string("/etc/hostname"));
+        let app_name = std::env::current_exe().ok().and_then(|exe| Some(exe.file_name()?.to_string_lossy().into_owned()));
+        SyslogLogger {
+            path: PathBuf::from("/dev/log"),
+            // unbound, so it can send to the daemon after it restarts without reconnecting
+            socket: UnixDatagram::unbound().ok(),
+            facility: 1,
+            hostname: header_field(hostname.as_deref().unwrap_or("").trim(), 255),
+            app_name: header_field(app_name.as_deref().unwrap_or(""), 48),
+            failing: AtomicBool::new(false),
+        }
+    }
+
+    pub fn with_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    /// Facilities are numbered as in RFC 5424, 0 for kernel messages up to 23 for local7, and others are clamped.
+    pub fn with_facility(mut self, facility: u8) -> Self {
+        self.facility = facility.min(23);
+        self
+    }
+
+    pub fn with_hostname(mut self, hostname: &str) -> Self {
+        self.hostname = header_field(hostname, 255);
+        self
+    }
+
+    pub fn with_app_name(mut self, app_name: &str) -> Self {
+        self.app_name = header_field(app_name, 48);
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// One record as an RFC 5424 message, as if logged at `time`.
+    pub fn format(&self, record: &Record, time: SystemTime) -> String {
+        let priority = u16::from(self.facility) * 8 + u16::from(severity(record.verbosity));
+        let mut message = format!(
+            "<{priority}>1 {} {} {} {} {} ",
+            timestamp(time),
+            self.hostname,
+            self.app_name,
+            std::process::id(),
+            header_field(record.target, 32)
+        );
+        if record.fields.is_empty() {
+            message.push('-');
+        } else {
+            message.push('[');
+            message.push_str(FIELDS_ID);
+            for (key, value) in record.fields {
+                let name: String = key.chars().filter(|c| c.is_ascii_graphic() && !"=]\"".contains(*c)).take(32).collect();
+                let value = match value {
+                    Value::Str(s) => s.to_string(),
+                    value => value.to_string(),
+                };
+                let escaped = value.replace('\\', "\\\\").replace('"', "\\\"").replace(']', "\\]");
+                message.push_str(&format!(" {}=\"{escaped}\"", header_field(&name, 32)));
+            }
+            message.push(']');
+        }
+        if !record.message.is_empty() {
+            message.push(' ');
+            // UTF-8 messages must say so with a byte order mark
+            if !record.message.is_ascii() {
+                message.push('\u{feff}');
+            }
+            message.push_str(record.message);
+        }
+        message
+    }
+}
+
+impl Default for SyslogLogger {
+    fn default() -> Self {
+        SyslogLogger::new()
+    }
+}
+
+impl Logger for SyslogLogger {
+    fn log_record(&self, record: &Record) {
+        let message = self.format(record, SystemTime::now());
+        let result = match &self.socket {
+            Some(socket) => socket.send_to(message.as_bytes(), &self.path).map(|_| ()),
+            None => Err(std::io::Error::other("no socket")),
+        };
+        match result {
+            Ok(()) => self.failing.store(false, Ordering::Relaxed),
+            Err(error) => {
+                // report the error once, rather than before every record while it lasts
+                if !self.failing.swap(true, Ordering::Relaxed) {
+                    eprintln!("cannot log to syslog at {}: {error}", self.path.display());
+                }
+                eprintln!("{record}");
+            }
+        }
+    }
+}
+
+#[test]
+fn test_format() {
+    let logger = SyslogLogger::new().with_hostname("web 1").with_app_name("").with_facility(16);
+    let time = std::time::UNIX_EPOCH + std::time::Duration::from_millis(1_714_566_600_250);
+    let pid = std::process::id();
+    assert_eq!(logger.format(&Record::new(0, "Uhoh"), time), format!("<131>1 2024-05-01T12:30:00.250Z web_1 - {pid} - - Uhoh"));
+    let fields = [("attempt", 3.into()), ("path", "C:\\ \"x\" [y]".into()), ("odd key=", true.into())];
+    let record = Record::new(9, "caf\u{e9}").with_target("db::pool").with_fields(&fields);
+    assert_eq!(
+        logger.format(&record, time),
+        format!(
+            "<135>1 2024-05-01T12:30:00.250Z web_1 - {pid} db::pool [fields@32473 attempt=\"3\" path=\"C:\\\\ \\\"x\\\" [y\\]\" oddkey=\"true\"] \u{feff}caf\u{e9}"
+        )
+    );
+    assert_eq!((0..=5).map(severity).collect::<Vec<_>>(), [3, 4, 5, 6, 7, 7]);
+}
+
+#[test]
+fn test_sends_to_socket() {
+    let dir = std::env::temp_dir().join(format!("day2_morning_syslog_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let listener = UnixDatagram::bind(dir.join("log")).unwrap();
+    listener.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+    let logger = SyslogLogger::new().with_path(dir.join("log")).with_hostname("web1").with_app_name("server");
+    logger.log_with(2, "Uhoh", &[("attempt", 3.into())]);
+    logger.log(4, "FYI");
+    let mut buffer = [0; 2048];
+    let mut receive = || {
+        let len = listener.recv(&mut buffer).unwrap();
+        String::from_utf8(buffer[..len].to_vec()).unwrap()
+    };
+    let first = receive();
+    assert!(first.starts_with("<13>1 "), "{first}");
+    assert!(first.ends_with(&format!(" web1 server {} - [fields@32473 attempt=\"3\"] Uhoh", std::process::id())), "{first}");
+    let second = receive();
+    assert!(second.starts_with("<15>1 ") && second.ends_with(" - - FYI"), "{second}");
+    assert!(!logger.failing.load(Ordering::Relaxed));
+
+    // with no listener, records go to stderr
+    drop(listener);
+    std::fs::remove_dir_all(&dir).unwrap();
+    logger.log(1, "to stderr");
+    assert!(logger.failing.load(Ordering::Relaxed));
+}
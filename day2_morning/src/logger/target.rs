@@ -0,0 +1,145 @@
+use std::fmt;
+use std::str::FromStr;
+
+use super::{Logger, Record};
+
+/// Maximum verbosities by target, parsed from a comma-separated list of directives in the style of `RUST_LOG`:
+/// `target=verbosity` sets it for a target and everything under it, a bare `target` lets everything from it
+/// through, and a bare `verbosity` applies to targets that no other directive matches. For example,
+/// `net=4,db::pool=2,3`.
+///
+/// A directive matches a target with the same path or one below it, a `::` at a time, so `db` covers `db::pool`
+/// but not `dbx`. The longest matching directive wins. Without a bare verbosity, records from unmatched targets
+/// are dropped.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Directives {
+    default: Option<u8>,
+    // longest first, so the first match is the longest
+    targets: Vec<(String, u8)>,
+}
+
+// Whether `target` is `prefix` or a path below it.
+pub(crate) fn is_under(target: &str, prefix: &str) -> bool {
+    target.strip_prefix(prefix).is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+}
+
+impl Directives {
+    pub fn max_verbosity(&self, target: &str) -> Option<u8> {
+        self.targets.iter().find(|(prefix, _)| is_under(target, prefix)).map(|&(_, max)| max).or(self.default)
+    }
+
+    pub fn enabled(&self, target: &str, verbosity: u8) -> bool {
+        self.max_verbosity(target).is_some_and(|max| verbosity <= max)
+    }
+}
+
+/// Why a directive string couldn't be parsed, and which directive it was.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectiveError {
+    pub directive: String,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for DirectiveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid log directive '{}': {}", self.directive, self.reason)
+    }
+}
+
+impl std::error::Error for DirectiveError {}
+
+fn is_target(s: &str) -> bool {
+    !s.is_empty() && s.split("::").all(|segment| !segment.is_empty() && segment.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-'))
+}
+
+impl FromStr for Directives {
+    type Err = DirectiveError;
+
+    /// Whitespace around directives and empty directives, as from a trailing comma, are allowed.
+    fn from_str(s: &str) -> Result<Self, DirectiveError> {
+        let mut directives = Directives::default();
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let error = |reason| DirectiveError { directive: directive.to_string(), reason };
+            let verbosity = |level: &str| level.trim().parse::<u8>().map_err(|_| error("the verbosity must be a number from 0 to 255"));
+            let (target, max) = match directive.split_once('=') {
+                Some((target, level)) => (target.trim(), verbosity(level)?),
+                None if directive.starts_with(|c: char| c.is_ascii_digit()) => {
+                    if directives.default.is_some() {
+                        return Err(error("there is already a default verbosity"));
+                    }
+                    directives.default = Some(verbosity(directive)?);
+                    continue;
+                }
+                None => (directive, u8::MAX),
+            };
+            if !is_target(target) {
+                return Err(error("the target must be a path like `net` or `db::pool`"));
+            }
+            if directives.targets.iter().any(|(t, _)| t == target) {
+                return Err(error("the target already has a directive"));
+            }
+            directives.targets.push((target.to_string(), max));
+        }
+        directives.targets.sort_by_key(|(target, _)| std::cmp::Reverse(target.len()));
+        Ok(directives)
+    }
+}
+
+/// Passes records on to `inner` by their target and verbosity, as `directives` say.
+pub struct TargetFilter<L: Logger> {
+    pub directives: Directives,
+    pub inner: L,
+}
+
+impl<L: Logger> Logger for TargetFilter<L> {
+    fn log_record(&self, record: &Record) {
+        if self.directives.enabled(record.target, record.verbosity) {
+            self.inner.log_record(record);
+        }
+    }
+}
+
+#[test]
+fn test_directives() {
+    let directives: Directives = "net=4, db::pool=2,3,db::pool::slow,".parse().unwrap();
+    assert_eq!(directives.max_verbosity("net"), Some(4));
+    assert_eq!(directives.max_verbosity("net::tcp"), Some(4));
+    assert_eq!(directives.max_verbosity("network"), Some(3));
+    assert_eq!(directives.max_verbosity("db"), Some(3));
+    assert_eq!(directives.max_verbosity("db::pool"), Some(2));
+    assert_eq!(directives.max_verbosity("db::pool::conn"), Some(2));
+    assert_eq!(directives.max_verbosity("db::pool::slow::query"), Some(255));
+    assert_eq!(directives.max_verbosity(""), Some(3));
+    let directives: Directives = "net=1".parse().unwrap();
+    assert_eq!(directives.max_verbosity("db"), None);
+    assert!(!directives.enabled("db", 0));
+    assert!(directives.enabled("net::udp", 1));
+    assert_eq!("".parse(), Ok(Directives::default()));
+
+    let error = |s: &str| s.parse::<Directives>().unwrap_err().to_string();
+    assert_eq!(error("net=4,db=lots"), "invalid log directive 'db=lots': the verbosity must be a number from 0 to 255");
+    assert_eq!(error("net=256"), "invalid log directive 'net=256': the verbosity must be a number from 0 to 255");
+    assert_eq!(error("=3"), "invalid log directive '=3': the target must be a path like `net` or `db::pool`");
+    assert_eq!(error("db:pool=3"), "invalid log directive 'db:pool=3': the target must be a path like `net` or `db::pool`");
+    assert_eq!(error("net::=3"), "invalid log directive 'net::=3': the target must be a path like `net` or `db::pool`");
+    assert_eq!(error("2,net=1,3"), "invalid log directive '3': there is already a default verbosity");
+    assert_eq!(error("net=1,net=2"), "invalid log directive 'net=2': the target already has a directive");
+}
+
+#[test]
+fn test_target_filter() {
+    let logger = TargetFilter { directives: "net=4,db::pool=2,3".parse().unwrap(), inner: super::Lines::default() };
+    for (target, verbosity) in [("net::tcp", 4), ("net", 5), ("db::pool", 3), ("db::pool", 2), ("app", 3), ("app", 4)] {
+        logger.log_record(&Record::new(verbosity, "hello").with_target(target));
+    }
+    logger.log(1, "no target");
+    assert_eq!(
+        *logger.inner.0.borrow(),
+        [
+            "verbosity=4 target=net::tcp: hello",
+            "verbosity=2 target=db::pool: hello",
+            "verbosity=3 target=app: hello",
+            "verbosity=1: no target"
+        ]
+    );
+}
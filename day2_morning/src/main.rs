@@ -1,3 +1,9 @@
+// the arithmetic parser exercise lives in the library, where other crates can reuse it
+#[cfg(test)]
+use day2_morning::expression::{eval, Expression, Operation};
+// as does the generic logger exercise, which grew structured fields
+use day2_morning::logger::{Logger, StdoutLogger, VerbosityFilter};
+
 // helper functions
 
 fn demonstrate_match(){
@@ -51,42 +57,6 @@ fn demonstrate_match(){
 
 }
 
-#[derive(Debug)]
-enum Operation {
-    Add,
-    Sub,
-    Mul,
-    Div,
-}
-
-#[derive(Debug)]
-enum Expression {
-    Op { op: Operation, left: Box<Expression>, right: Box<Expression> },
-    Value(i64),
-}
-
-
-fn eval(e: Expression) -> i64 {
-        match e {
-        Expression::Value(v) => v, 
-        Expression::Op { op, left, right } => {
-            let left_val = eval(*left);   
-            let right_val = eval(*right); 
-            match op {
-                Operation::Add => left_val + right_val,
-                Operation::Sub => left_val - right_val,
-                Operation::Mul => left_val * right_val,
-                Operation::Div => {
-                    if right_val == 0 {
-                        panic!("Division by zero!"); 
-                    }
-                    left_val / right_val
-                }
-            }
-        }
-    }
-}
-
 fn demonstrate_methods(){
     println!("Rust further allows you to associate functions with your user-defined types via the impl block");
     println!("Frankly, this is the closest we will get to classes in Rust, with user-defined types and methods implemented on those types.");
@@ -194,31 +164,6 @@ fn demonstrate_traits(){
 
 }
 
-pub trait Logger {
-    fn log(&self, verbosity: u8, message: &str);
-}
-
-struct StdoutLogger;
-
-impl Logger for StdoutLogger {
-    fn log(&self, verbosity: u8, message: &str) {
-        println!("verbosity={verbosity}: {message}");
-    }
-}
-
-struct VerbosityFilter<L: Logger> {
-    max_verbosity: u8,
-    inner: L,
-}
-
-impl<L: Logger> Logger for VerbosityFilter<L> {
-    fn log(&self, verbosity: u8, message: &str) {
-        if verbosity <= self.max_verbosity {
-            self.inner.log(verbosity, message);
-        }
-    }
-}
-
 // execution code
 
 fn main() {
@@ -233,13 +178,13 @@ fn main() {
     
     #[test]
     fn test_value() {
-        assert_eq!(eval(Expression::Value(19)), 19);
+        assert_eq!(eval(&Expression::Value(19)), 19);
     }
 
     #[test]
     fn test_sum() {
         assert_eq!(
-            eval(Expression::Op {
+            eval(&Expression::Op {
                 op: Operation::Add,
                 left: Box::new(Expression::Value(10)),
                 right: Box::new(Expression::Value(20)),
@@ -265,7 +210,7 @@ fn main() {
             right: Box::new(Expression::Value(5)),
         };
         assert_eq!(
-            eval(Expression::Op {
+            eval(&Expression::Op {
                 op: Operation::Add,
                 left: Box::new(term1),
                 right: Box::new(term2),
@@ -277,7 +222,7 @@ fn main() {
     #[test]
     fn test_zeros() {
         assert_eq!(
-            eval(Expression::Op {
+            eval(&Expression::Op {
                 op: Operation::Add,
                 left: Box::new(Expression::Value(0)),
                 right: Box::new(Expression::Value(0))
@@ -285,7 +230,7 @@ fn main() {
             0
         );
         assert_eq!(
-            eval(Expression::Op {
+            eval(&Expression::Op {
                 op: Operation::Mul,
                 left: Box::new(Expression::Value(0)),
                 right: Box::new(Expression::Value(0))
@@ -293,7 +238,7 @@ fn main() {
             0
         );
         assert_eq!(
-            eval(Expression::Op {
+            eval(&Expression::Op {
                 op: Operation::Sub,
                 left: Box::new(Expression::Value(0)),
                 right: Box::new(Expression::Value(0))
@@ -312,5 +257,6 @@ fn main() {
     let logger = VerbosityFilter { max_verbosity: 3, inner: StdoutLogger };
     logger.log(5, "FYI");
     logger.log(2, "Uhoh"); 
+    logger.log_with(2, "Uhoh", &[("attempt", 3.into()), ("retrying", true.into())]);
 
 }
@@ -0,0 +1,64 @@
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use day4_afternoon::bytecode::{compile, Vm};
+use day4_afternoon::expression::{eval, Expression, Operation};
+
+fn balanced(depth: u32, next: &mut i64) -> Expression {
+    if depth == 0 {
+        *next = *next % 7 + 1;
+        return Expression::Value(*next);
+    }
+    // no `Div`, so every run walks the whole program instead of stopping at a zero divisor
+    let op = [Operation::Add, Operation::Mul, Operation::Add, Operation::Sub][depth as usize % 4];
+    Expression::Op {
+        op,
+        left: Box::new(balanced(depth - 1, next)),
+        right: Box::new(balanced(depth - 1, next)),
+    }
+}
+
+fn left_chain(length: usize) -> Expression {
+    let mut expr = Expression::Value(1);
+    for i in 0..length {
+        expr = Expression::Op {
+            op: if i % 2 == 0 { Operation::Add } else { Operation::Sub },
+            left: Box::new(expr),
+            right: Box::new(Expression::Value(i as i64)),
+        };
+    }
+    expr
+}
+
+fn time(iterations: u32, mut f: impl FnMut()) -> Duration {
+    let start = Instant::now();
+    for _ in 0..iterations {
+        f();
+    }
+    start.elapsed() / iterations
+}
+
+fn bench(name: &str, expr: &Expression, iterations: u32) {
+    let program = compile(expr).unwrap();
+    let mut vm = Vm::new();
+    let expected = eval(expr);
+    assert!(expected.is_ok());
+    assert_eq!(vm.run(&program), expected);
+
+    let tree = time(iterations, || {
+        black_box(eval(black_box(expr)).ok());
+    });
+    let bytecode = time(iterations, || {
+        black_box(vm.run(black_box(&program)).ok());
+    });
+    println!(
+        "{name}: {} instructions, eval {tree:?}, vm {bytecode:?}",
+        program.instructions().len()
+    );
+}
+
+fn main() {
+    bench("balanced depth 4", &balanced(4, &mut 0), 200_000);
+    bench("balanced depth 12", &balanced(12, &mut 0), 2_000);
+    bench("left chain 1000", &left_chain(1000), 5_000);
+}
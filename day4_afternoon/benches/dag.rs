@@ -0,0 +1,73 @@
+use std::hint::black_box;
+use std::mem::size_of;
+use std::time::{Duration, Instant};
+
+use day4_afternoon::dag::{squares, Dag, Node, NodeId};
+use day4_afternoon::expression::{evaluate, Expression, Operation};
+
+// A sum of many small terms drawn from a handful of distinct ones, as generated formulas tend to be.
+fn repetitive(terms: usize) -> Expression {
+    let mut expr = Expression::Value(0);
+    for i in 0..terms {
+        let term = Expression::Op {
+            op: Operation::Mul,
+            left: Box::new(Expression::Value(i as i64 % 8)),
+            right: Box::new(Expression::Op {
+                op: Operation::Add,
+                left: Box::new(Expression::Value(i as i64 % 5)),
+                right: Box::new(Expression::Value(3)),
+            }),
+        };
+        expr = Expression::Op { op: Operation::Add, left: Box::new(expr), right: Box::new(term) };
+    }
+    expr
+}
+
+fn time(iterations: u32, mut f: impl FnMut()) -> Duration {
+    let start = Instant::now();
+    for _ in 0..iterations {
+        f();
+    }
+    start.elapsed() / iterations
+}
+
+// Heap bytes, roughly: every tree node but the root is its own allocation, and the DAG keeps each node twice,
+// once in the arena and once as a hash map key.
+fn tree_bytes(expr: &Expression) -> usize {
+    expr.node_count() * size_of::<Expression>()
+}
+
+fn dag_bytes(dag: &Dag<i64>) -> usize {
+    dag.len() * (2 * size_of::<Node<i64>>() + size_of::<NodeId>())
+}
+
+fn bench(name: &str, expr: &Expression, iterations: u32) {
+    let mut dag = Dag::new();
+    let root = dag.insert(expr);
+    let expected = evaluate(expr);
+    assert!(expected.is_ok());
+    assert_eq!(dag.evaluate(root), expected);
+
+    let insert = time(iterations, || {
+        black_box(Dag::new().insert(black_box(expr)));
+    });
+    let tree = time(iterations, || {
+        black_box(evaluate(black_box(expr)).ok());
+    });
+    let memoized = time(iterations, || {
+        black_box(dag.evaluate(black_box(root)).ok());
+    });
+    println!(
+        "{name}: tree {} nodes ~{} KiB, dag {} nodes ~{} KiB; insert {insert:?}, eval tree {tree:?}, eval dag {memoized:?}",
+        expr.node_count(),
+        tree_bytes(expr) / 1024,
+        dag.len(),
+        dag_bytes(&dag) / 1024,
+    );
+}
+
+fn main() {
+    bench("squares depth 6", &squares(6), 2_000);
+    bench("squares depth 10", &squares(10), 20);
+    bench("repetitive 10000", &repetitive(10_000), 100);
+}
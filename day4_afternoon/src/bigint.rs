@@ -0,0 +1,480 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::{Add, Mul, Neg, Sub};
+use std::str::FromStr;
+
+use crate::expression::EvalError;
+use crate::number::Number;
+
+/// Powers with more bits than this fail with `TooLarge`, rather than running out of memory.
+pub const MAX_POW_BITS: u64 = 1 << 24;
+
+/// An integer of any size. Arithmetic on it never overflows; division truncates toward zero like `i64`'s.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct BigInt {
+    negative: bool,
+    /// The magnitude in base 2^32, least significant digit first, without leading zeros. Zero has no digits and is
+    /// never negative.
+    digits: Vec<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseBigIntError;
+
+impl fmt::Display for ParseBigIntError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid integer literal")
+    }
+}
+
+impl std::error::Error for ParseBigIntError {}
+
+// Operations on magnitudes, least significant digit first. Results are trimmed.
+
+fn trim(mut digits: Vec<u32>) -> Vec<u32> {
+    while digits.last() == Some(&0) {
+        digits.pop();
+    }
+    digits
+}
+
+fn compare(a: &[u32], b: &[u32]) -> Ordering {
+    a.len().cmp(&b.len()).then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn add(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let (long, short) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+    let mut sum = Vec::with_capacity(long.len() + 1);
+    let mut carry = 0;
+    for (i, &digit) in long.iter().enumerate() {
+        let s = digit as u64 + *short.get(i).unwrap_or(&0) as u64 + carry;
+        sum.push(s as u32);
+        carry = s >> 32;
+    }
+    sum.push(carry as u32);
+    trim(sum)
+}
+
+// Requires `a >= b`.
+fn sub(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut difference = Vec::with_capacity(a.len());
+    let mut borrow = 0;
+    for (i, &digit) in a.iter().enumerate() {
+        let d = digit as i64 - *b.get(i).unwrap_or(&0) as i64 - borrow;
+        difference.push(d as u32);
+        borrow = (d < 0) as i64;
+    }
+    debug_assert_eq!(borrow, 0, "the subtrahend is at most the minuend");
+    trim(difference)
+}
+
+fn mul(a: &[u32], b: &[u32]) -> Vec<u32> {
+    if a.is_empty() || b.is_empty() {
+        return Vec::new();
+    }
+    let mut product = vec![0u32; a.len() + b.len()];
+    for (i, &x) in a.iter().enumerate() {
+        let mut carry = 0;
+        for (j, &y) in b.iter().enumerate() {
+            let p = x as u64 * y as u64 + product[i + j] as u64 + carry;
+            product[i + j] = p as u32;
+            carry = p >> 32;
+        }
+        product[i + b.len()] = carry as u32;
+    }
+    trim(product)
+}
+
+fn div_rem_digit(a: &[u32], d: u32) -> (Vec<u32>, u32) {
+    let mut quotient = vec![0u32; a.len()];
+    let mut remainder = 0u64;
+    for (i, &digit) in a.iter().enumerate().rev() {
+        let n = remainder << 32 | digit as u64;
+        quotient[i] = (n / d as u64) as u32;
+        remainder = n % d as u64;
+    }
+    (trim(quotient), remainder as u32)
+}
+
+fn shift_left(a: &[u32], bits: u32) -> Vec<u32> {
+    let mut shifted = Vec::with_capacity(a.len() + 1);
+    let mut carry = 0;
+    for &digit in a {
+        shifted.push(((digit as u64) << bits | carry) as u32);
+        carry = (digit as u64) >> (32 - bits);
+    }
+    shifted.push(carry as u32);
+    shifted
+}
+
+fn shift_right(a: &[u32], bits: u32) -> Vec<u32> {
+    let shifted = a.iter().enumerate().map(|(i, &digit)| {
+        let high = a.get(i + 1).map_or(0, |&d| (d as u64) << 32);
+        ((high | digit as u64) >> bits) as u32
+    });
+    trim(shifted.collect())
+}
+
+// Long division, Knuth's Algorithm D: each quotient digit is estimated from the top two digits of what is left of
+// the dividend and the top digit of the divisor, which is at most 2 too large once the divisor is normalized so its
+// top bit is set. Requires a non-zero `b`.
+fn div_rem(a: &[u32], b: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    if compare(a, b) == Ordering::Less {
+        return (Vec::new(), a.to_vec());
+    }
+    if let [d] = b {
+        let (quotient, remainder) = div_rem_digit(a, *d);
+        return (quotient, trim(vec![remainder]));
+    }
+    let shift = b[b.len() - 1].leading_zeros();
+    let b = trim(shift_left(b, shift));
+    let mut a = shift_left(a, shift);
+    let n = b.len();
+    let mut quotient = vec![0u32; a.len() - n];
+    for j in (0..quotient.len()).rev() {
+        let top = (a[j + n] as u64) << 32 | a[j + n - 1] as u64;
+        let mut estimate = top / b[n - 1] as u64;
+        let mut remainder = top % b[n - 1] as u64;
+        while estimate > u32::MAX as u64 || estimate * b[n - 2] as u64 > (remainder << 32 | a[j + n - 2] as u64) {
+            estimate -= 1;
+            remainder += b[n - 1] as u64;
+            if remainder > u32::MAX as u64 {
+                break;
+            }
+        }
+        // subtract estimate * b from the current window of a
+        let mut borrow = 0i64;
+        let mut carry = 0u64;
+        for i in 0..n {
+            let p = estimate * b[i] as u64 + carry;
+            carry = p >> 32;
+            let d = a[i + j] as i64 - borrow - (p & 0xffff_ffff) as i64;
+            a[i + j] = d as u32;
+            borrow = (d < 0) as i64;
+        }
+        let d = a[j + n] as i64 - borrow - carry as i64;
+        a[j + n] = d as u32;
+        if d < 0 {
+            // the estimate was one too large: add b back
+            estimate -= 1;
+            let mut carry = 0u64;
+            for i in 0..n {
+                let s = a[i + j] as u64 + b[i] as u64 + carry;
+                a[i + j] = s as u32;
+                carry = s >> 32;
+            }
+            a[j + n] = a[j + n].wrapping_add(carry as u32);
+        }
+        quotient[j] = estimate as u32;
+    }
+    (trim(quotient), shift_right(&a[..n], shift))
+}
+
+impl BigInt {
+    fn new(negative: bool, digits: Vec<u32>) -> Self {
+        let digits = trim(digits);
+        BigInt { negative: negative && !digits.is_empty(), digits }
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    pub fn abs(&self) -> BigInt {
+        BigInt { negative: false, digits: self.digits.clone() }
+    }
+
+    /// The number of bits in the magnitude, 0 for zero.
+    pub fn bits(&self) -> u64 {
+        match self.digits.last() {
+            Some(top) => self.digits.len() as u64 * 32 - top.leading_zeros() as u64,
+            None => 0,
+        }
+    }
+
+    pub fn to_i128(&self) -> Option<i128> {
+        if self.digits.len() > 4 {
+            return None;
+        }
+        let magnitude = self.digits.iter().rev().fold(0u128, |m, &d| m << 32 | d as u128);
+        if self.negative {
+            0i128.checked_sub_unsigned(magnitude)
+        } else {
+            i128::try_from(magnitude).ok()
+        }
+    }
+
+    /// The quotient truncated toward zero and the remainder with the sign of `self`, or `None` for a zero divisor.
+    pub fn div_rem(&self, other: &BigInt) -> Option<(BigInt, BigInt)> {
+        if other.digits.is_empty() {
+            return None;
+        }
+        let (quotient, remainder) = div_rem(&self.digits, &other.digits);
+        Some((BigInt::new(self.negative != other.negative, quotient), BigInt::new(self.negative, remainder)))
+    }
+
+    pub fn pow(&self, mut exponent: u32) -> BigInt {
+        let mut result = BigInt::one();
+        let mut base = self.clone();
+        while exponent > 0 {
+            if exponent & 1 == 1 {
+                result = &result * &base;
+            }
+            exponent >>= 1;
+            if exponent > 0 {
+                base = &base * &base;
+            }
+        }
+        result
+    }
+}
+
+impl From<i128> for BigInt {
+    fn from(n: i128) -> Self {
+        let mut magnitude = n.unsigned_abs();
+        let mut digits = Vec::new();
+        while magnitude > 0 {
+            digits.push(magnitude as u32);
+            magnitude >>= 32;
+        }
+        BigInt { negative: n < 0, digits }
+    }
+}
+
+impl From<i64> for BigInt {
+    fn from(n: i64) -> Self {
+        BigInt::from(n as i128)
+    }
+}
+
+impl Neg for &BigInt {
+    type Output = BigInt;
+
+    fn neg(self) -> BigInt {
+        BigInt::new(!self.negative, self.digits.clone())
+    }
+}
+
+impl Add for &BigInt {
+    type Output = BigInt;
+
+    fn add(self, other: &BigInt) -> BigInt {
+        if self.negative == other.negative {
+            return BigInt::new(self.negative, add(&self.digits, &other.digits));
+        }
+        // opposite signs: the larger magnitude decides the sign
+        match compare(&self.digits, &other.digits) {
+            Ordering::Less => BigInt::new(other.negative, sub(&other.digits, &self.digits)),
+            _ => BigInt::new(self.negative, sub(&self.digits, &other.digits)),
+        }
+    }
+}
+
+impl Sub for &BigInt {
+    type Output = BigInt;
+
+    fn sub(self, other: &BigInt) -> BigInt {
+        self + &-other
+    }
+}
+
+impl Mul for &BigInt {
+    type Output = BigInt;
+
+    fn mul(self, other: &BigInt) -> BigInt {
+        BigInt::new(self.negative != other.negative, mul(&self.digits, &other.digits))
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => compare(&self.digits, &other.digits),
+            (true, true) => compare(&other.digits, &self.digits),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Decimal digits are handled 9 at a time, the most that fit in a `u32` digit.
+const CHUNK: u32 = 1_000_000_000;
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut chunks = Vec::new();
+        let mut rest = self.digits.clone();
+        while !rest.is_empty() {
+            let (quotient, chunk) = div_rem_digit(&rest, CHUNK);
+            chunks.push(chunk);
+            rest = quotient;
+        }
+        if self.negative {
+            write!(f, "-")?;
+        }
+        let mut chunks = chunks.iter().rev();
+        write!(f, "{}", chunks.next().unwrap_or(&0))?;
+        for chunk in chunks {
+            write!(f, "{chunk:09}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses an optional `-` followed by decimal digits.
+impl FromStr for BigInt {
+    type Err = ParseBigIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, s),
+        };
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(ParseBigIntError);
+        }
+        let mut magnitude = Vec::new();
+        // the first chunk is short, so the others are all 9 digits long
+        let first = (digits.len() - 1) % 9 + 1;
+        let chunks = std::iter::once(&digits[..first]).chain(digits.as_bytes()[first..].chunks(9).map(|c| {
+            std::str::from_utf8(c).expect("ASCII digits")
+        }));
+        for chunk in chunks {
+            let scale = 10u32.pow(chunk.len() as u32);
+            let value: u32 = chunk.parse().expect("at most 9 digits");
+            magnitude = add(&mul(&magnitude, &[scale]), &[value]);
+        }
+        Ok(BigInt::new(negative, magnitude))
+    }
+}
+
+impl Number for BigInt {
+    fn zero() -> Self {
+        BigInt::default()
+    }
+
+    fn one() -> Self {
+        BigInt::from(1i64)
+    }
+
+    fn try_add(self, other: Self) -> Result<Self, EvalError> {
+        Ok(&self + &other)
+    }
+
+    fn try_sub(self, other: Self) -> Result<Self, EvalError> {
+        Ok(&self - &other)
+    }
+
+    fn try_mul(self, other: Self) -> Result<Self, EvalError> {
+        Ok(&self * &other)
+    }
+
+    fn try_div(self, other: Self) -> Result<Self, EvalError> {
+        self.div_rem(&other).map(|(quotient, _)| quotient).ok_or(EvalError::DivideByZero)
+    }
+
+    fn try_rem(self, other: Self) -> Result<Self, EvalError> {
+        self.div_rem(&other).map(|(_, remainder)| remainder).ok_or(EvalError::DivideByZero)
+    }
+
+    fn try_pow(self, exponent: Self) -> Result<Self, EvalError> {
+        if exponent.negative {
+            return Err(EvalError::NegativeExponent);
+        }
+        if self.bits() <= 1 {
+            // 0, 1 and -1 have small powers however large the exponent
+            let odd = exponent.digits.first().is_some_and(|d| d & 1 == 1);
+            return Ok(if exponent.digits.is_empty() { BigInt::one() } else if odd { self } else { self.abs() });
+        }
+        match exponent.to_i128().and_then(|e| u32::try_from(e).ok()) {
+            Some(e) if (self.bits() - 1) * e as u64 <= MAX_POW_BITS => Ok(self.pow(e)),
+            _ => Err(EvalError::TooLarge { max_bits: MAX_POW_BITS }),
+        }
+    }
+
+    fn try_neg(self) -> Result<Self, EvalError> {
+        Ok(-&self)
+    }
+}
+
+#[cfg(test)]
+fn big(s: &str) -> BigInt {
+    s.parse().unwrap()
+}
+
+#[test]
+fn test_bigint_arithmetic() {
+    let a = big("123456789012345678901234567890");
+    let b = big("-987654321098765432109876543210");
+    assert_eq!((&a + &b).to_string(), "-864197532086419753208641975320");
+    assert_eq!((&a - &b).to_string(), "1111111110111111111011111111100");
+    assert_eq!((&a * &b).to_string(), "-121932631137021795226185032733622923332237463801111263526900");
+    let (q, r) = b.div_rem(&a).unwrap();
+    assert_eq!((q.to_string(), r.to_string()), (String::from("-8"), String::from("-9000000000900000000090")));
+    assert_eq!(big("2").pow(200).to_string(), "1606938044258990275541962092341162602522202993782792835301376");
+    assert_eq!(big("-0"), BigInt::zero());
+    assert!(b < a && -&a < BigInt::zero() && big("-5") > big("-6"));
+    assert_eq!(big("1").div_rem(&BigInt::zero()), None);
+    for bad in ["", "-", "1.5", "+1", "12a", " 1"] {
+        assert_eq!(bad.parse::<BigInt>(), Err(ParseBigIntError), "{bad:?}");
+    }
+}
+
+#[test]
+fn test_bigint_matches_i128() {
+    let mut generator = crate::fuzz::Generator::new(0);
+    let mut random = || {
+        // magnitudes spread over every size up to 2^63
+        let r = generator.random();
+        (r as i64 >> (r % 63)) as i128
+    };
+    for _ in 0..20_000 {
+        let (x, y) = (random() * random(), random());
+        let (a, b) = (BigInt::from(x), BigInt::from(y));
+        assert_eq!(big(&x.to_string()), a);
+        assert_eq!(a.to_string(), x.to_string());
+        assert_eq!((&a + &b).to_i128(), Some(x + y), "{x} + {y}");
+        assert_eq!((&a - &b).to_i128(), Some(x - y), "{x} - {y}");
+        assert_eq!(a.cmp(&b), x.cmp(&y), "{x} <=> {y}");
+        if let Some(p) = x.checked_mul(y) {
+            assert_eq!((&a * &b).to_i128(), Some(p), "{x} * {y}");
+        }
+        if y != 0 {
+            let (q, r) = a.div_rem(&b).unwrap();
+            assert_eq!((q.to_i128(), r.to_i128()), (Some(x / y), Some(x % y)), "{x} / {y}");
+        }
+    }
+}
+
+#[test]
+fn test_bigint_division_identity() {
+    // large operands take the multi-digit path, where each quotient digit is estimated and corrected
+    let mut n = big("1");
+    for k in 1..60 {
+        n = &(&n * &big("18446744073709551557")) + &BigInt::from(k as i64);
+        for d in [big("4294967296"), big("18446744073709551615"), big("-340282366920938463463374607431768211297"), n.pow(1)] {
+            let (q, r) = n.div_rem(&d).unwrap();
+            assert_eq!(&(&q * &d) + &r, n);
+            assert!(r.abs() < d.abs() && (r.is_negative() == n.is_negative() || r == BigInt::zero()));
+        }
+    }
+}
+
+#[test]
+fn test_bigint_evaluation() {
+    let expr = crate::parser::parse("9223372036854775807 * 9223372036854775807 - 2 ** 100 % 7").unwrap();
+    assert_eq!(crate::expression::eval(&expr), Err(EvalError::Overflow));
+    let big_expr = expr.map_values(&|&v| BigInt::from(v));
+    assert_eq!(crate::expression::evaluate(&big_expr).unwrap().to_string(), "85070591730234615847396907784232501247");
+    let evaluate = |src| crate::expression::evaluate(&crate::parser::parse(src).unwrap().map_values(&|&v| BigInt::from(v)));
+    assert_eq!(evaluate("(-1) ** 9223372036854775807 + 0 ** 4 + 7 ** 0"), Ok(BigInt::zero()));
+    assert_eq!(evaluate("2 ** -1"), Err(EvalError::NegativeExponent));
+    assert_eq!(evaluate("3 ** 100000000"), Err(EvalError::TooLarge { max_bits: MAX_POW_BITS }));
+    assert_eq!(evaluate("2 ** 16777217"), Err(EvalError::TooLarge { max_bits: MAX_POW_BITS }));
+}
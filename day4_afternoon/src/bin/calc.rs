@@ -0,0 +1,25 @@
+use std::io::{self, BufRead, Write};
+
+use day4_afternoon::repl::{Outcome, Session};
+
+fn main() -> io::Result<()> {
+    println!("~~~ Expression calculator, :help for commands ~~~");
+    let mut session = if std::env::args().any(|arg| arg == "--json") { Session::with_json_errors() } else { Session::new() };
+    let stdin = io::stdin();
+    let mut line = String::new();
+    loop {
+        print!("> ");
+        io::stdout().flush()?;
+        line.clear();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            break;
+        }
+        match session.execute(&line) {
+            Outcome::Print(output) if output.is_empty() => {}
+            Outcome::Print(output) => println!("{output}"),
+            Outcome::Error(message) => println!("{message}"),
+            Outcome::Quit => break,
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,29 @@
+use day4_afternoon::fuzz::{check, check_against_day2, fuzz, Generator, Grammar};
+
+// Usage: fuzz [SEED] [CASES]. Runs the differential checks on the full language, then on the arithmetic that
+// day 2's `eval` understands, and prints the first failure of each, shrunk.
+fn main() {
+    let mut args = std::env::args().skip(1).map(|arg| arg.parse::<u64>().expect("SEED and CASES are numbers"));
+    let seed = args.next().unwrap_or(0);
+    let cases = args.next().unwrap_or(10_000) as usize;
+    // expected panics from day 2's `eval` would otherwise drown out the report
+    std::panic::set_hook(Box::new(|_| {}));
+    let mut failed = false;
+    for grammar in [Grammar::Full, Grammar::Arithmetic] {
+        let mut generator = Generator::new(seed).with_grammar(grammar);
+        let failure = match grammar {
+            Grammar::Full | Grammar::Closed => fuzz(&mut generator, cases, check),
+            Grammar::Arithmetic => fuzz(&mut generator, cases, |e| check(e).and_then(|()| check_against_day2(e))),
+        };
+        match failure {
+            Some(failure) => {
+                println!("{grammar:?}, seed {seed}: {failure}");
+                failed = true;
+            }
+            None => println!("{grammar:?}, seed {seed}: {cases} cases passed"),
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,301 @@
+use std::fmt;
+
+use crate::expression::{apply, apply_unary, truth, EvalError, Expression, Operation, UnaryOperation};
+
+/// A single instruction for the stack machine. Jump targets are indices into the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Push(i64),
+    Op(Operation),
+    Unary(UnaryOperation),
+    Jump(usize),
+    /// Pops the top of the stack and jumps if it is false.
+    JumpIfFalse(usize),
+    /// Pops the top of the stack and jumps if it is true.
+    JumpIfTrue(usize),
+}
+
+/// An `Expression` flattened into postfix order, ready to be run any number of times.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    code: Vec<Instruction>,
+    max_stack: usize,
+}
+
+impl Program {
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.code
+    }
+
+    pub fn max_stack(&self) -> usize {
+        self.max_stack
+    }
+}
+
+/// The tree-walking evaluator handles everything; the stack machine only the closed arithmetic subset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompileError {
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompileError::Unsupported(what) => write!(f, "the bytecode compiler does not support {what}"),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+pub fn compile(e: &Expression) -> Result<Program, CompileError> {
+    let mut code = Vec::new();
+    let max_stack = emit(e, &mut code)?;
+    Ok(Program { code, max_stack })
+}
+
+// Pending work for `emit`, which keeps its own stack so that deep trees compile without recursing.
+enum Step<'a> {
+    /// Emits the code for an expression, given how many values are on the stack already.
+    Emit(&'a Expression, usize),
+    Push(Instruction),
+    /// Reserves a slot for a jump whose target is not known yet, and remembers where.
+    Placeholder,
+    /// Finishes `&&` or `||` once both sides are emitted, pointing its two reserved jumps past them.
+    ShortCircuit(Operation),
+    /// Points the jump reserved after an `if` condition at the `else` branch, which comes next.
+    Otherwise,
+    /// Points the jump reserved after the `then` branch here, past the `else` branch.
+    EndIf,
+    /// Replaces the stack maxima of the last few subexpressions with the largest of them.
+    Max(usize),
+}
+
+// Returns the deepest the stack gets while running the emitted code.
+fn emit(e: &Expression, code: &mut Vec<Instruction>) -> Result<usize, CompileError> {
+    let mut steps = vec![Step::Emit(e, 0)];
+    let mut jumps = Vec::new();
+    let mut maxima = Vec::new();
+    while let Some(step) = steps.pop() {
+        // the steps to take next, in order
+        let next = match step {
+            Step::Emit(e, depth) => match e {
+                // `a && b` and `a || b` become a pair of conditional jumps that leave 0 or 1 behind.
+                Expression::Op { op: op @ (Operation::And | Operation::Or), left, right } => vec![
+                    Step::Emit(left, depth),
+                    Step::Placeholder,
+                    Step::Emit(right, depth),
+                    Step::Placeholder,
+                    Step::ShortCircuit(*op),
+                    Step::Max(2),
+                ],
+                Expression::Op { op, left, right } => {
+                    vec![Step::Emit(left, depth), Step::Emit(right, depth + 1), Step::Push(Instruction::Op(*op)), Step::Max(2)]
+                }
+                Expression::Unary { op, operand } => vec![Step::Emit(operand, depth), Step::Push(Instruction::Unary(*op))],
+                Expression::If { cond, then, otherwise } => vec![
+                    Step::Emit(cond, depth),
+                    Step::Placeholder,
+                    Step::Emit(then, depth),
+                    Step::Placeholder,
+                    Step::Otherwise,
+                    Step::Emit(otherwise, depth),
+                    Step::EndIf,
+                    Step::Max(3),
+                ],
+                Expression::Value(v) => {
+                    code.push(Instruction::Push(*v));
+                    maxima.push(depth + 1);
+                    Vec::new()
+                }
+                Expression::Let { .. } => return Err(CompileError::Unsupported("let")),
+                Expression::Function { .. } | Expression::Call { .. } => return Err(CompileError::Unsupported("functions")),
+                Expression::Var(_) => return Err(CompileError::Unsupported("variables")),
+                Expression::Bool(b) => {
+                    code.push(Instruction::Push(*b as i64));
+                    maxima.push(depth + 1);
+                    Vec::new()
+                }
+                Expression::Spanned { inner, .. } => vec![Step::Emit(inner, depth)],
+            },
+            Step::Push(instruction) => {
+                code.push(instruction);
+                Vec::new()
+            }
+            Step::Placeholder => {
+                jumps.push(code.len());
+                code.push(Instruction::Jump(usize::MAX));
+                Vec::new()
+            }
+            Step::ShortCircuit(op) => {
+                let second = jumps.pop().expect("the jump after the right side");
+                let first = jumps.pop().expect("the jump after the left side");
+                let short_circuit = |target| match op {
+                    Operation::And => Instruction::JumpIfFalse(target),
+                    _ => Instruction::JumpIfTrue(target),
+                };
+                code.push(Instruction::Push((op == Operation::And) as i64));
+                let end = code.len();
+                code.push(Instruction::Jump(usize::MAX));
+                let decided = code.len();
+                code.push(Instruction::Push((op == Operation::Or) as i64));
+                code[first] = short_circuit(decided);
+                code[second] = short_circuit(decided);
+                code[end] = Instruction::Jump(code.len());
+                Vec::new()
+            }
+            Step::Otherwise => {
+                let to_end = jumps.pop().expect("the jump after the then branch");
+                let to_otherwise = jumps.pop().expect("the jump after the condition");
+                code[to_otherwise] = Instruction::JumpIfFalse(code.len());
+                jumps.push(to_end);
+                Vec::new()
+            }
+            Step::EndIf => {
+                let to_end = jumps.pop().expect("the jump after the then branch");
+                code[to_end] = Instruction::Jump(code.len());
+                Vec::new()
+            }
+            Step::Max(count) => {
+                let max = maxima.drain(maxima.len() - count..).max().expect("at least one subexpression");
+                maxima.push(max);
+                Vec::new()
+            }
+        };
+        steps.extend(next.into_iter().rev());
+    }
+    Ok(maxima.pop().expect("the whole expression's maximum"))
+}
+
+/// Runs compiled programs, keeping its stack allocation between runs.
+#[derive(Debug, Default)]
+pub struct Vm {
+    stack: Vec<i64>,
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn run(&mut self, program: &Program) -> Result<i64, EvalError> {
+        self.stack.clear();
+        self.stack.reserve(program.max_stack);
+        let mut pc = 0;
+        while let Some(instruction) = program.code.get(pc) {
+            pc += 1;
+            match *instruction {
+                Instruction::Push(v) => self.stack.push(v),
+                Instruction::Op(op) => {
+                    let right = self.pop();
+                    let left = self.pop();
+                    self.stack.push(apply(op, left, right)?);
+                }
+                Instruction::Unary(op) => {
+                    let operand = self.pop();
+                    self.stack.push(apply_unary(op, operand)?);
+                }
+                Instruction::Jump(target) => pc = target,
+                Instruction::JumpIfFalse(target) => {
+                    if !truth(&self.pop()) {
+                        pc = target;
+                    }
+                }
+                Instruction::JumpIfTrue(target) => {
+                    if truth(&self.pop()) {
+                        pc = target;
+                    }
+                }
+            }
+        }
+        Ok(self.pop())
+    }
+
+    fn pop(&mut self) -> i64 {
+        self.stack.pop().expect("compiled programs never underflow the stack")
+    }
+}
+
+#[test]
+fn test_compile_postfix() {
+    let expr = Expression::Op {
+        op: Operation::Mul,
+        left: Box::new(Expression::Op {
+            op: Operation::Sub,
+            left: Box::new(Expression::Value(3)),
+            right: Box::new(Expression::Value(4)),
+        }),
+        right: Box::new(Expression::Value(5)),
+    };
+    let program = compile(&expr).unwrap();
+    assert_eq!(
+        program.instructions(),
+        &[
+            Instruction::Push(3),
+            Instruction::Push(4),
+            Instruction::Op(Operation::Sub),
+            Instruction::Push(5),
+            Instruction::Op(Operation::Mul),
+        ]
+    );
+    assert_eq!(program.max_stack(), 2);
+    assert_eq!(Vm::new().run(&program), Ok(-5));
+}
+
+#[test]
+fn test_vm_divide_by_zero() {
+    let expr = Expression::Op {
+        op: Operation::Div,
+        left: Box::new(Expression::Value(99)),
+        right: Box::new(Expression::Value(0)),
+    };
+    assert_eq!(Vm::new().run(&compile(&expr).unwrap()), Err(EvalError::DivideByZero));
+}
+
+#[test]
+fn test_vm_matches_eval() {
+    use crate::fuzz::{Generator, Grammar};
+    let mut generator = Generator::new(0).with_grammar(Grammar::Closed).with_limits(5, 64);
+    let mut vm = Vm::new();
+    for _ in 0..2000 {
+        let expr = generator.generate();
+        let program = compile(&expr).unwrap();
+        assert_eq!(vm.run(&program), crate::expression::eval(&expr), "{expr:?}");
+    }
+}
+
+#[test]
+fn test_vm_matches_eval_on_deep_trees() {
+    let mut vm = Vm::new();
+    let chain = crate::expression::left_chain(1_000_000);
+    assert_eq!(vm.run(&compile(&chain).unwrap()), crate::expression::eval(&chain));
+    let mut expr = Expression::Value(1);
+    for i in 0..300_000 {
+        expr = match i % 4 {
+            0 => Expression::Unary { op: UnaryOperation::Neg, operand: Box::new(expr) },
+            1 => Expression::If { cond: Box::new(Expression::Bool(true)), then: Box::new(expr), otherwise: Box::new(Expression::Value(0)) },
+            2 => Expression::Op { op: Operation::Or, left: Box::new(Expression::Value(0)), right: Box::new(expr) },
+            _ => Expression::Op { op: Operation::Sub, left: Box::new(Expression::Value(i)), right: Box::new(expr) },
+        };
+    }
+    let program = compile(&expr).unwrap();
+    assert_eq!(vm.run(&program), crate::expression::eval(&expr));
+    // every `-` holds its left operand while the right one is computed
+    assert_eq!(program.max_stack(), 75_001);
+}
+
+#[test]
+fn test_vm_short_circuit() {
+    let expr = crate::parser::parse("if 1 > 2 || 3 / 0 == 0 then 1 / 0 else 2").unwrap();
+    assert_eq!(Vm::new().run(&compile(&expr).unwrap()), Err(EvalError::DivideByZero));
+    let expr = crate::parser::parse("if 1 < 2 || 3 / 0 == 0 then 4 && 5 else 1 / 0").unwrap();
+    let program = compile(&expr).unwrap();
+    assert_eq!(Vm::new().run(&program), Ok(1));
+    assert_eq!(program.max_stack(), 2);
+}
+
+#[test]
+fn test_compile_unsupported() {
+    let expr = crate::parser::parse("let x = 1 in x").unwrap();
+    assert_eq!(compile(&expr), Err(CompileError::Unsupported("let")));
+}
@@ -0,0 +1,458 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::expression::{apply, apply_unary, truth, EvalError, Expression, Operation, UnaryOperation, DEFAULT_MAX_CALL_DEPTH};
+use crate::number::Number;
+use crate::visit::Layer;
+
+/// Identifies a node in a `Dag`. Only meaningful for the `Dag` that returned it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(u32);
+
+impl NodeId {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// An `Expression` node whose children are referred to by ID.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Node<N> {
+    Op { op: Operation, left: NodeId, right: NodeId },
+    Unary { op: UnaryOperation, operand: NodeId },
+    If { cond: NodeId, then: NodeId, otherwise: NodeId },
+    Let { name: String, value: NodeId, body: NodeId },
+    Function { params: Vec<String>, body: NodeId },
+    Call { callee: NodeId, args: Vec<NodeId> },
+    Var(String),
+    Value(N),
+    Bool(bool),
+}
+
+impl<N> Node<N> {
+    fn children(&self) -> Vec<NodeId> {
+        match self {
+            Node::Op { left, right, .. } => vec![*left, *right],
+            Node::Unary { operand, .. } => vec![*operand],
+            Node::If { cond, then, otherwise } => vec![*cond, *then, *otherwise],
+            Node::Let { value, body, .. } => vec![*value, *body],
+            Node::Function { body, .. } => vec![*body],
+            Node::Call { callee, args } => std::iter::once(*callee).chain(args.iter().copied()).collect(),
+            Node::Var(_) | Node::Value(_) | Node::Bool(_) => Vec::new(),
+        }
+    }
+}
+
+/// Expressions stored in one arena, with structurally equal subtrees stored once.
+///
+/// Nodes are only ever added, and a node's children always have smaller IDs than the node itself.
+#[derive(Debug, Clone)]
+pub struct Dag<N> {
+    nodes: Vec<Node<N>>,
+    ids: HashMap<Node<N>, NodeId>,
+}
+
+impl<N> Default for Dag<N> {
+    fn default() -> Self {
+        Dag { nodes: Vec::new(), ids: HashMap::new() }
+    }
+}
+
+impl<N: Clone + Eq + Hash> Dag<N> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn node(&self, id: NodeId) -> &Node<N> {
+        &self.nodes[id.index()]
+    }
+
+    /// Adds a node, or returns the existing ID of an equal one.
+    pub fn intern(&mut self, node: Node<N>) -> NodeId {
+        if let Some(&id) = self.ids.get(&node) {
+            return id;
+        }
+        let id = NodeId(u32::try_from(self.nodes.len()).expect("a DAG holds fewer than 2^32 nodes"));
+        self.nodes.push(node.clone());
+        self.ids.insert(node, id);
+        id
+    }
+
+    /// Adds every subtree of `e`, dropping `Spanned` wrappers, and returns the ID of its root.
+    pub fn insert(&mut self, e: &Expression<N>) -> NodeId {
+        e.fold(|layer| {
+            let node = match layer {
+                Layer::Op { op, left, right } => Node::Op { op, left, right },
+                Layer::Unary { op, operand } => Node::Unary { op, operand },
+                Layer::If { cond, then, otherwise } => Node::If { cond, then, otherwise },
+                Layer::Let { name, value, body } => Node::Let { name: name.to_string(), value, body },
+                Layer::Function { params, body } => Node::Function { params: params.to_vec(), body },
+                Layer::Call { callee, args } => Node::Call { callee, args },
+                Layer::Var(name) => Node::Var(name.to_string()),
+                Layer::Value(v) => Node::Value(v.clone()),
+                Layer::Bool(b) => Node::Bool(b),
+            };
+            self.intern(node)
+        })
+    }
+
+    /// Expands the node back into a tree, copying shared subtrees once per use.
+    pub fn to_expression(&self, id: NodeId) -> Expression<N> {
+        let mut pending = vec![(id, false)];
+        let mut built: Vec<Expression<N>> = Vec::new();
+        while let Some((id, expanded)) = pending.pop() {
+            let node = self.node(id);
+            if !expanded {
+                pending.push((id, true));
+                pending.extend(node.children().into_iter().rev().map(|child| (child, false)));
+                continue;
+            }
+            let mut children = built.split_off(built.len() - node.children().len()).into_iter().map(Box::new);
+            let mut child = || children.next().expect("one expression per child");
+            built.push(match node {
+                Node::Op { op, .. } => Expression::Op { op: *op, left: child(), right: child() },
+                Node::Unary { op, .. } => Expression::Unary { op: *op, operand: child() },
+                Node::If { .. } => Expression::If { cond: child(), then: child(), otherwise: child() },
+                Node::Let { name, .. } => Expression::Let { name: name.clone(), value: child(), body: child() },
+                Node::Function { params, .. } => Expression::Function { params: params.clone(), body: child() },
+                Node::Call { args, .. } => {
+                    let callee = child();
+                    Expression::Call { callee, args: args.iter().map(|_| *child()).collect() }
+                }
+                Node::Var(name) => Expression::Var(name.clone()),
+                Node::Value(v) => Expression::Value(v.clone()),
+                Node::Bool(b) => Expression::Bool(*b),
+            });
+        }
+        built.pop().expect("the root is built last")
+    }
+}
+
+// Why `Dag::evaluate` cannot finish a node yet.
+enum Stop {
+    /// The value of this child, in this environment, is needed first.
+    Need(NodeId, EnvId),
+    /// The same, where the child is the body of a function being called.
+    Call(NodeId, EnvId),
+    Failed(EvalError),
+}
+
+// Identifies a set of variable bindings during `Dag::evaluate`, where 0 is the empty one. Environments are
+// hash-consed like nodes, so equal ones get the same ID and share memoized values.
+type EnvId = usize;
+
+// A value during `Dag::evaluate`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Value<'a, N> {
+    Number(N),
+    /// A `Function` node, the environment it was made in, and the name `let` bound it to, which is in scope in
+    /// its body so that it can call itself.
+    Closure(NodeId, EnvId, Option<&'a str>),
+}
+
+// A result during `Dag::evaluate`, with the number of calls deep its evaluation went.
+type Memoized<'a, N> = (Result<Value<'a, N>, EvalError>, usize);
+
+struct Evaluation<'a, N> {
+    /// For each environment after the empty one, the one it extends and the binding it adds.
+    envs: Vec<(EnvId, &'a str, Value<'a, N>)>,
+    env_ids: HashMap<(EnvId, &'a str, Value<'a, N>), EnvId>,
+    /// Each result, with how many calls deep its evaluation went, so that reusing it fails exactly when computing
+    /// it again would.
+    memo: HashMap<(NodeId, EnvId), Memoized<'a, N>>,
+    max_call_depth: usize,
+    /// Calls active at the node being evaluated.
+    calls: usize,
+    /// How many calls deep the memoized values read while evaluating the current node went.
+    height: usize,
+}
+
+impl<'a, N: Number + Eq + Hash> Evaluation<'a, N> {
+    fn bind(&mut self, env: EnvId, name: &'a str, value: Value<'a, N>) -> EnvId {
+        let binding = (env, name, value);
+        if let Some(&id) = self.env_ids.get(&binding) {
+            return id;
+        }
+        self.envs.push(binding.clone());
+        self.env_ids.insert(binding, self.envs.len());
+        self.envs.len()
+    }
+
+    fn lookup(&self, mut env: EnvId, name: &str) -> Option<&Value<'a, N>> {
+        while env != 0 {
+            let (outer, bound, value) = &self.envs[env - 1];
+            if *bound == name {
+                return Some(value);
+            }
+            env = *outer;
+        }
+        None
+    }
+
+    fn value(&mut self, id: NodeId, env: EnvId) -> Result<Value<'a, N>, Stop> {
+        self.reuse(id, env, 0)
+    }
+
+    // The value of a function body called from the current node.
+    fn called(&mut self, body: NodeId, env: EnvId) -> Result<Value<'a, N>, Stop> {
+        match self.reuse(body, env, 1) {
+            Err(Stop::Need(body, env)) => Err(Stop::Call(body, env)),
+            result => result,
+        }
+    }
+
+    fn reuse(&mut self, id: NodeId, env: EnvId, calls: usize) -> Result<Value<'a, N>, Stop> {
+        match self.memo.get(&(id, env)) {
+            Some((Ok(v), height)) => {
+                let height = calls + height;
+                if self.calls + height > self.max_call_depth {
+                    return Err(Stop::Failed(EvalError::CallDepthExceeded(self.max_call_depth)));
+                }
+                self.height = self.height.max(height);
+                Ok(v.clone())
+            }
+            Some((Err(e), _)) => Err(Stop::Failed(e.clone())),
+            None => Err(Stop::Need(id, env)),
+        }
+    }
+}
+
+fn number<N>(v: Value<'_, N>) -> Result<N, Stop> {
+    match v {
+        Value::Number(n) => Ok(n),
+        Value::Closure(..) => Err(Stop::Failed(EvalError::ExpectedNumber)),
+    }
+}
+
+impl<N: Number + Eq + Hash> Dag<N> {
+    /// Evaluates `root` with the same results as `evaluate`, computing each shared node at most once per
+    /// environment it is evaluated in.
+    ///
+    /// Like the tree evaluator it does not recurse, and operands that are never needed, such as the untaken branch
+    /// of an `if`, are never evaluated. Environments are hash-consed too, so a node under a `let` or in a function
+    /// body is only computed again when the values of the variables in scope differ. A reused result still counts
+    /// the calls it took towards the call depth limit.
+    pub fn evaluate(&self, root: NodeId) -> Result<N, EvalError> {
+        self.evaluate_with_call_limit(root, DEFAULT_MAX_CALL_DEPTH)
+    }
+
+    /// `evaluate`, failing with `CallDepthExceeded` once more than `max_call_depth` calls are active at once.
+    pub fn evaluate_with_call_limit(&self, root: NodeId, max_call_depth: usize) -> Result<N, EvalError> {
+        let mut evaluation =
+            Evaluation { envs: Vec::new(), env_ids: HashMap::new(), memo: HashMap::new(), max_call_depth, calls: 0, height: 0 };
+        // nodes waiting for their children, with their environment and whether they are the body of a call
+        let mut pending = vec![(root, 0, false)];
+        while let Some(&(id, env, call)) = pending.last() {
+            if evaluation.memo.contains_key(&(id, env)) {
+                pending.pop();
+                evaluation.calls -= usize::from(call);
+                continue;
+            }
+            evaluation.height = 0;
+            let result = match self.step(id, env, &mut evaluation) {
+                Ok(v) => Ok(v),
+                Err(Stop::Failed(e)) => Err(e),
+                Err(Stop::Need(child, env)) => {
+                    pending.push((child, env, false));
+                    continue;
+                }
+                Err(Stop::Call(body, env)) if evaluation.calls < max_call_depth => {
+                    evaluation.calls += 1;
+                    pending.push((body, env, true));
+                    continue;
+                }
+                Err(Stop::Call(..)) => Err(EvalError::CallDepthExceeded(max_call_depth)),
+            };
+            evaluation.memo.insert((id, env), (result, evaluation.height));
+        }
+        let (result, _) = evaluation.memo.remove(&(root, 0)).expect("the root is evaluated last");
+        number(result?).map_err(|_| EvalError::ExpectedNumber)
+    }
+
+    // Functions are only rejected once the operands next to them are evaluated, as in the tree evaluator, so that
+    // an error in those operands is the one reported.
+    fn step<'a>(&'a self, id: NodeId, env: EnvId, evaluation: &mut Evaluation<'a, N>) -> Result<Value<'a, N>, Stop> {
+        match self.node(id) {
+            Node::Op { op: op @ (Operation::And | Operation::Or), left, right } => {
+                let left = truth(&number(evaluation.value(*left, env)?)?);
+                if left == (*op == Operation::Or) {
+                    return Ok(Value::Number(N::from_bool(left)));
+                }
+                Ok(Value::Number(N::from_bool(truth(&number(evaluation.value(*right, env)?)?))))
+            }
+            Node::Op { op, left, right } => {
+                let (left, right) = (evaluation.value(*left, env)?, evaluation.value(*right, env)?);
+                apply(*op, number(left)?, number(right)?).map(Value::Number).map_err(Stop::Failed)
+            }
+            Node::Unary { op, operand } => {
+                apply_unary(*op, number(evaluation.value(*operand, env)?)?).map(Value::Number).map_err(Stop::Failed)
+            }
+            Node::If { cond, then, otherwise } => {
+                let cond = number(evaluation.value(*cond, env)?)?;
+                evaluation.value(if truth(&cond) { *then } else { *otherwise }, env)
+            }
+            Node::Let { name, value, body } => {
+                let bound = match self.node(*value) {
+                    Node::Function { .. } => Value::Closure(*value, env, Some(name)),
+                    _ => evaluation.value(*value, env)?,
+                };
+                let env = evaluation.bind(env, name, bound);
+                evaluation.value(*body, env)
+            }
+            Node::Function { .. } => Ok(Value::Closure(id, env, None)),
+            Node::Call { callee, args } => {
+                let callee = evaluation.value(*callee, env)?;
+                let args = args.iter().map(|arg| evaluation.value(*arg, env)).collect::<Result<Vec<_>, _>>()?;
+                let Value::Closure(function, closure_env, name) = callee else {
+                    return Err(Stop::Failed(EvalError::NotAFunction));
+                };
+                let Node::Function { params, body } = self.node(function) else {
+                    unreachable!("closures are only made from functions");
+                };
+                if params.len() != args.len() {
+                    return Err(Stop::Failed(EvalError::ArityMismatch { expected: params.len(), found: args.len() }));
+                }
+                let mut env = closure_env;
+                if let Some(name) = name {
+                    env = evaluation.bind(env, name, Value::Closure(function, closure_env, Some(name)));
+                }
+                for (param, arg) in params.iter().zip(args) {
+                    env = evaluation.bind(env, param, arg);
+                }
+                evaluation.called(*body, env)
+            }
+            Node::Var(name) => evaluation.lookup(env, name).cloned().ok_or_else(|| Stop::Failed(EvalError::UnboundVariable(name.clone()))),
+            Node::Value(v) => v.clone().check().map(Value::Number).map_err(Stop::Failed),
+            Node::Bool(b) => Ok(Value::Number(N::from_bool(*b))),
+        }
+    }
+}
+
+/// x(k+1) = (x(k) * x(k) - x(k)) % 1000003 as a tree, where every use of x(k) is a separate copy, so there are
+/// 3^depth copies of the innermost term. For tests and benchmarks of sharing.
+#[doc(hidden)]
+pub fn squares(depth: u32) -> Expression {
+    let mut expr = Expression::Value(2);
+    for _ in 0..depth {
+        let difference = Expression::Op {
+            op: Operation::Sub,
+            left: Box::new(Expression::Op { op: Operation::Mul, left: Box::new(expr.clone()), right: Box::new(expr.clone()) }),
+            right: Box::new(expr),
+        };
+        expr = Expression::Op { op: Operation::Rem, left: Box::new(difference), right: Box::new(Expression::Value(1_000_003)) };
+    }
+    expr
+}
+
+#[test]
+fn test_hash_consing() {
+    let mut dag = Dag::new();
+    let expr = squares(10);
+    let root = dag.insert(&expr);
+    // 2 literals, then a `*`, `-` and `%` per level
+    assert_eq!(dag.len(), 2 + 3 * 10);
+    assert_eq!(dag.insert(&squares(10)), root);
+    assert_eq!(dag.insert(&squares(3)), dag.insert(&squares(3)));
+    assert_eq!(dag.to_expression(root), expr);
+}
+
+#[test]
+fn test_round_trip() {
+    for src in ["1 + 2 * 3", "let sq = fn(x) x * x in sq(sq(2)) + sq(2)", "if a < b then -a else !b", "f(x, x, f(x, x, x))"] {
+        let expr = crate::parser::parse(src).unwrap();
+        let mut dag = Dag::new();
+        let root = dag.insert(&expr);
+        assert_eq!(dag.to_expression(root), expr, "{src}");
+        let spanned = dag.insert(&crate::parser::parse_spanned(src).unwrap());
+        assert_eq!(spanned, root, "{src}");
+    }
+}
+
+#[test]
+fn test_memoized_evaluation() {
+    let mut dag = Dag::new();
+    let expr = squares(10);
+    let root = dag.insert(&expr);
+    assert_eq!(dag.evaluate(root), crate::expression::evaluate(&expr));
+    // 3^60 leaves as a tree, but only 3 nodes per level as a DAG
+    let mut dag = Dag::new();
+    let mut x = dag.intern(Node::Value(2));
+    let modulus = dag.intern(Node::Value(1_000_003));
+    for _ in 0..60 {
+        let square = dag.intern(Node::Op { op: Operation::Mul, left: x, right: x });
+        let difference = dag.intern(Node::Op { op: Operation::Sub, left: square, right: x });
+        x = dag.intern(Node::Op { op: Operation::Rem, left: difference, right: modulus });
+    }
+    let mut expected = 2i64;
+    for _ in 0..60 {
+        expected = (expected * expected - expected) % 1_000_003;
+    }
+    assert_eq!(dag.evaluate(x), Ok(expected));
+
+    // the same under a `let` and in a function body, where each node is still computed once per environment
+    let name = dag.intern(Node::Var(String::from("y")));
+    let body = dag.intern(Node::Op { op: Operation::Add, left: x, right: name });
+    let one = dag.intern(Node::Value(1));
+    let under_let = dag.intern(Node::Let { name: String::from("y"), value: one, body });
+    assert_eq!(dag.evaluate(under_let), Ok(expected + 1));
+    let function = dag.intern(Node::Function { params: vec![String::from("y")], body });
+    let call = dag.intern(Node::Call { callee: function, args: vec![one] });
+    let twice = dag.intern(Node::Op { op: Operation::Add, left: call, right: under_let });
+    assert_eq!(dag.evaluate(twice), Ok(2 * expected + 2));
+}
+
+#[test]
+fn test_evaluation_matches_tree() {
+    for src in [
+        "(1 + 2) * (1 + 2) / (3 - 3)",
+        "false && 1 / 0 == 1 || 2 ** 2 == 4",
+        "if 1 > 2 then 1 / 0 else -(3 % 2)",
+        "let x = 4 in x * x + (let y = x in y)",
+        "(fn(a, b) a - b)(10, 3) * 2",
+        "y + 1",
+        "fn(x) x",
+        "9223372036854775807 + 1",
+        "(fn(a) a) < (1 + 2)(3)",
+        "(let f = fn(a) a in f) - y",
+        "let fact = fn(n) if n <= 1 then 1 else n * fact(n - 1) in fact(20)",
+        "let adder = fn(n) fn(x) x + n in adder(5)(2) * adder(5)(2) + adder(6)(2)",
+        "let f = fn(n) f(n) + 1 in f(0)",
+        "let f = fn(n) if n == 0 then 0 else f(n - 1) in f(9999)",
+        "let f = fn(n) if n == 0 then 0 else f(n - 1) in f(10000)",
+        "(fn(a, b) a)(1) + (3)(4) + z",
+        "let x = 1 in (fn(y) x + y)(let x = 2 in x)",
+    ] {
+        let expr = crate::parser::parse(src).unwrap();
+        let mut dag = Dag::new();
+        let root = dag.insert(&expr);
+        assert_eq!(dag.evaluate(root), crate::expression::evaluate(&expr), "{src}");
+    }
+}
+
+#[test]
+fn test_evaluation_call_limit() {
+    // reused results count the calls they took, whether they are reached by a call or not
+    for (src, limit) in [
+        ("let f = fn(n) if n == 0 then 0 else f(n - 1) in f(9999) + f(10000)", DEFAULT_MAX_CALL_DEPTH),
+        ("let f = fn(n) if n == 0 then 0 else f(n - 1) in f(99) + f(100)", 100),
+        ("let f = fn(n) if n == 0 then 0 else f(n - 1) in f(99) + f(99)", 100),
+        ("let f = fn(n) if n == 0 then 0 else f(n - 1) in f(50) + (fn(x) f(50))(0)", 51),
+        ("let f = fn(n) if n == 0 then 0 else f(n - 1) in f(50) + (fn(x) f(50))(0)", 52),
+        ("let g = fn(x) (let f = fn(n) if n == 0 then 0 else f(n - 1) in f(5) + 1) in g(0) + (fn(y) g(0))(0)", 7),
+        ("let g = fn(x) (let f = fn(n) if n == 0 then 0 else f(n - 1) in f(5) + 1) in g(0) + (fn(y) g(0))(0)", 8),
+    ] {
+        let expr = crate::parser::parse(src).unwrap();
+        let mut dag = Dag::new();
+        let root = dag.insert(&expr);
+        let expected = crate::expression::evaluate_with_call_limit(&expr, limit);
+        assert_eq!(dag.evaluate_with_call_limit(root, limit), expected, "{src} with {limit}");
+    }
+}
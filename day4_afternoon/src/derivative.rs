@@ -0,0 +1,301 @@
+use std::fmt;
+
+use crate::expression::{Expression, Operation, UnaryOperation};
+use crate::number::Number;
+use crate::simplify::simplify;
+use crate::visit::{Layer, Order};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffError {
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for DiffError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DiffError::Unsupported(what) => write!(f, "cannot differentiate {what}"),
+        }
+    }
+}
+
+impl std::error::Error for DiffError {}
+
+/// The derivative of `e` with respect to the variable `var`, simplified.
+///
+/// Comparisons and boolean operators are treated as piecewise constant, and `if` is differentiated branch by
+/// branch. Terms whose derivative is zero are left out rather than multiplied by zero, so an error in such a term,
+/// like the `1 / 0` in `x + 1 / 0`, does not carry over to the derivative.
+pub fn differentiate<N: Number>(e: &Expression<N>, var: &str) -> Result<Expression<N>, DiffError> {
+    let mut names = Vec::new();
+    collect_names(e, &mut names);
+    let mut d = Differentiator { scope: vec![(var.to_string(), Some(Expression::Value(N::one())))], names };
+    Ok(simplify(&d.derivative(e)?.unwrap_or(Expression::Value(N::zero()))))
+}
+
+struct Differentiator<N> {
+    /// The derivative of each variable in scope, innermost last. `None` means zero.
+    scope: Vec<(String, Option<Expression<N>>)>,
+    /// Every name used in the expression, so introduced names do not clash with them.
+    names: Vec<String>,
+}
+
+fn collect_names<N>(e: &Expression<N>, names: &mut Vec<String>) {
+    for e in e.nodes(Order::Pre) {
+        match e.layer() {
+            Layer::Let { name, .. } | Layer::Var(name) => names.push(name.to_string()),
+            Layer::Function { params, .. } => names.extend(params.iter().cloned()),
+            _ => {}
+        }
+    }
+}
+
+fn op<N>(op: Operation, left: Expression<N>, right: Expression<N>) -> Expression<N> {
+    Expression::Op { op, left: Box::new(left), right: Box::new(right) }
+}
+
+fn neg<N>(operand: Expression<N>) -> Expression<N> {
+    Expression::Unary { op: UnaryOperation::Neg, operand: Box::new(operand) }
+}
+
+impl<N: Number> Differentiator<N> {
+    fn fresh_name(&mut self, base: &str) -> String {
+        let mut name = format!("d_{base}");
+        let mut n = 1;
+        while self.names.contains(&name) {
+            n += 1;
+            name = format!("d_{base}_{n}");
+        }
+        self.names.push(name.clone());
+        name
+    }
+
+    // `None` stands for a derivative that is identically zero.
+    //
+    // Runs on a heap stack of tasks rather than recursing, so deep trees can't overflow the stack. Subexpressions
+    // are differentiated in the same order as they would be by recursing, so the first error found is the same.
+    fn derivative(&mut self, e: &Expression<N>) -> Result<Option<Expression<N>>, DiffError> {
+        enum Task<'a, N> {
+            Derive(&'a Expression<N>),
+            // builds the derivative of a node from those of its children, the last results
+            Combine(&'a Expression<N>),
+            // after the exponent's derivative: `left ** right`
+            Exponent(&'a Expression<N>, &'a Expression<N>),
+            // after the base's derivative
+            Power(&'a Expression<N>, Expression<N>),
+            // after the value's derivative: `let name = value in body`
+            Bind(&'a String, &'a Expression<N>, &'a Expression<N>),
+            // after the body's derivative, with the binding for the value's derivative, if it needs one
+            Unbind(&'a String, &'a Expression<N>, Option<(String, Expression<N>)>),
+        }
+        let mut tasks = vec![Task::Derive(e)];
+        let mut results = Vec::new();
+        let pop = |results: &mut Vec<Option<Expression<N>>>| results.pop().expect("each task leaves a result");
+        while let Some(task) = tasks.pop() {
+            match task {
+                Task::Derive(e) => match e {
+                    Expression::Op { op: Operation::Add | Operation::Sub | Operation::Mul | Operation::Div, left, right } => {
+                        tasks.extend([Task::Combine(e), Task::Derive(right), Task::Derive(left)]);
+                    }
+                    Expression::Op { op: Operation::Pow, left, right } => tasks.extend([Task::Exponent(left, right), Task::Derive(right)]),
+                    Expression::Op { op: Operation::Rem, .. } => return Err(DiffError::Unsupported("a remainder")),
+                    Expression::Op { .. } => results.push(None),
+                    Expression::Unary { op: UnaryOperation::Neg, operand } => tasks.extend([Task::Combine(e), Task::Derive(operand)]),
+                    Expression::Unary { op: UnaryOperation::Not, .. } => results.push(None),
+                    Expression::If { then, otherwise, .. } => tasks.extend([Task::Combine(e), Task::Derive(otherwise), Task::Derive(then)]),
+                    Expression::Let { name, value, body } => tasks.extend([Task::Bind(name, value, body), Task::Derive(value)]),
+                    Expression::Function { .. } | Expression::Call { .. } => return Err(DiffError::Unsupported("functions")),
+                    Expression::Var(name) => results.push(match self.scope.iter().rev().find(|(n, _)| n == name) {
+                        Some((_, d)) => d.clone(),
+                        None => None,
+                    }),
+                    Expression::Value(_) | Expression::Bool(_) => results.push(None),
+                    Expression::Spanned { inner, .. } => tasks.push(Task::Derive(inner)),
+                },
+                Task::Combine(e) => {
+                    let d = match e {
+                        Expression::Op { op: Operation::Add, .. } => match (pop(&mut results), pop(&mut results)) {
+                            (Some(r), Some(l)) => Some(op(Operation::Add, l, r)),
+                            (r, l) => l.or(r),
+                        },
+                        Expression::Op { op: Operation::Sub, .. } => match (pop(&mut results), pop(&mut results)) {
+                            (Some(r), Some(l)) => Some(op(Operation::Sub, l, r)),
+                            (r, l) => l.or(r.map(neg)),
+                        },
+                        // (uv)' = u'v + uv'
+                        Expression::Op { op: Operation::Mul, left, right } => {
+                            let r = pop(&mut results).map(|r| op(Operation::Mul, (**left).clone(), r));
+                            let l = pop(&mut results).map(|l| op(Operation::Mul, l, (**right).clone()));
+                            match (l, r) {
+                                (Some(l), Some(r)) => Some(op(Operation::Add, l, r)),
+                                (l, r) => l.or(r),
+                            }
+                        }
+                        // (u/v)' = (u'v - uv') / v²
+                        Expression::Op { op: Operation::Div, left, right } => match (pop(&mut results), pop(&mut results)) {
+                            (None, l) => l.map(|l| op(Operation::Div, l, (**right).clone())),
+                            (Some(r), l) => {
+                                let r = op(Operation::Mul, (**left).clone(), r);
+                                let numerator = match l {
+                                    Some(l) => op(Operation::Sub, op(Operation::Mul, l, (**right).clone()), r),
+                                    None => neg(r),
+                                };
+                                let square = op(Operation::Pow, (**right).clone(), Expression::Value(N::one().try_add(N::one()).unwrap()));
+                                Some(op(Operation::Div, numerator, square))
+                            }
+                        },
+                        Expression::Unary { .. } => pop(&mut results).map(neg),
+                        Expression::If { cond, .. } => match (pop(&mut results), pop(&mut results)) {
+                            (None, None) => None,
+                            (otherwise, then) => Some(Expression::If {
+                                cond: cond.clone(),
+                                then: Box::new(then.unwrap_or(Expression::Value(N::zero()))),
+                                otherwise: Box::new(otherwise.unwrap_or(Expression::Value(N::zero()))),
+                            }),
+                        },
+                        _ => unreachable!("only nodes with a rule are combined"),
+                    };
+                    results.push(d);
+                }
+                // (uⁿ)' = n·uⁿ⁻¹·u', for any n that does not depend on the variable
+                Task::Exponent(left, right) => {
+                    if pop(&mut results).is_some() {
+                        return Err(DiffError::Unsupported("a power with a variable exponent"));
+                    }
+                    let exponent = simplify(right);
+                    if matches!(exponent, Expression::Value(ref n) if n.is_zero()) {
+                        results.push(None);
+                    } else {
+                        tasks.extend([Task::Power(left, exponent), Task::Derive(left)]);
+                    }
+                }
+                Task::Power(left, exponent) => {
+                    let d = pop(&mut results).map(|l| {
+                        let reduced = op(Operation::Sub, exponent.clone(), Expression::Value(N::one()));
+                        op(Operation::Mul, op(Operation::Mul, exponent, op(Operation::Pow, left.clone(), reduced)), l)
+                    });
+                    results.push(d);
+                }
+                // The chain rule through a binding: the body sees `name`'s derivative under a fresh name, bound
+                // outside `name` so that it still refers to the variables `value` was written against.
+                Task::Bind(name, value, body) => {
+                    let (binding, d_name) = match pop(&mut results).map(|d| simplify(&d)) {
+                        Some(d @ (Expression::Value(_) | Expression::Bool(_))) => (None, Some(d)),
+                        Some(d) => {
+                            let fresh = self.fresh_name(name);
+                            (Some((fresh.clone(), d)), Some(Expression::Var(fresh)))
+                        }
+                        None => (None, None),
+                    };
+                    self.scope.push((name.clone(), d_name));
+                    tasks.extend([Task::Unbind(name, value, binding), Task::Derive(body)]);
+                }
+                Task::Unbind(name, value, binding) => {
+                    self.scope.pop();
+                    let d = pop(&mut results).map(|d_body| {
+                        let inner = Expression::Let { name: name.clone(), value: Box::new(value.clone()), body: Box::new(d_body) };
+                        match binding {
+                            Some((fresh, d)) => Expression::Let { name: fresh, value: Box::new(d), body: Box::new(inner) },
+                            None => inner,
+                        }
+                    });
+                    results.push(d);
+                }
+            }
+        }
+        Ok(pop(&mut results))
+    }
+}
+
+#[cfg(test)]
+fn d(src: &str) -> String {
+    match differentiate(&crate::parser::parse(src).unwrap(), "x") {
+        Ok(e) => e.to_string(),
+        Err(e) => e.to_string(),
+    }
+}
+
+#[test]
+fn test_differentiation_rules() {
+    assert_eq!(d("3 * x + y"), "3");
+    assert_eq!(d("x * x"), "x + x");
+    assert_eq!(d("x ** 3 - 2 * x"), "3 * x ** 2 - 2");
+    assert_eq!(d("1 / x"), "-1 / x ** 2");
+    assert_eq!(d("x / (x + 1)"), "(x + 1 - x) / (x + 1) ** 2");
+    assert_eq!(d("(x * x + 1) ** 2"), "2 * (x * x + 1) * (x + x)");
+    assert_eq!(d("-(y * x)"), "-y");
+    assert_eq!(d("if x > 0 then x else -x"), "if x > 0 then 1 else -1");
+    assert_eq!(d("x ** 0 + 7"), "0");
+}
+
+#[test]
+fn test_differentiation_through_let() {
+    assert_eq!(d("let u = x * x in u * u"), "let d_u = x + x in let u = x * x in d_u * u + u * d_u");
+    // the inner `x` shadows the variable being differentiated
+    assert_eq!(d("let x = 5 in x * x"), "0");
+    assert_eq!(d("let y = 2 * x in let d_y = 1 in y + d_y"), "let y = 2 * x in let d_y = 1 in 2");
+}
+
+#[test]
+fn test_differentiating_deep_trees() {
+    assert_eq!(d(&format!("x{}", " + 1".repeat(200_000))), "1");
+    assert_eq!(d(&format!("x{}", " * 1".repeat(200_000))), "1");
+    let nested = d(&format!("{}x{}", "let y = 2 in -(".repeat(100_000), ")".repeat(100_000)));
+    assert!(nested.starts_with("let y = 2 in -(let y = 2 in -("));
+    assert!(nested.ends_with(&format!("-1{}", ")".repeat(99_999))));
+    assert_eq!(d(&format!("x{} % 2", " + 1".repeat(200_000))), "cannot differentiate a remainder");
+}
+
+#[test]
+fn test_differentiation_errors() {
+    assert_eq!(d("2 ** x"), "cannot differentiate a power with a variable exponent");
+    assert_eq!(d("x % 3"), "cannot differentiate a remainder");
+    assert_eq!(d("let f = fn(t) t * t in f(x)"), "cannot differentiate functions");
+}
+
+#[cfg(test)]
+fn random_formula(random: &mut crate::fuzz::Generator, depth: u32) -> Expression<f64> {
+    if depth == 0 || random.below(4) == 0 {
+        return match random.below(3) {
+            0 => Expression::Value(random.below(5) as f64 + 1.0),
+            _ => Expression::Var(String::from("x")),
+        };
+    }
+    let next = |random: &mut crate::fuzz::Generator| random_formula(random, depth - 1);
+    match random.below(7) {
+        0 => neg(next(random)),
+        1 => {
+            let exponent = [-1.0, 2.0, 3.0][random.below(3)];
+            op(Operation::Pow, next(random), Expression::Value(exponent))
+        }
+        2 => Expression::Let { name: String::from("u"), value: Box::new(next(random)), body: Box::new(op(Operation::Mul, Expression::Var(String::from("u")), next(random))) },
+        n => op([Operation::Add, Operation::Sub, Operation::Mul, Operation::Div][n - 3], next(random), next(random)),
+    }
+}
+
+#[test]
+fn test_derivatives_match_finite_differences() {
+    let at = |e: &Expression<f64>, x: f64| {
+        let bound = Expression::Let { name: String::from("x"), value: Box::new(Expression::Value(x)), body: Box::new(e.clone()) };
+        crate::expression::evaluate(&bound).ok()
+    };
+    let mut random = crate::fuzz::Generator::new(0);
+    let mut checked = 0;
+    for _ in 0..2000 {
+        let f = random_formula(&mut random, 5);
+        let df = differentiate(&f, "x").unwrap();
+        for x in [-2.7, -0.6, 0.35, 1.3, 3.1] {
+            let h = 1e-6;
+            let (Some(exact), Some(above), Some(below)) = (at(&df, x), at(&f, x + h), at(&f, x - h)) else {
+                continue;
+            };
+            // near poles the difference quotient says nothing useful
+            if exact.abs() > 1e4 || above.abs() > 1e6 || below.abs() > 1e6 {
+                continue;
+            }
+            let numeric = (above - below) / (2.0 * h);
+            assert!((numeric - exact).abs() <= 1e-3 * exact.abs().max(1.0), "d/dx {f} = {df} at x = {x}: {exact} vs {numeric}");
+            checked += 1;
+        }
+    }
+    assert!(checked > 5000, "only {checked} points were checked");
+}
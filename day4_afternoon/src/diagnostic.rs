@@ -0,0 +1,149 @@
+use std::fmt::Write;
+
+/// A half-open range of byte offsets into the source text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+
+    /// The smallest span covering both.
+    pub fn to(self, other: Span) -> Span {
+        Span { start: self.start.min(other.start), end: self.end.max(other.end) }
+    }
+}
+
+/// An error message, optionally pointing at the part of the source it is about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Option<Span>,
+}
+
+// Where a span starts, for people: 1-based line and column, plus the text of that line.
+struct Position<'a> {
+    line: usize,
+    column: usize,
+    text: &'a str,
+    /// How many characters of the span fall on this line, at least one.
+    width: usize,
+}
+
+fn position(source: &str, span: Span) -> Position<'_> {
+    let start = span.start.min(source.len());
+    let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = source[start..].find('\n').map_or(source.len(), |i| start + i);
+    let text = &source[line_start..line_end];
+    let end = span.end.clamp(start, line_end);
+    Position {
+        line: source[..line_start].matches('\n').count() + 1,
+        column: source[line_start..start].chars().count() + 1,
+        text,
+        width: source[start..end].chars().count().max(1),
+    }
+}
+
+impl Diagnostic {
+    pub fn new(message: impl Into<String>, span: Option<Span>) -> Self {
+        Diagnostic { message: message.into(), span }
+    }
+
+    /// Renders the message with the offending source line and a caret underline:
+    ///
+    /// ```text
+    /// error: division by zero
+    ///  --> 1:4
+    ///   |
+    /// 1 | 10 / (5 - 5)
+    ///   |    ^
+    /// ```
+    pub fn render(&self, source: &str) -> String {
+        let mut out = format!("error: {}", self.message);
+        let Some(span) = self.span else {
+            return out;
+        };
+        let pos = position(source, span);
+        let gutter = " ".repeat(pos.line.to_string().len());
+        let _ = write!(
+            out,
+            "\n{gutter}--> {}:{}\n{gutter} |\n{} | {}\n{gutter} | {}{}",
+            pos.line,
+            pos.column,
+            pos.line,
+            pos.text,
+            " ".repeat(pos.column - 1),
+            "^".repeat(pos.width)
+        );
+        out
+    }
+
+    /// The same information as `render`, as a single line of JSON.
+    pub fn to_json(&self, source: &str) -> String {
+        let mut out = format!("{{\"message\":{}", json_string(&self.message));
+        match self.span {
+            Some(span) => {
+                let pos = position(source, span);
+                let _ = write!(
+                    out,
+                    ",\"span\":{{\"start\":{},\"end\":{},\"line\":{},\"column\":{}}},\"source_line\":{}}}",
+                    span.start,
+                    span.end,
+                    pos.line,
+                    pos.column,
+                    json_string(pos.text)
+                );
+            }
+            None => out.push_str(",\"span\":null,\"source_line\":null}"),
+        }
+        out
+    }
+}
+
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[test]
+fn test_render_caret() {
+    let diagnostic = Diagnostic::new("division by zero", Some(Span::new(3, 4)));
+    assert_eq!(diagnostic.render("10 / (5 - 5)"), "error: division by zero\n --> 1:4\n  |\n1 | 10 / (5 - 5)\n  |    ^");
+}
+
+#[test]
+fn test_render_multiline() {
+    let source = "let x = 1 in\nx + nope";
+    let diagnostic = Diagnostic::new("unbound variable 'nope'", Some(Span::new(17, 21)));
+    assert_eq!(diagnostic.render(source), "error: unbound variable 'nope'\n --> 2:5\n  |\n2 | x + nope\n  |     ^^^^");
+    assert_eq!(Diagnostic::new("overflow", None).render(source), "error: overflow");
+}
+
+#[test]
+fn test_json() {
+    let diagnostic = Diagnostic::new("unexpected \"$\"", Some(Span::new(2, 3)));
+    assert_eq!(
+        diagnostic.to_json("1 $ 2"),
+        r#"{"message":"unexpected \"$\"","span":{"start":2,"end":3,"line":1,"column":3},"source_line":"1 $ 2"}"#
+    );
+    assert_eq!(Diagnostic::new("overflow", None).to_json(""), r#"{"message":"overflow","span":null,"source_line":null}"#);
+}
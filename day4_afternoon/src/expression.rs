@@ -0,0 +1,878 @@
+use std::fmt;
+use std::rc::Rc;
+
+use crate::diagnostic::{Diagnostic, Span};
+use crate::number::Number;
+use crate::visit::{Layer, Order};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Pow,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnaryOperation {
+    Neg,
+    Not,
+}
+
+/// Booleans are numbers, as in C: `false` is 0, `true` is 1, and any non-zero value counts as true.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Expression<N = i64> {
+    Op { op: Operation, left: Box<Expression<N>>, right: Box<Expression<N>> },
+    Unary { op: UnaryOperation, operand: Box<Expression<N>> },
+    If { cond: Box<Expression<N>>, then: Box<Expression<N>>, otherwise: Box<Expression<N>> },
+    /// `let name = value in body`. When `value` is a function, `name` is also bound inside it, so it can recurse.
+    Let { name: String, value: Box<Expression<N>>, body: Box<Expression<N>> },
+    /// `fn(params) body`, which evaluates to a closure over the variables in scope.
+    Function { params: Vec<String>, body: Box<Expression<N>> },
+    Call { callee: Box<Expression<N>>, args: Vec<Expression<N>> },
+    Var(String),
+    Value(N),
+    Bool(bool),
+    /// Marks where `inner` came from in the source text. Only `parse_spanned` produces these;
+    /// everything else looks straight through them.
+    Spanned { span: Span, inner: Box<Expression<N>> },
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum EvalError {
+    DivideByZero,
+    NegativeExponent,
+    NonIntegerExponent,
+    NotANumber,
+    Overflow,
+    /// A result would take more than this many bits to store, so it is not computed at all.
+    TooLarge { max_bits: u64 },
+    UnboundVariable(String),
+    /// A function was used where a number was needed, e.g. as an operand or as the final result.
+    ExpectedNumber,
+    NotAFunction,
+    ArityMismatch { expected: usize, found: usize },
+    CallDepthExceeded(usize),
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EvalError::DivideByZero => write!(f, "division by zero"),
+            EvalError::NegativeExponent => write!(f, "negative exponent"),
+            EvalError::NonIntegerExponent => write!(f, "exponent is not an integer"),
+            EvalError::NotANumber => write!(f, "result is not a number"),
+            EvalError::Overflow => write!(f, "overflow"),
+            EvalError::TooLarge { max_bits } => write!(f, "result would be larger than {max_bits} bits"),
+            EvalError::UnboundVariable(name) => write!(f, "unbound variable '{name}'"),
+            EvalError::ExpectedNumber => write!(f, "expected a number, found a function"),
+            EvalError::NotAFunction => write!(f, "called a value that is not a function"),
+            EvalError::ArityMismatch { expected, found } => {
+                write!(f, "function takes {expected} argument(s) but {found} were given")
+            }
+            EvalError::CallDepthExceeded(limit) => write!(f, "call depth limit of {limit} exceeded"),
+        }
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+/// An `EvalError` together with the source span of the operation that raised it, when known.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct LocatedError {
+    pub error: EvalError,
+    pub span: Option<Span>,
+}
+
+impl LocatedError {
+    pub fn diagnostic(&self) -> Diagnostic {
+        Diagnostic::new(self.error.to_string(), self.span)
+    }
+}
+
+impl fmt::Display for LocatedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.span {
+            Some(span) => write!(f, "{} at offset {}", self.error, span.start),
+            None => write!(f, "{}", self.error),
+        }
+    }
+}
+
+impl std::error::Error for LocatedError {}
+
+pub(crate) const UNARY_PRECEDENCE: u8 = 6;
+
+impl Operation {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Operation::Add => "+",
+            Operation::Sub => "-",
+            Operation::Mul => "*",
+            Operation::Div => "/",
+            Operation::Rem => "%",
+            Operation::Pow => "**",
+            Operation::Eq => "==",
+            Operation::Ne => "!=",
+            Operation::Lt => "<",
+            Operation::Le => "<=",
+            Operation::Gt => ">",
+            Operation::Ge => ">=",
+            Operation::And => "&&",
+            Operation::Or => "||",
+        }
+    }
+
+    pub(crate) fn precedence(self) -> u8 {
+        match self {
+            Operation::Or => 1,
+            Operation::And => 2,
+            Operation::Eq | Operation::Ne | Operation::Lt | Operation::Le | Operation::Gt | Operation::Ge => 3,
+            Operation::Add | Operation::Sub => 4,
+            Operation::Mul | Operation::Div | Operation::Rem => 5,
+            Operation::Pow => 7,
+        }
+    }
+
+    pub(crate) fn is_right_associative(self) -> bool {
+        self == Operation::Pow
+    }
+
+    /// Whether the result is a truth value rather than a number.
+    pub fn is_boolean(self) -> bool {
+        matches!(
+            self,
+            Operation::Eq | Operation::Ne | Operation::Lt | Operation::Le | Operation::Gt | Operation::Ge | Operation::And | Operation::Or
+        )
+    }
+}
+
+impl UnaryOperation {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            UnaryOperation::Neg => "-",
+            UnaryOperation::Not => "!",
+        }
+    }
+}
+
+impl<N> Expression<N> {
+    /// Converts every literal, e.g. to evaluate an `i64` tree with another backend. Spans are kept.
+    pub fn map_values<M>(&self, f: &impl Fn(&N) -> M) -> Expression<M> {
+        // `fold` looks through `Spanned` wrappers, so they are put back from `nodes`, which visits the same nodes
+        // in the same order
+        let mut nodes = self.nodes(Order::Post);
+        self.fold(|layer| {
+            let e = match layer {
+                Layer::Op { op, left, right } => Expression::Op { op, left: Box::new(left), right: Box::new(right) },
+                Layer::Unary { op, operand } => Expression::Unary { op, operand: Box::new(operand) },
+                Layer::If { cond, then, otherwise } => {
+                    Expression::If { cond: Box::new(cond), then: Box::new(then), otherwise: Box::new(otherwise) }
+                }
+                Layer::Let { name, value, body } => Expression::Let { name: name.to_string(), value: Box::new(value), body: Box::new(body) },
+                Layer::Function { params, body } => Expression::Function { params: params.to_vec(), body: Box::new(body) },
+                Layer::Call { callee, args } => Expression::Call { callee: Box::new(callee), args },
+                Layer::Var(name) => Expression::Var(name.to_string()),
+                Layer::Value(v) => Expression::Value(f(v)),
+                Layer::Bool(b) => Expression::Bool(b),
+            };
+            let mut node = nodes.next().expect("`fold` and `nodes` visit the same nodes");
+            let mut spans = Vec::new();
+            while let Expression::Spanned { span, inner } = node {
+                spans.push(*span);
+                node = inner;
+            }
+            spans.into_iter().rev().fold(e, |e, span| Expression::Spanned { span, inner: Box::new(e) })
+        })
+    }
+
+    /// The expression under any `Spanned` wrappers.
+    pub fn unspanned(&self) -> &Expression<N> {
+        let mut e = self;
+        while let Expression::Spanned { inner, .. } = e {
+            e = inner;
+        }
+        e
+    }
+
+    /// The innermost source span wrapped around this expression, if it came from `parse_spanned`.
+    pub fn span(&self) -> Option<Span> {
+        let mut e = self;
+        let mut span = None;
+        while let Expression::Spanned { span: s, inner } = e {
+            span = Some(*s);
+            e = inner;
+        }
+        span
+    }
+
+    fn is_leaf(&self) -> bool {
+        matches!(self, Expression::Var(_) | Expression::Value(_) | Expression::Bool(_))
+    }
+
+    // Moves out every child that has children of its own, leaving a leaf in its place.
+    fn take_children(&mut self, out: &mut Vec<Expression<N>>) {
+        for child in self.children_mut() {
+            if !child.is_leaf() {
+                out.push(std::mem::replace(child, Expression::Bool(false)));
+            }
+        }
+    }
+}
+
+// The derived drop glue would recurse once per level and overflow the stack on deep trees,
+// so subtrees are detached onto a heap stack and dropped one shallow node at a time.
+impl<N> Drop for Expression<N> {
+    fn drop(&mut self) {
+        let mut pending = Vec::new();
+        self.take_children(&mut pending);
+        while let Some(mut e) = pending.pop() {
+            e.take_children(&mut pending);
+        }
+    }
+}
+
+// Like dropping, the derived clone would recurse once per level.
+impl<N: Clone> Clone for Expression<N> {
+    fn clone(&self) -> Self {
+        self.map_values(&N::clone)
+    }
+}
+
+impl<N: Number> Expression<N> {
+    fn precedence(&self) -> u8 {
+        match self {
+            Expression::Op { op, .. } => op.precedence(),
+            Expression::Unary { .. } => UNARY_PRECEDENCE,
+            Expression::If { .. } | Expression::Let { .. } | Expression::Function { .. } => 0,
+            Expression::Value(v) if *v < N::zero() => UNARY_PRECEDENCE,
+            Expression::Call { .. } | Expression::Var(_) | Expression::Value(_) | Expression::Bool(_) => u8::MAX,
+            Expression::Spanned { inner, .. } => inner.precedence(),
+        }
+    }
+}
+
+// Prints infix with the fewest parentheses that still parse back to the same tree,
+// so `1 + (2 + 3)` keeps its parentheses while `(1 + 2) + 3` drops them.
+impl<N: Number> fmt::Display for Expression<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Writes from a heap stack of pieces rather than recursing, so deep trees can't overflow the stack.
+        enum Piece<'a, N> {
+            Text(&'a str),
+            Tree(&'a Expression<N>),
+        }
+        let mut pending = vec![Piece::Tree(self)];
+        let mut pieces = Vec::new();
+        while let Some(piece) = pending.pop() {
+            let e = match piece {
+                Piece::Text(text) => {
+                    f.write_str(text)?;
+                    continue;
+                }
+                Piece::Tree(e) => e,
+            };
+            let tree = |pieces: &mut Vec<_>, e, parens: bool| {
+                if parens {
+                    pieces.extend([Piece::Text("("), Piece::Tree(e), Piece::Text(")")]);
+                } else {
+                    pieces.push(Piece::Tree(e));
+                }
+            };
+            match e {
+                Expression::Op { op, left, right } => {
+                    let (left_parens, right_parens) = if op.is_right_associative() {
+                        (left.precedence() <= op.precedence(), right.precedence() < UNARY_PRECEDENCE)
+                    } else {
+                        (left.precedence() < op.precedence(), right.precedence() <= op.precedence())
+                    };
+                    tree(&mut pieces, left, left_parens);
+                    pieces.extend([Piece::Text(" "), Piece::Text(op.symbol()), Piece::Text(" ")]);
+                    tree(&mut pieces, right, right_parens);
+                }
+                // `-(3)` must keep its parentheses, or it would read back as the literal -3.
+                Expression::Unary { op, operand } => {
+                    let literal = *op == UnaryOperation::Neg && matches!(operand.unspanned(), Expression::Value(_));
+                    pieces.push(Piece::Text(op.symbol()));
+                    tree(&mut pieces, operand, literal || operand.precedence() < UNARY_PRECEDENCE);
+                }
+                Expression::If { cond, then, otherwise } => pieces.extend([
+                    Piece::Text("if "),
+                    Piece::Tree(cond),
+                    Piece::Text(" then "),
+                    Piece::Tree(then),
+                    Piece::Text(" else "),
+                    Piece::Tree(otherwise),
+                ]),
+                Expression::Let { name, value, body } => pieces.extend([
+                    Piece::Text("let "),
+                    Piece::Text(name),
+                    Piece::Text(" = "),
+                    Piece::Tree(value),
+                    Piece::Text(" in "),
+                    Piece::Tree(body),
+                ]),
+                Expression::Function { params, body } => {
+                    pieces.push(Piece::Text("fn("));
+                    for (i, param) in params.iter().enumerate() {
+                        if i > 0 {
+                            pieces.push(Piece::Text(", "));
+                        }
+                        pieces.push(Piece::Text(param));
+                    }
+                    pieces.extend([Piece::Text(") "), Piece::Tree(body)]);
+                }
+                Expression::Call { callee, args } => {
+                    tree(&mut pieces, callee, callee.precedence() < u8::MAX);
+                    pieces.push(Piece::Text("("));
+                    for (i, arg) in args.iter().enumerate() {
+                        if i > 0 {
+                            pieces.push(Piece::Text(", "));
+                        }
+                        pieces.push(Piece::Tree(arg));
+                    }
+                    pieces.push(Piece::Text(")"));
+                }
+                Expression::Var(name) => f.write_str(name)?,
+                Expression::Value(v) => write!(f, "{v}")?,
+                Expression::Bool(b) => write!(f, "{b}")?,
+                Expression::Spanned { inner, .. } => pieces.push(Piece::Tree(inner)),
+            }
+            pending.extend(pieces.drain(..).rev());
+        }
+        Ok(())
+    }
+}
+
+pub fn eval(e: &Expression) -> Result<i64, EvalError> {
+    evaluate(e)
+}
+
+/// How deeply function calls may nest before `evaluate` gives up with `CallDepthExceeded`.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 10_000;
+
+// Variables in scope, innermost first. Closures share the tail of the chain they were created in.
+type Scope<'a, N> = Option<Rc<Binding<'a, N>>>;
+
+struct Binding<'a, N> {
+    name: &'a str,
+    value: Value<'a, N>,
+    next: Scope<'a, N>,
+}
+
+// Long chains are unlinked one binding at a time, for the same reason as `Expression`'s drop.
+impl<N> Drop for Binding<'_, N> {
+    fn drop(&mut self) {
+        let mut next = self.next.take();
+        while let Some(binding) = next {
+            next = match Rc::try_unwrap(binding) {
+                Ok(mut binding) => binding.next.take(),
+                Err(_) => None,
+            };
+        }
+    }
+}
+
+fn bind<'a, N>(scope: &Scope<'a, N>, name: &'a str, value: Value<'a, N>) -> Scope<'a, N> {
+    Some(Rc::new(Binding { name, value, next: scope.clone() }))
+}
+
+fn lookup<'s, 'a, N>(mut scope: &'s Scope<'a, N>, name: &str) -> Option<&'s Value<'a, N>> {
+    while let Some(binding) = scope {
+        if binding.name == name {
+            return Some(&binding.value);
+        }
+        scope = &binding.next;
+    }
+    None
+}
+
+#[derive(Clone)]
+enum Value<'a, N> {
+    Number(N),
+    Closure(Rc<Closure<'a, N>>),
+}
+
+impl<N> Value<'_, N> {
+    fn number(self) -> Result<N, EvalError> {
+        match self {
+            Value::Number(n) => Ok(n),
+            Value::Closure(_) => Err(EvalError::ExpectedNumber),
+        }
+    }
+}
+
+struct Closure<'a, N> {
+    /// The `let` name the function was bound to, which is in scope inside its own body.
+    name: Option<&'a str>,
+    params: &'a [String],
+    body: &'a Expression<N>,
+    scope: Scope<'a, N>,
+}
+
+// Pending work for `evaluate`, which keeps its own stack instead of recursing. What to evaluate next depends on
+// values computed so far (short-circuiting, branches, calls), so this is a loop over `Layer`s rather than a fold.
+//
+// Tasks that can fail carry the span of the expression they came from, so errors can point at it.
+enum Task<'a, N> {
+    Eval(&'a Expression<N>, Scope<'a, N>),
+    Apply(Operation, Option<Span>),
+    ApplyUnary(UnaryOperation, Option<Span>),
+    /// Decides an `&&` or `||` from its left value, evaluating the right side only if needed.
+    ShortCircuit(Operation, &'a Expression<N>, Scope<'a, N>, Option<Span>),
+    /// Replaces the top value with 0 or 1.
+    Truth(Option<Span>),
+    Branch(&'a Expression<N>, &'a Expression<N>, Scope<'a, N>, Option<Span>),
+    /// Binds the top value to a name and evaluates the body of a `let` with it in scope.
+    Bind(&'a str, &'a Expression<N>, Scope<'a, N>),
+    /// Calls the closure below the given number of arguments on the stack.
+    Call(usize, Option<Span>),
+    Return,
+}
+
+/// `eval` for any numeric backend.
+///
+/// Uses a heap-allocated work stack, so arbitrarily deep trees evaluate without overflowing the call stack.
+pub fn evaluate<N: Number>(e: &Expression<N>) -> Result<N, EvalError> {
+    evaluate_with_call_limit(e, DEFAULT_MAX_CALL_DEPTH)
+}
+
+/// `evaluate`, failing with `CallDepthExceeded` once more than `max_call_depth` calls are active at once.
+pub fn evaluate_with_call_limit<N: Number>(e: &Expression<N>, max_call_depth: usize) -> Result<N, EvalError> {
+    evaluate_located_with_call_limit(e, max_call_depth).map_err(|e| e.error)
+}
+
+/// `evaluate`, reporting where in the source the error happened for trees from `parse_spanned`.
+pub fn evaluate_located<N: Number>(e: &Expression<N>) -> Result<N, LocatedError> {
+    evaluate_located_with_call_limit(e, DEFAULT_MAX_CALL_DEPTH)
+}
+
+pub fn evaluate_located_with_call_limit<N: Number>(
+    e: &Expression<N>,
+    max_call_depth: usize,
+) -> Result<N, LocatedError> {
+    run(e, max_call_depth)?.number().map_err(|error| LocatedError { error, span: e.span() })
+}
+
+fn run<N: Number>(e: &Expression<N>, max_call_depth: usize) -> Result<Value<'_, N>, LocatedError> {
+    fn pop<'a, N>(values: &mut Vec<Value<'a, N>>) -> Value<'a, N> {
+        values.pop().expect("every task leaves its operands on the stack")
+    }
+    let at = |span| move |error| LocatedError { error, span };
+    let mut tasks = vec![Task::Eval(e, None)];
+    let mut values = Vec::new();
+    let mut depth = 0;
+    while let Some(task) = tasks.pop() {
+        match task {
+            Task::Eval(e, scope) => {
+                let span = e.span();
+                match e.layer() {
+                    Layer::Op { op: op @ (Operation::And | Operation::Or), left, right } => {
+                        tasks.push(Task::ShortCircuit(op, right, scope.clone(), span));
+                        tasks.push(Task::Eval(left, scope));
+                    }
+                    Layer::Op { op, left, right } => {
+                        tasks.push(Task::Apply(op, span));
+                        tasks.push(Task::Eval(right, scope.clone()));
+                        tasks.push(Task::Eval(left, scope));
+                    }
+                    Layer::Unary { op, operand } => {
+                        tasks.push(Task::ApplyUnary(op, span));
+                        tasks.push(Task::Eval(operand, scope));
+                    }
+                    Layer::If { cond, then, otherwise } => {
+                        tasks.push(Task::Branch(then, otherwise, scope.clone(), span));
+                        tasks.push(Task::Eval(cond, scope));
+                    }
+                    Layer::Let { name, value, body } => match value.layer() {
+                        Layer::Function { params, body: function_body } => {
+                            let closure = Closure { name: Some(name), params, body: function_body, scope: scope.clone() };
+                            let scope = bind(&scope, name, Value::Closure(Rc::new(closure)));
+                            tasks.push(Task::Eval(body, scope));
+                        }
+                        _ => {
+                            tasks.push(Task::Bind(name, body, scope.clone()));
+                            tasks.push(Task::Eval(value, scope));
+                        }
+                    },
+                    Layer::Function { params, body } => {
+                        values.push(Value::Closure(Rc::new(Closure { name: None, params, body, scope })));
+                    }
+                    Layer::Call { callee, args } => {
+                        tasks.push(Task::Call(args.len(), span));
+                        for arg in args.into_iter().rev() {
+                            tasks.push(Task::Eval(arg, scope.clone()));
+                        }
+                        tasks.push(Task::Eval(callee, scope));
+                    }
+                    Layer::Var(name) => match lookup(&scope, name) {
+                        Some(value) => values.push(value.clone()),
+                        None => return Err(at(span)(EvalError::UnboundVariable(name.to_string()))),
+                    },
+                    Layer::Value(v) => values.push(Value::Number(v.clone().check().map_err(at(span))?)),
+                    Layer::Bool(b) => values.push(Value::Number(N::from_bool(b))),
+                }
+            }
+            Task::Apply(op, span) => {
+                let right = pop(&mut values).number().map_err(at(span))?;
+                let left = pop(&mut values).number().map_err(at(span))?;
+                values.push(Value::Number(apply(op, left, right).map_err(at(span))?));
+            }
+            Task::ApplyUnary(op, span) => {
+                let operand = pop(&mut values).number().map_err(at(span))?;
+                values.push(Value::Number(apply_unary(op, operand).map_err(at(span))?));
+            }
+            Task::ShortCircuit(op, right, scope, span) => {
+                let left = truth(&pop(&mut values).number().map_err(at(span))?);
+                if left == (op == Operation::Or) {
+                    values.push(Value::Number(N::from_bool(left)));
+                } else {
+                    tasks.push(Task::Truth(span));
+                    tasks.push(Task::Eval(right, scope));
+                }
+            }
+            Task::Truth(span) => {
+                let v = pop(&mut values).number().map_err(at(span))?;
+                values.push(Value::Number(N::from_bool(truth(&v))));
+            }
+            Task::Branch(then, otherwise, scope, span) => {
+                let cond = pop(&mut values).number().map_err(at(span))?;
+                tasks.push(Task::Eval(if truth(&cond) { then } else { otherwise }, scope));
+            }
+            Task::Bind(name, body, scope) => {
+                let value = pop(&mut values);
+                tasks.push(Task::Eval(body, bind(&scope, name, value)));
+            }
+            Task::Call(arg_count, span) => {
+                let args = values.split_off(values.len() - arg_count);
+                let Value::Closure(closure) = pop(&mut values) else {
+                    return Err(at(span)(EvalError::NotAFunction));
+                };
+                if closure.params.len() != arg_count {
+                    return Err(at(span)(EvalError::ArityMismatch { expected: closure.params.len(), found: arg_count }));
+                }
+                if depth == max_call_depth {
+                    return Err(at(span)(EvalError::CallDepthExceeded(max_call_depth)));
+                }
+                depth += 1;
+                let mut scope = closure.scope.clone();
+                if let Some(name) = closure.name {
+                    scope = bind(&scope, name, Value::Closure(closure.clone()));
+                }
+                for (param, arg) in closure.params.iter().zip(args) {
+                    scope = bind(&scope, param, arg);
+                }
+                tasks.push(Task::Return);
+                tasks.push(Task::Eval(closure.body, scope));
+            }
+            Task::Return => depth -= 1,
+        }
+    }
+    Ok(pop(&mut values))
+}
+
+pub(crate) fn truth<N: Number>(v: &N) -> bool {
+    !v.is_zero()
+}
+
+/// Applies a single binary operation, shared by every evaluator so they agree on results and errors.
+///
+/// `And` and `Or` are applied strictly here; evaluators short-circuit them before getting this far.
+pub(crate) fn apply<N: Number>(op: Operation, left: N, right: N) -> Result<N, EvalError> {
+    match op {
+        Operation::Add => left.try_add(right),
+        Operation::Sub => left.try_sub(right),
+        Operation::Mul => left.try_mul(right),
+        Operation::Div => left.try_div(right),
+        Operation::Rem => left.try_rem(right),
+        Operation::Pow => left.try_pow(right),
+        Operation::Eq => Ok(N::from_bool(left == right)),
+        Operation::Ne => Ok(N::from_bool(left != right)),
+        Operation::Lt => Ok(N::from_bool(left < right)),
+        Operation::Le => Ok(N::from_bool(left <= right)),
+        Operation::Gt => Ok(N::from_bool(left > right)),
+        Operation::Ge => Ok(N::from_bool(left >= right)),
+        Operation::And => Ok(N::from_bool(truth(&left) && truth(&right))),
+        Operation::Or => Ok(N::from_bool(truth(&left) || truth(&right))),
+    }
+}
+
+pub(crate) fn apply_unary<N: Number>(op: UnaryOperation, v: N) -> Result<N, EvalError> {
+    match op {
+        UnaryOperation::Neg => v.try_neg(),
+        UnaryOperation::Not => Ok(N::from_bool(!truth(&v))),
+    }
+}
+
+#[test]
+fn test_error() {
+    assert_eq!(
+        eval(&Expression::Op {
+            op: Operation::Div,
+            left: Box::new(Expression::Value(99)),
+            right: Box::new(Expression::Value(0)),
+        }),
+        Err(EvalError::DivideByZero)
+    );
+}
+
+#[test]
+fn test_valid_operations() {
+    assert_eq!(eval(&Expression::Op {
+        op: Operation::Add,
+        left: Box::new(Expression::Value(20)),
+        right: Box::new(Expression::Value(10)),
+    }).unwrap(), 30);
+
+    assert_eq!(eval(&Expression::Op {
+        op: Operation::Sub,
+        left: Box::new(Expression::Value(20)),
+        right: Box::new(Expression::Value(10)),
+    }).unwrap(), 10);
+
+    assert_eq!(eval(&Expression::Op {
+        op: Operation::Mul,
+        left: Box::new(Expression::Value(5)),
+        right: Box::new(Expression::Value(6)),
+    }).unwrap(), 30);
+
+    assert_eq!(eval(&Expression::Op {
+        op: Operation::Div,
+        left: Box::new(Expression::Value(20)),
+        right: Box::new(Expression::Value(5)),
+    }).unwrap(), 4);
+}
+
+#[test]
+fn test_display() {
+    let expr: Expression = Expression::Op {
+        op: Operation::Sub,
+        left: Box::new(Expression::Op {
+            op: Operation::Mul,
+            left: Box::new(Expression::Value(10)),
+            right: Box::new(Expression::Value(9)),
+        }),
+        right: Box::new(Expression::Op {
+            op: Operation::Sub,
+            left: Box::new(Expression::Value(3)),
+            right: Box::new(Expression::Value(-4)),
+        }),
+    };
+    assert_eq!(expr.to_string(), "10 * 9 - (3 - -4)");
+}
+
+#[test]
+fn test_new_operations() {
+    let op = |op, left, right| Expression::Op { op, left: Box::new(Expression::Value(left)), right: Box::new(Expression::Value(right)) };
+    assert_eq!(eval(&op(Operation::Rem, -7, 3)), Ok(-1));
+    assert_eq!(eval(&op(Operation::Rem, 7, 0)), Err(EvalError::DivideByZero));
+    assert_eq!(eval(&op(Operation::Pow, -2, 3)), Ok(-8));
+    assert_eq!(eval(&op(Operation::Pow, 2, -1)), Err(EvalError::NegativeExponent));
+    assert_eq!(eval(&op(Operation::Pow, 2, 63)), Err(EvalError::Overflow));
+    assert_eq!(eval(&op(Operation::Pow, -1, 1 << 40 | 1)), Ok(-1));
+    assert_eq!(eval(&op(Operation::Add, i64::MAX, 1)), Err(EvalError::Overflow));
+    assert_eq!(eval(&op(Operation::Div, i64::MIN, -1)), Err(EvalError::Overflow));
+    assert_eq!(eval(&op(Operation::Le, 3, 3)), Ok(1));
+    assert_eq!(eval(&op(Operation::Ne, 3, 3)), Ok(0));
+    assert_eq!(
+        eval(&Expression::Unary { op: UnaryOperation::Neg, operand: Box::new(Expression::Value(i64::MIN)) }),
+        Err(EvalError::Overflow)
+    );
+    assert_eq!(eval(&Expression::Unary { op: UnaryOperation::Not, operand: Box::new(Expression::Value(5)) }), Ok(0));
+}
+
+#[test]
+fn test_short_circuit() {
+    let failing = || Box::new(Expression::Op {
+        op: Operation::Div,
+        left: Box::new(Expression::Value(1)),
+        right: Box::new(Expression::Value(0)),
+    });
+    assert_eq!(eval(&Expression::Op { op: Operation::And, left: Box::new(Expression::Bool(false)), right: failing() }), Ok(0));
+    assert_eq!(eval(&Expression::Op { op: Operation::Or, left: Box::new(Expression::Value(7)), right: failing() }), Ok(1));
+    assert_eq!(
+        eval(&Expression::Op { op: Operation::Or, left: Box::new(Expression::Value(0)), right: failing() }),
+        Err(EvalError::DivideByZero)
+    );
+    assert_eq!(
+        eval(&Expression::If { cond: Box::new(Expression::Bool(true)), then: Box::new(Expression::Value(1)), otherwise: failing() }),
+        Ok(1)
+    );
+    assert_eq!(
+        eval(&Expression::If { cond: Box::new(Expression::Value(0)), then: failing(), otherwise: Box::new(Expression::Value(2)) }),
+        Ok(2)
+    );
+}
+
+#[test]
+fn test_backends() {
+    let expr = crate::parser::parse("(7 / 2 + 1) * 3 ** 2 - -1").unwrap();
+    assert_eq!(evaluate(&expr), Ok(37));
+    assert_eq!(evaluate(&expr.map_values(&|v| *v as i128)), Ok(37));
+    assert_eq!(evaluate(&expr.map_values(&|v| *v as f64)), Ok(41.5));
+    assert_eq!(
+        evaluate(&expr.map_values(&|v| crate::number::Rational::integer(*v))),
+        crate::number::Rational::new(83, 2)
+    );
+
+    let expr = crate::parser::parse("if 2 ** 70 > 0 then 1 else 2").unwrap();
+    assert_eq!(evaluate(&expr), Err(EvalError::Overflow));
+    assert_eq!(evaluate(&expr.map_values(&|v| *v as i128)), Ok(1));
+
+    assert_eq!(evaluate(&Expression::Value(f64::NAN)), Err(EvalError::NotANumber));
+}
+
+#[cfg(test)]
+pub(crate) fn left_chain(length: usize) -> Expression {
+    let mut expr = Expression::Value(0);
+    for i in 0..length {
+        expr = Expression::Op { op: Operation::Add, left: Box::new(expr), right: Box::new(Expression::Value(i as i64 % 3)) };
+    }
+    expr
+}
+
+#[test]
+fn test_deep_left_chain() {
+    let expr = left_chain(1_000_000);
+    assert_eq!(eval(&expr), Ok(999_999));
+}
+
+#[test]
+fn test_deep_clone_and_display() {
+    let expr = left_chain(200_000);
+    let printed = expr.clone().to_string();
+    assert_eq!(printed.len(), 4 * 200_000 + 1);
+    assert_eq!(crate::parser::parse(&printed).unwrap().to_string(), printed);
+    let src = format!("{}x{}", "-(".repeat(200_000), ")".repeat(200_000));
+    let spanned = crate::parser::parse_spanned(&src).unwrap();
+    assert_eq!(spanned.to_string(), format!("{}x", "-".repeat(200_000)));
+    let wide = spanned.map_values(&|&v| i128::from(v));
+    // the spans come through, so the error still points at `x`
+    assert_eq!(evaluate_located(&wide).unwrap_err().span, Some(Span::new(400_000, 400_001)));
+}
+
+#[test]
+fn test_deep_serialization() {
+    use crate::serialize::{parse_json, parse_sexpr, to_json, to_sexpr};
+    let mut expr = left_chain(200_000);
+    for i in 0..50_000 {
+        expr = match i % 4 {
+            0 => Expression::Unary { op: UnaryOperation::Neg, operand: Box::new(expr) },
+            1 => Expression::Spanned { span: Span::new(i, i + 1), inner: Box::new(expr) },
+            2 => Expression::Let { name: String::from("x"), value: Box::new(expr), body: Box::new(Expression::Var(String::from("x"))) },
+            _ => Expression::Call {
+                callee: Box::new(Expression::Function { params: vec![String::from("y")], body: Box::new(Expression::Var(String::from("y"))) }),
+                args: vec![expr],
+            },
+        };
+    }
+    // comparing the trees with `==` would recurse, so compare what they serialize and evaluate to instead
+    let sexpr = to_sexpr(&expr);
+    let from_sexpr: Expression = parse_sexpr(&sexpr).unwrap();
+    assert!(to_sexpr(&from_sexpr) == sexpr);
+    assert_eq!(eval(&from_sexpr), Ok(199_999));
+    let json = to_json(&expr);
+    let from_json: Expression = parse_json(&json).unwrap();
+    assert!(to_json(&from_json) == json);
+    assert_eq!(eval(&from_json), Ok(199_999));
+}
+
+#[test]
+fn test_deep_malformed_input() {
+    use crate::serialize::{parse_json, parse_sexpr};
+    let sexpr = |src: &str| parse_sexpr::<i64>(src).unwrap_err().to_string();
+    let nested = |open: &str, inner: &str, close: &str| format!("{}{inner}{}", open.repeat(200_000), close.repeat(200_000));
+    assert_eq!(sexpr(&"(- ".repeat(200_000)), "expected ')' at offset 600000");
+    assert_eq!(sexpr(&nested("(- ", "x.y", ")")), "unexpected 'x.y' at offset 600000");
+    assert_eq!(sexpr(&nested("((", "", "))")), "expected an operator or form name at offset 1");
+    let json = |src: &str| parse_json::<i64>(src).unwrap_err().to_string();
+    assert_eq!(json(&"[".repeat(200_000)), "unexpected end of input at offset 200000");
+    assert_eq!(json(&nested("[", "1", "]")), "expected an object, found an array at offset 0");
+    assert_eq!(json(&nested(r#"{"unary":"-","operand":"#, r#"{"var":1}"#, "}")), "expected a string for 'var', found a number at offset 4600007");
+    assert_eq!(json(&nested(r#"{"x":"#, "1", "")), "expected ',' or '}' at offset 1000001");
+}
+
+#[test]
+fn test_deep_mixed_nesting() {
+    let mut expr = Expression::Value(1);
+    for i in 0..300_000 {
+        expr = match i % 3 {
+            0 => Expression::Unary { op: UnaryOperation::Neg, operand: Box::new(expr) },
+            1 => Expression::If { cond: Box::new(Expression::Bool(true)), then: Box::new(expr), otherwise: Box::new(Expression::Value(0)) },
+            _ => Expression::Op { op: Operation::And, left: Box::new(Expression::Value(1)), right: Box::new(expr) },
+        };
+    }
+    assert_eq!(eval(&expr), Ok(1));
+}
+
+#[test]
+fn test_deep_drop() {
+    let mut expr = left_chain(1_000_000);
+    for _ in 0..1_000_000 {
+        expr = Expression::Op { op: Operation::Mul, left: Box::new(Expression::Value(1)), right: Box::new(expr) };
+    }
+    drop(expr);
+}
+
+#[test]
+fn test_functions() {
+    let value = |src: &str| eval(&crate::parser::parse(src).unwrap());
+    assert_eq!(value("let sq = fn(x) x * x in sq(3) + sq(4)"), Ok(25));
+    assert_eq!(value("let add = fn(a, b) a + b in add(1, add(2, 3))"), Ok(6));
+    assert_eq!(value("let fact = fn(n) if n <= 1 then 1 else n * fact(n - 1) in fact(20)"), Ok(2432902008176640000));
+    assert_eq!(value("(fn(x) x + 1)(41)"), Ok(42));
+    assert_eq!(value("let x = 5 in let x = x + 1 in x"), Ok(6));
+}
+
+#[test]
+fn test_closures_are_lexically_scoped() {
+    let value = |src: &str| eval(&crate::parser::parse(src).unwrap());
+    assert_eq!(value("let x = 1 in let f = fn(y) x + y in let x = 100 in f(10)"), Ok(11));
+    assert_eq!(value("let adder = fn(n) fn(x) x + n in let add5 = adder(5) in add5(1) + adder(10)(1)"), Ok(17));
+    assert_eq!(value("let twice = fn(f, x) f(f(x)) in twice(fn(x) x * 3, 2)"), Ok(18));
+}
+
+#[test]
+fn test_function_errors() {
+    let value = |src: &str| eval(&crate::parser::parse(src).unwrap());
+    assert_eq!(value("x + 1"), Err(EvalError::UnboundVariable(String::from("x"))));
+    assert_eq!(value("fn(x) x"), Err(EvalError::ExpectedNumber));
+    assert_eq!(value("1 + fn(x) x"), Err(EvalError::ExpectedNumber));
+    assert_eq!(value("3(4)"), Err(EvalError::NotAFunction));
+    assert_eq!(value("(fn(a, b) a)(1)"), Err(EvalError::ArityMismatch { expected: 2, found: 1 }));
+    // a non-function `let` is not recursive, so `f` inside refers to nothing
+    assert_eq!(value("let f = if true then fn(n) f(n) else 0 in f(1)"), Err(EvalError::UnboundVariable(String::from("f"))));
+}
+
+#[test]
+fn test_call_depth_limit() {
+    let expr = crate::parser::parse("let down = fn(n) if n == 0 then 0 else down(n - 1) in down(100000)").unwrap();
+    assert_eq!(eval(&expr), Err(EvalError::CallDepthExceeded(DEFAULT_MAX_CALL_DEPTH)));
+    // down(100000) through down(0) are all active at once
+    assert_eq!(evaluate_with_call_limit(&expr, 100_001), Ok(0));
+    assert_eq!(evaluate_with_call_limit(&expr, 100_000), Err(EvalError::CallDepthExceeded(100_000)));
+}
+
+#[test]
+fn test_errors_point_at_their_source() {
+    fn located(src: &str) -> (EvalError, Option<&str>) {
+        let expr = crate::parser::parse_spanned(src).unwrap();
+        let error = evaluate_located(&expr).unwrap_err();
+        (error.error, error.span.map(|span| &src[span.start..span.end]))
+    }
+    assert_eq!(located("10 / 2 + 3 / (1 - 1)"), (EvalError::DivideByZero, Some("/")));
+    assert_eq!(located("let x = 1 in x + y"), (EvalError::UnboundVariable(String::from("y")), Some("y")));
+    assert_eq!(located("(fn(a) a)(1, 2)"), (EvalError::ArityMismatch { expected: 1, found: 2 }, Some("(fn(a) a)(1, 2)")));
+    assert_eq!(located("-(-9223372036854775807 - 1)"), (EvalError::Overflow, Some("-")));
+    // hand-built trees have no source to point at
+    assert_eq!(evaluate_located(&left_chain(3).map_values(&|_| f64::NAN)).unwrap_err().span, None);
+}
@@ -0,0 +1,440 @@
+use std::fmt;
+
+use crate::bigint::BigInt;
+use crate::bytecode::{compile, Vm};
+use crate::dag::Dag;
+use crate::expression::{evaluate, EvalError, Expression, Operation, UnaryOperation};
+use crate::parser::parse;
+use crate::serialize;
+use crate::simplify::simplify;
+use crate::trace::trace;
+use crate::types::Type;
+use crate::visit::{Layer, Order};
+
+/// Which constructs a `Generator` may produce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Grammar {
+    /// Integer literals combined with `+`, `-`, `*` and `/`: the language of day 2's `eval`.
+    Arithmetic,
+    /// Everything but variables, `let`, functions and calls: the language the bytecode compiler accepts.
+    Closed,
+    /// Every construct, including booleans, variables, `let`, functions and calls.
+    Full,
+}
+
+const ARITHMETIC: [Operation; 4] = [Operation::Add, Operation::Sub, Operation::Mul, Operation::Div];
+const OPERATIONS: [Operation; 14] = [
+    Operation::Add,
+    Operation::Sub,
+    Operation::Mul,
+    Operation::Div,
+    Operation::Rem,
+    Operation::Pow,
+    Operation::Eq,
+    Operation::Ne,
+    Operation::Lt,
+    Operation::Le,
+    Operation::Gt,
+    Operation::Ge,
+    Operation::And,
+    Operation::Or,
+];
+// Mostly small numbers, so that operations are likely to succeed, plus the edges of the i64 range.
+const EXTREMES: [i64; 6] = [i64::MIN, i64::MIN + 1, -4_294_967_296, 3_037_000_500, 4_294_967_296, i64::MAX];
+const NAMES: [&str; 3] = ["a", "b", "c"];
+const FUNCTIONS: [&str; 2] = ["f", "g"];
+
+/// Produces random expressions from a seed, so any failure can be reproduced from the seed alone.
+#[derive(Debug, Clone)]
+pub struct Generator {
+    state: u64,
+    grammar: Grammar,
+    max_depth: u32,
+    max_size: usize,
+    /// Nodes in the expression being generated, counting children that are promised but not generated yet.
+    size: usize,
+}
+
+impl Generator {
+    /// A generator for the full language, with at most 8 levels and 64 nodes per expression.
+    pub fn new(seed: u64) -> Self {
+        // xorshift gets stuck at zero, and nearby seeds would otherwise start out alike
+        let state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+        Generator { state, grammar: Grammar::Full, max_depth: 8, max_size: 64, size: 0 }
+    }
+
+    pub fn with_grammar(mut self, grammar: Grammar) -> Self {
+        self.grammar = grammar;
+        self
+    }
+
+    /// Limits how many levels deep and how many nodes in total each expression has. Both must be at least 1.
+    pub fn with_limits(mut self, max_depth: u32, max_size: usize) -> Self {
+        assert!(max_depth >= 1 && max_size >= 1, "an expression has at least one node");
+        self.max_depth = max_depth;
+        self.max_size = max_size;
+        self
+    }
+
+    pub fn generate(&mut self) -> Expression {
+        self.size = 1;
+        self.expression(self.max_depth - 1, &mut Vec::new())
+    }
+
+    /// The next number in the generator's sequence (xorshift64), for tests that need random inputs other than
+    /// expressions and want them reproducible the same way.
+    pub fn random(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    /// A random number less than `n`, which must not be 0.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.random() % n as u64) as usize
+    }
+
+    /// Reserves room for `children` more nodes, if the size limit allows it.
+    fn reserve(&mut self, children: usize) -> bool {
+        if self.size + children > self.max_size {
+            return false;
+        }
+        self.size += children;
+        true
+    }
+
+    // `scope` holds the variables bound around this point, innermost last, and whether each is a function.
+    fn expression(&mut self, depth: u32, scope: &mut Vec<(&'static str, bool)>) -> Expression {
+        if depth == 0 || self.below(5) == 0 {
+            return self.leaf(scope);
+        }
+        let child = |generator: &mut Self, scope: &mut Vec<_>| Box::new(generator.expression(depth - 1, scope));
+        if self.grammar == Grammar::Arithmetic {
+            if !self.reserve(2) {
+                return self.leaf(scope);
+            }
+            let op = ARITHMETIC[self.below(4)];
+            return Expression::Op { op, left: child(self, scope), right: child(self, scope) };
+        }
+        match self.below(20) {
+            0 | 1 if self.reserve(1) => {
+                let op = [UnaryOperation::Neg, UnaryOperation::Not][self.below(2)];
+                Expression::Unary { op, operand: child(self, scope) }
+            }
+            2 if self.reserve(3) => Expression::If { cond: child(self, scope), then: child(self, scope), otherwise: child(self, scope) },
+            3 | 4 if self.grammar == Grammar::Full && self.reserve(2) => {
+                let name = NAMES[self.below(NAMES.len())];
+                let value = child(self, scope);
+                scope.push((name, false));
+                let body = child(self, scope);
+                scope.pop();
+                Expression::Let { name: name.to_string(), value, body }
+            }
+            // A function is only ever in scope under its own name inside its body, where it is left out of
+            // `scope`, so generated expressions never recurse and always terminate.
+            5 if self.grammar == Grammar::Full && depth >= 2 && self.reserve(3) => {
+                let name = FUNCTIONS[self.below(FUNCTIONS.len())];
+                let function = self.function(depth - 1, scope, name);
+                scope.push((name, true));
+                let body = child(self, scope);
+                scope.pop();
+                Expression::Let { name: name.to_string(), value: Box::new(function), body }
+            }
+            6 | 7 if self.grammar == Grammar::Full => self.call(depth, scope),
+            _ if self.reserve(2) => {
+                let op = OPERATIONS[self.below(OPERATIONS.len())];
+                Expression::Op { op, left: child(self, scope), right: child(self, scope) }
+            }
+            _ => self.leaf(scope),
+        }
+    }
+
+    // The function node and its body have been reserved already.
+    fn function(&mut self, depth: u32, scope: &mut Vec<(&'static str, bool)>, name: &str) -> Expression {
+        let params: Vec<&'static str> = NAMES[..self.below(NAMES.len() + 1)].to_vec();
+        let mut inner: Vec<_> = scope.iter().copied().filter(|&(n, _)| n != name && !params.contains(&n)).collect();
+        inner.extend(params.iter().map(|&p| (p, false)));
+        let body = Box::new(self.expression(depth - 1, &mut inner));
+        Expression::Function { params: params.iter().map(|p| p.to_string()).collect(), body }
+    }
+
+    fn call(&mut self, depth: u32, scope: &mut Vec<(&'static str, bool)>) -> Expression {
+        let functions: Vec<&'static str> = scope.iter().filter(|(_, function)| *function).map(|&(n, _)| n).collect();
+        // any number of arguments, so that arity errors come up too
+        let arity = self.below(NAMES.len() + 1);
+        let callee = if !functions.is_empty() && self.below(3) != 0 && self.reserve(1 + arity) {
+            Expression::Var(functions[self.below(functions.len())].to_string())
+        } else if depth >= 2 && self.reserve(2 + arity) {
+            self.function(depth - 1, scope, "")
+        } else {
+            return self.leaf(scope);
+        };
+        let args = (0..arity).map(|_| self.expression(depth - 1, scope)).collect();
+        Expression::Call { callee: Box::new(callee), args }
+    }
+
+    fn leaf(&mut self, scope: &[(&'static str, bool)]) -> Expression {
+        let r = self.random();
+        if self.grammar != Grammar::Arithmetic {
+            match r % 16 {
+                0 => return Expression::Bool(r & 1 << 20 != 0),
+                // a name that is never bound
+                1 if self.grammar == Grammar::Full => return Expression::Var(String::from("z")),
+                2..6 if !scope.is_empty() => return Expression::Var(scope[(r >> 8) as usize % scope.len()].0.to_string()),
+                _ => {}
+            }
+        }
+        match (r >> 4) % 32 {
+            0 => Expression::Value(EXTREMES[(r >> 16) as usize % EXTREMES.len()]),
+            1 => Expression::Value((r >> 16) as i64),
+            n => Expression::Value(n as i64 % 13 - 6),
+        }
+    }
+}
+
+/// Two ways of computing the same thing that disagreed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    /// What was being compared.
+    pub check: &'static str,
+    pub expected: String,
+    pub found: String,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: expected {}, found {}", self.check, self.expected, self.found)
+    }
+}
+
+impl std::error::Error for Mismatch {}
+
+fn compare<T: PartialEq + fmt::Debug>(check: &'static str, expected: T, found: T) -> Result<(), Mismatch> {
+    if expected == found {
+        return Ok(());
+    }
+    Err(Mismatch { check, expected: format!("{expected:?}"), found: format!("{found:?}") })
+}
+
+/// Cross-checks everything in this crate that should agree about `e`:
+///
+/// - printing it and parsing the result gives back the same tree;
+/// - the memoized `Dag` evaluator, the bytecode VM (when `e` compiles), evaluating the simplified tree and tracing
+///   (unless the trace is cut short) all give the same result or error as `evaluate`;
+/// - evaluating with `i128` or `BigInt` gives the same result unless the `i64` evaluation overflowed;
+/// - a tree that type checks, and is not a function, never fails with an error the type checker exists to rule out.
+pub fn check(e: &Expression) -> Result<(), Mismatch> {
+    let printed = e.to_string();
+    compare("printing then parsing", Ok(e), parse(&printed).as_ref().map_err(|e| e.to_string()))?;
+    compare("S-expression round trip", Ok(e), serialize::parse_sexpr(&serialize::to_sexpr(e)).as_ref().map_err(|e| e.to_string()))?;
+    compare("JSON round trip", Ok(e), serialize::parse_json(&serialize::to_json(e)).as_ref().map_err(|e| e.to_string()))?;
+    let expected = evaluate(e);
+    let mut dag = Dag::new();
+    let root = dag.insert(e);
+    compare("evaluating as a DAG", &expected, &dag.evaluate(root))?;
+    if let Ok(program) = compile(e) {
+        compare("running the bytecode", &expected, &Vm::new().run(&program))?;
+    }
+    compare("evaluating the simplified tree", &expected, &evaluate(&simplify(e)))?;
+    if let Some(traced) = trace(e).result() {
+        compare("tracing", &expected, &traced)?;
+    }
+    if expected != Err(EvalError::Overflow) {
+        let wide = evaluate(&e.map_values(&|&v| i128::from(v)));
+        compare("evaluating with i128", expected.clone().map(i128::from), wide)?;
+        let big = evaluate(&e.map_values(&|&v| BigInt::from(v)));
+        compare("evaluating with BigInt", expected.clone().map(BigInt::from), big)?;
+    }
+    // `evaluate` needs a number at the root, which the type checker does not ask for
+    let well_typed = crate::types::check(e).is_ok_and(|typing| !matches!(typing.root(), Type::Function(..)));
+    if well_typed
+        && let Err(
+            error @ (EvalError::UnboundVariable(_)
+            | EvalError::ExpectedNumber
+            | EvalError::NotAFunction
+            | EvalError::ArityMismatch { .. }),
+        ) = &expected
+    {
+        return Err(Mismatch { check: "type checking", expected: String::from("no type error"), found: error.to_string() });
+    }
+    Ok(())
+}
+
+// Smaller in node count first, then in leaves that are not numbers, then in the size of its numbers.
+// Every shrinking step makes this strictly smaller, so shrinking always ends.
+fn measure(e: &Expression) -> (usize, usize, u128) {
+    e.nodes(Order::Pre).fold((0, 0, 0), |(nodes, leaves, magnitude), e| match e.layer() {
+        Layer::Value(v) => (nodes + 1, leaves, magnitude + v.unsigned_abs() as u128),
+        Layer::Var(_) | Layer::Bool(_) => (nodes + 1, leaves + 1, magnitude),
+        _ => (nodes + 1, leaves, magnitude),
+    })
+}
+
+// What a node might be replaced with to make the tree smaller.
+fn replacements(e: &Expression) -> Vec<Expression> {
+    let mut candidates: Vec<Expression> = e.children().into_iter().cloned().collect();
+    candidates.extend([Expression::Value(0), Expression::Value(1)]);
+    if let Expression::Value(v) = e {
+        candidates.extend([Expression::Value(v / 2), Expression::Value(v - v.signum())]);
+    }
+    candidates
+}
+
+/// Greedily shrinks an expression for which `fails` is true into a smaller one for which it is still true.
+///
+/// Each step replaces one node by one of its children or by a smaller number. The result is minimal in the sense
+/// that no single such step keeps it failing.
+pub fn shrink(e: &Expression, mut fails: impl FnMut(&Expression) -> bool) -> Expression {
+    let mut current = e.clone();
+    'shrinking: loop {
+        let size = measure(&current);
+        for index in 0..current.nodes(Order::Pre).count() {
+            let node = current.nodes(Order::Pre).nth(index).expect("the index is in range");
+            for replacement in replacements(node) {
+                let mut seen = 0;
+                let mut replacement = Some(replacement);
+                let candidate = current.clone().transform_down(|node| {
+                    seen += 1;
+                    match replacement.take() {
+                        Some(r) if seen == index + 1 => r,
+                        r => {
+                            replacement = r;
+                            node
+                        }
+                    }
+                });
+                if measure(&candidate) < size && fails(&candidate) {
+                    current = candidate;
+                    continue 'shrinking;
+                }
+            }
+        }
+        return current;
+    }
+}
+
+/// A generated expression that failed a check, with a shrunk version of it.
+#[derive(Debug, Clone)]
+pub struct Failure {
+    /// How many expressions the generator had produced before this one.
+    pub case: usize,
+    pub original: Expression,
+    pub shrunk: Expression,
+    /// What went wrong with the shrunk expression.
+    pub mismatch: Mismatch,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "case {} failed: {}", self.case, self.mismatch)?;
+        writeln!(f, "  shrunk:   {}", self.shrunk)?;
+        write!(f, "  original: {}", self.original)
+    }
+}
+
+/// Runs `check` on `cases` generated expressions, stopping at the first one that fails and shrinking it.
+pub fn fuzz(generator: &mut Generator, cases: usize, check: impl Fn(&Expression) -> Result<(), Mismatch>) -> Option<Failure> {
+    for case in 0..cases {
+        let original = generator.generate();
+        if check(&original).is_ok() {
+            continue;
+        }
+        let shrunk = shrink(&original, |e| check(e).is_err());
+        let mismatch = check(&shrunk).expect_err("shrinking keeps the expression failing");
+        return Some(Failure { case, original, shrunk, mismatch });
+    }
+    None
+}
+
+fn to_day2(e: &Expression) -> Option<day2_morning::expression::Expression> {
+    use day2_morning::expression::{Expression as Day2, Operation as Day2Operation};
+    Some(match e {
+        Expression::Op { op, left, right } => Day2::Op {
+            op: match op {
+                Operation::Add => Day2Operation::Add,
+                Operation::Sub => Day2Operation::Sub,
+                Operation::Mul => Day2Operation::Mul,
+                Operation::Div => Day2Operation::Div,
+                _ => return None,
+            },
+            left: Box::new(to_day2(left)?),
+            right: Box::new(to_day2(right)?),
+        },
+        Expression::Value(v) => Day2::Value(*v),
+        _ => return None,
+    })
+}
+
+/// Checks that day 2's `eval` agrees with `evaluate` on an expression from `Grammar::Arithmetic`.
+///
+/// Day 2's `eval` panics where `evaluate` returns an error. Without overflow checks, as in release builds, it wraps
+/// on overflow instead, so expressions that overflow are not compared there. Panic messages are still printed by the
+/// panic hook.
+pub fn check_against_day2(e: &Expression) -> Result<(), Mismatch> {
+    let Some(day2) = to_day2(e) else {
+        return Err(Mismatch { check: "day 2's eval", expected: String::from("arithmetic"), found: e.to_string() });
+    };
+    let expected = evaluate(e);
+    if expected == Err(EvalError::Overflow) && !cfg!(debug_assertions) {
+        return Ok(());
+    }
+    let found = std::panic::catch_unwind(|| day2_morning::expression::eval(&day2)).map_err(|_| "a panic");
+    let expected = expected.map_err(|error| match error {
+        EvalError::DivideByZero | EvalError::Overflow => "a panic",
+        _ => "another error",
+    });
+    compare("day 2's eval", expected, found)
+}
+
+#[test]
+fn test_generator() {
+    let generate = |seed| {
+        let mut generator = Generator::new(seed).with_limits(6, 40);
+        (0..200).map(|_| generator.generate()).collect::<Vec<_>>()
+    };
+    let expressions = generate(7);
+    assert_eq!(expressions, generate(7));
+    assert_ne!(expressions, generate(8));
+    for e in &expressions {
+        assert!(e.node_count() <= 40 && e.depth() <= 6, "{e}");
+    }
+    assert!(expressions.iter().any(|e| e.node_count() > 20));
+    let mut generator = Generator::new(7).with_grammar(Grammar::Arithmetic);
+    for _ in 0..200 {
+        assert!(to_day2(&generator.generate()).is_some());
+    }
+    let mut generator = Generator::new(7).with_grammar(Grammar::Closed);
+    for _ in 0..200 {
+        let e = generator.generate();
+        assert!(compile(&e).is_ok(), "{e}");
+    }
+}
+
+#[test]
+fn test_shrink() {
+    let e = parse("(3 + 4 * 5) / (if 2 > 1 then 7 - 7 else 1) + let a = 9 in a").unwrap();
+    let divides_by_zero = |e: &Expression| evaluate(e) == Err(EvalError::DivideByZero);
+    assert_eq!(shrink(&e, divides_by_zero).to_string(), "0 / 0");
+    let e = parse("2 * (1000 + x)").unwrap();
+    let big = |e: &Expression| matches!(evaluate(e), Ok(v) if v > 100);
+    assert_eq!(shrink(&e, big).to_string(), "101");
+}
+
+#[test]
+fn test_differential() {
+    for seed in 0..4 {
+        if let Some(failure) = fuzz(&mut Generator::new(seed), 2_000, check) {
+            panic!("seed {seed}: {failure}");
+        }
+    }
+}
+
+#[test]
+fn test_differential_against_day2() {
+    let mut generator = Generator::new(1).with_grammar(Grammar::Arithmetic).with_limits(10, 100);
+    let check = |e: &Expression| check(e).and_then(|()| check_against_day2(e));
+    if let Some(failure) = fuzz(&mut generator, 2_000, check) {
+        panic!("{failure}");
+    }
+}
@@ -0,0 +1,15 @@
+pub mod bigint;
+pub mod bytecode;
+pub mod dag;
+pub mod derivative;
+pub mod diagnostic;
+pub mod expression;
+pub mod fuzz;
+pub mod number;
+pub mod parser;
+pub mod repl;
+pub mod serialize;
+pub mod simplify;
+pub mod trace;
+pub mod types;
+pub mod visit;
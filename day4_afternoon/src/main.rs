@@ -113,83 +113,7 @@ fn demonstrate_error_handling(){
 
 }
 
-#[derive(Debug)]
-enum Operation {
-    Add,
-    Sub,
-    Mul,
-    Div,
-}
-
-#[derive(Debug)]
-enum Expression {
-    Op { op: Operation, left: Box<Expression>, right: Box<Expression> },
-    Value(i64),
-}
-
-#[derive(PartialEq, Eq, Debug)]
-struct DivideByZeroError;
-
-fn eval(e: Expression) -> Result<i64, DivideByZeroError> {
-    match e {
-        Expression::Op { op, left, right } => {
-            let left = eval(*left)?;
-            let right = eval(*right)?;
-            match op {
-                Operation::Add => Ok(left + right),
-                Operation::Sub => Ok(left - right),
-                Operation::Mul => Ok(left * right),
-                Operation::Div => {
-                    if right != 0 {
-                        Ok(left / right)
-                    } else {
-                        Err(DivideByZeroError)
-                    }
-                },
-            }
-        }
-        Expression::Value(v) => Ok(v),
-    }
-}
-
-#[test]
-fn test_error() {
-    assert_eq!(
-        eval(Expression::Op {
-            op: Operation::Div,
-            left: Box::new(Expression::Value(99)),
-            right: Box::new(Expression::Value(0)),
-        }),
-        Err(DivideByZeroError)
-    );
-}
-
-#[test]
-fn test_valid_operations() {
-    assert_eq!(eval(Expression::Op {
-        op: Operation::Add,
-        left: Box::new(Expression::Value(20)),
-        right: Box::new(Expression::Value(10)),
-    }).unwrap(), 30);
-
-    assert_eq!(eval(Expression::Op {
-        op: Operation::Sub,
-        left: Box::new(Expression::Value(20)),
-        right: Box::new(Expression::Value(10)),
-    }).unwrap(), 10);
-
-    assert_eq!(eval(Expression::Op {
-        op: Operation::Mul,
-        left: Box::new(Expression::Value(5)),
-        right: Box::new(Expression::Value(6)),
-    }).unwrap(), 30);
-
-    assert_eq!(eval(Expression::Op {
-        op: Operation::Div,
-        left: Box::new(Expression::Value(20)),
-        right: Box::new(Expression::Value(5)),
-    }).unwrap(), 4);
-}
+use day4_afternoon::expression::{eval, Expression, Operation};
 
 fn demonstrate_unsafe_rust(){
 
@@ -303,7 +227,7 @@ fn main() -> Result<(), String> {
         right: Box::new(Expression::Value(10)),
     };
     println!("expr: {expr:?}");
-    match eval(expr) {
+    match eval(&expr) {
         Ok(result) => println!("result: {:?}", result),
         Err(_) => println!("Error occurred during evaluation."),
     }
@@ -0,0 +1,345 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+use crate::expression::EvalError;
+
+/// A numeric backend for `Expression`.
+///
+/// Every operation is checked: each backend decides which inputs are errors rather than
+/// panicking, wrapping or producing a value it cannot represent.
+pub trait Number: Clone + PartialEq + PartialOrd + fmt::Debug + fmt::Display {
+    fn zero() -> Self;
+    fn one() -> Self;
+
+    fn is_zero(&self) -> bool {
+        *self == Self::zero()
+    }
+
+    fn from_bool(b: bool) -> Self {
+        if b { Self::one() } else { Self::zero() }
+    }
+
+    /// Rejects literals the backend refuses to compute with.
+    fn check(self) -> Result<Self, EvalError> {
+        Ok(self)
+    }
+
+    fn try_add(self, other: Self) -> Result<Self, EvalError>;
+    fn try_sub(self, other: Self) -> Result<Self, EvalError>;
+    fn try_mul(self, other: Self) -> Result<Self, EvalError>;
+    fn try_div(self, other: Self) -> Result<Self, EvalError>;
+    fn try_rem(self, other: Self) -> Result<Self, EvalError>;
+    fn try_pow(self, exponent: Self) -> Result<Self, EvalError>;
+    fn try_neg(self) -> Result<Self, EvalError>;
+}
+
+// Fixed-width integers: overflow is an error, division truncates, and exponents must be non-negative.
+macro_rules! integer_number {
+    ($t:ty) => {
+        impl Number for $t {
+            fn zero() -> Self {
+                0
+            }
+
+            fn one() -> Self {
+                1
+            }
+
+            fn try_add(self, other: Self) -> Result<Self, EvalError> {
+                self.checked_add(other).ok_or(EvalError::Overflow)
+            }
+
+            fn try_sub(self, other: Self) -> Result<Self, EvalError> {
+                self.checked_sub(other).ok_or(EvalError::Overflow)
+            }
+
+            fn try_mul(self, other: Self) -> Result<Self, EvalError> {
+                self.checked_mul(other).ok_or(EvalError::Overflow)
+            }
+
+            fn try_div(self, other: Self) -> Result<Self, EvalError> {
+                if other == 0 {
+                    return Err(EvalError::DivideByZero);
+                }
+                self.checked_div(other).ok_or(EvalError::Overflow)
+            }
+
+            fn try_rem(self, other: Self) -> Result<Self, EvalError> {
+                if other == 0 {
+                    return Err(EvalError::DivideByZero);
+                }
+                self.checked_rem(other).ok_or(EvalError::Overflow)
+            }
+
+            fn try_pow(self, exponent: Self) -> Result<Self, EvalError> {
+                if exponent < 0 {
+                    return Err(EvalError::NegativeExponent);
+                }
+                match (self, u32::try_from(exponent)) {
+                    (_, Ok(exponent)) => self.checked_pow(exponent).ok_or(EvalError::Overflow),
+                    // Exponents past u32::MAX only have a representable result for these bases.
+                    (0 | 1, Err(_)) => Ok(self),
+                    (-1, Err(_)) => Ok(if exponent % 2 == 0 { 1 } else { -1 }),
+                    (_, Err(_)) => Err(EvalError::Overflow),
+                }
+            }
+
+            fn try_neg(self) -> Result<Self, EvalError> {
+                self.checked_neg().ok_or(EvalError::Overflow)
+            }
+        }
+    };
+}
+
+integer_number!(i64);
+integer_number!(i128);
+
+// Floats never produce NaN or infinity: NaN is `NotANumber`, infinity is `Overflow`, and dividing
+// by zero is `DivideByZero` rather than an infinity.
+impl Number for f64 {
+    fn zero() -> Self {
+        0.0
+    }
+
+    fn one() -> Self {
+        1.0
+    }
+
+    fn check(self) -> Result<Self, EvalError> {
+        if self.is_nan() {
+            Err(EvalError::NotANumber)
+        } else if self.is_infinite() {
+            Err(EvalError::Overflow)
+        } else {
+            Ok(self)
+        }
+    }
+
+    fn try_add(self, other: Self) -> Result<Self, EvalError> {
+        (self + other).check()
+    }
+
+    fn try_sub(self, other: Self) -> Result<Self, EvalError> {
+        (self - other).check()
+    }
+
+    fn try_mul(self, other: Self) -> Result<Self, EvalError> {
+        (self * other).check()
+    }
+
+    fn try_div(self, other: Self) -> Result<Self, EvalError> {
+        if other == 0.0 {
+            return Err(EvalError::DivideByZero);
+        }
+        (self / other).check()
+    }
+
+    fn try_rem(self, other: Self) -> Result<Self, EvalError> {
+        if other == 0.0 {
+            return Err(EvalError::DivideByZero);
+        }
+        (self % other).check()
+    }
+
+    fn try_pow(self, exponent: Self) -> Result<Self, EvalError> {
+        if self == 0.0 && exponent < 0.0 {
+            return Err(EvalError::DivideByZero);
+        }
+        self.powf(exponent).check()
+    }
+
+    fn try_neg(self) -> Result<Self, EvalError> {
+        Ok(-self)
+    }
+}
+
+/// An exact fraction, always kept in lowest terms with a positive denominator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Rational {
+    numer: i64,
+    denom: i64,
+}
+
+fn gcd(mut a: i128, mut b: i128) -> i128 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a.abs()
+}
+
+impl Rational {
+    /// Fails with `DivideByZero` for a zero denominator.
+    pub fn new(numer: i64, denom: i64) -> Result<Self, EvalError> {
+        Self::reduce(numer as i128, denom as i128)
+    }
+
+    pub fn integer(n: i64) -> Self {
+        Rational { numer: n, denom: 1 }
+    }
+
+    pub fn numer(&self) -> i64 {
+        self.numer
+    }
+
+    pub fn denom(&self) -> i64 {
+        self.denom
+    }
+
+    // Intermediate results are computed in i128, where products of two i64s cannot overflow.
+    fn reduce(numer: i128, denom: i128) -> Result<Self, EvalError> {
+        if denom == 0 {
+            return Err(EvalError::DivideByZero);
+        }
+        let divisor = gcd(numer, denom) * denom.signum();
+        let numer = i64::try_from(numer / divisor).map_err(|_| EvalError::Overflow)?;
+        let denom = i64::try_from(denom / divisor).map_err(|_| EvalError::Overflow)?;
+        Ok(Rational { numer, denom })
+    }
+
+    fn parts(self) -> (i128, i128) {
+        (self.numer as i128, self.denom as i128)
+    }
+}
+
+impl fmt::Display for Rational {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.denom == 1 {
+            write!(f, "{}", self.numer)
+        } else {
+            write!(f, "{}/{}", self.numer, self.denom)
+        }
+    }
+}
+
+impl PartialOrd for Rational {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Rational {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let (a, b) = self.parts();
+        let (c, d) = other.parts();
+        (a * d).cmp(&(c * b))
+    }
+}
+
+impl Number for Rational {
+    fn zero() -> Self {
+        Rational::integer(0)
+    }
+
+    fn one() -> Self {
+        Rational::integer(1)
+    }
+
+    fn try_add(self, other: Self) -> Result<Self, EvalError> {
+        let ((a, b), (c, d)) = (self.parts(), other.parts());
+        Rational::reduce(a * d + c * b, b * d)
+    }
+
+    fn try_sub(self, other: Self) -> Result<Self, EvalError> {
+        let ((a, b), (c, d)) = (self.parts(), other.parts());
+        Rational::reduce(a * d - c * b, b * d)
+    }
+
+    fn try_mul(self, other: Self) -> Result<Self, EvalError> {
+        let ((a, b), (c, d)) = (self.parts(), other.parts());
+        Rational::reduce(a * c, b * d)
+    }
+
+    fn try_div(self, other: Self) -> Result<Self, EvalError> {
+        let ((a, b), (c, d)) = (self.parts(), other.parts());
+        Rational::reduce(a * d, b * c)
+    }
+
+    // Truncating, like the integer backends: the result has the sign of `self`.
+    fn try_rem(self, other: Self) -> Result<Self, EvalError> {
+        let ((a, b), (c, d)) = (self.parts(), other.parts());
+        if c == 0 {
+            return Err(EvalError::DivideByZero);
+        }
+        Rational::reduce((a * d) % (c * b), b * d)
+    }
+
+    fn try_pow(self, exponent: Self) -> Result<Self, EvalError> {
+        if exponent.denom != 1 {
+            return Err(EvalError::NonIntegerExponent);
+        }
+        if self.denom == 1 && self.numer.abs() <= 1 {
+            // 0, 1 and -1 have small powers however large the exponent
+            return match (self.numer, exponent.numer) {
+                (0, e) if e < 0 => Err(EvalError::DivideByZero),
+                (_, 0) => Ok(Rational::one()),
+                (-1, e) if e % 2 == 0 => Ok(Rational::one()),
+                _ => Ok(self),
+            };
+        }
+        let magnitude = i64::try_from(exponent.numer.unsigned_abs()).map_err(|_| EvalError::Overflow)?;
+        let numer = self.numer.try_pow(magnitude)?;
+        let denom = self.denom.try_pow(magnitude)?;
+        if exponent.numer < 0 {
+            Rational::new(denom, numer)
+        } else {
+            Ok(Rational { numer, denom })
+        }
+    }
+
+    fn try_neg(self) -> Result<Self, EvalError> {
+        Ok(Rational { numer: self.numer.try_neg()?, denom: self.denom })
+    }
+}
+
+#[cfg(test)]
+fn rational(numer: i64, denom: i64) -> Rational {
+    Rational::new(numer, denom).unwrap()
+}
+
+#[test]
+fn test_rational_arithmetic() {
+    assert_eq!(rational(2, -4), rational(-1, 2));
+    assert_eq!(rational(1, 2).try_add(rational(1, 3)), Ok(rational(5, 6)));
+    assert_eq!(rational(1, 2).try_div(rational(-3, 4)), Ok(rational(-2, 3)));
+    assert_eq!(rational(7, 2).try_rem(rational(-1, 1)), Ok(rational(1, 2)));
+    assert_eq!(rational(-2, 3).try_pow(Rational::integer(-3)), Ok(rational(-27, 8)));
+    assert_eq!(rational(4, 1).try_pow(rational(1, 2)), Err(EvalError::NonIntegerExponent));
+    let min = Rational::integer(i64::MIN);
+    assert_eq!(Rational::integer(-1).try_pow(min), Ok(Rational::one()));
+    assert_eq!(Rational::integer(-1).try_pow(Rational::integer(i64::MIN + 1)), Ok(Rational::integer(-1)));
+    assert_eq!(Rational::one().try_pow(min), Ok(Rational::one()));
+    assert_eq!(Rational::zero().try_pow(min), Err(EvalError::DivideByZero));
+    assert_eq!(Rational::zero().try_pow(Rational::integer(i64::MAX)), Ok(Rational::zero()));
+    assert_eq!(Rational::zero().try_pow(Rational::zero()), Ok(Rational::one()));
+    assert_eq!(Rational::integer(2).try_pow(min), Err(EvalError::Overflow));
+    assert!(rational(1, 3) < rational(1, 2));
+    assert_eq!(rational(3, 4).to_string(), "3/4");
+}
+
+#[test]
+fn test_rational_errors() {
+    assert_eq!(Rational::new(1, 0), Err(EvalError::DivideByZero));
+    assert_eq!(rational(1, 2).try_div(Rational::zero()), Err(EvalError::DivideByZero));
+    assert_eq!(Rational::zero().try_pow(Rational::integer(-1)), Err(EvalError::DivideByZero));
+    assert_eq!(Rational::integer(i64::MAX).try_add(Rational::one()), Err(EvalError::Overflow));
+    assert_eq!(rational(1, i64::MAX).try_mul(rational(1, 2)), Err(EvalError::Overflow));
+    // i64::MIN / -1 is out of range, while the halved fraction is fine
+    assert_eq!(Rational::new(i64::MIN, -1), Err(EvalError::Overflow));
+    assert_eq!(Rational::new(i64::MIN, -2), Ok(Rational::integer(1 << 62)));
+}
+
+#[test]
+fn test_float_policy() {
+    assert_eq!(1.0f64.try_div(0.0), Err(EvalError::DivideByZero));
+    assert_eq!((-8.0f64).try_pow(1.0 / 3.0), Err(EvalError::NotANumber));
+    assert_eq!(f64::MAX.try_mul(2.0), Err(EvalError::Overflow));
+    assert_eq!(f64::NAN.check(), Err(EvalError::NotANumber));
+    assert_eq!(2.0f64.try_pow(-1.0), Ok(0.5));
+}
+
+#[test]
+fn test_wide_integers() {
+    assert_eq!(2i128.try_pow(100), Ok(1 << 100));
+    assert_eq!(2i128.try_pow(127), Err(EvalError::Overflow));
+    assert_eq!(i128::MIN.try_rem(-1), Err(EvalError::Overflow));
+}
@@ -0,0 +1,565 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::diagnostic::{Diagnostic, Span};
+use crate::expression::{Expression, Operation, UnaryOperation, UNARY_PRECEDENCE};
+
+pub const KEYWORDS: &[&str] = &["if", "then", "else", "true", "false", "let", "in", "fn"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub message: String,
+    /// The token where the problem was found.
+    pub span: Span,
+}
+
+impl ParseError {
+    pub fn diagnostic(&self) -> Diagnostic {
+        Diagnostic::new(self.message.clone(), Some(self.span))
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at offset {}", self.message, self.span.start)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token<'a> {
+    Number(&'a str),
+    Ident(&'a str),
+    Op(Operation),
+    Bang,
+    Assign,
+    Comma,
+    LParen,
+    RParen,
+    End,
+}
+
+fn tokenize(src: &str) -> Result<Vec<(Token<'_>, Span)>, ParseError> {
+    let mut tokens = Vec::new();
+    let bytes = src.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        let c = bytes[i];
+        let token = match c {
+            b' ' | b'\t' | b'\r' | b'\n' => {
+                i += 1;
+                continue;
+            }
+            b'0'..=b'9' => {
+                while i < bytes.len() && bytes[i].is_ascii_digit() {
+                    i += 1;
+                }
+                Token::Number(&src[start..i])
+            }
+            b'a'..=b'z' | b'A'..=b'Z' | b'_' => {
+                while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                    i += 1;
+                }
+                Token::Ident(&src[start..i])
+            }
+            _ => {
+                let rest = &src[start..];
+                let (token, len) = match rest.get(..2) {
+                    Some("**") => (Token::Op(Operation::Pow), 2),
+                    Some("==") => (Token::Op(Operation::Eq), 2),
+                    Some("!=") => (Token::Op(Operation::Ne), 2),
+                    Some("<=") => (Token::Op(Operation::Le), 2),
+                    Some(">=") => (Token::Op(Operation::Ge), 2),
+                    Some("&&") => (Token::Op(Operation::And), 2),
+                    Some("||") => (Token::Op(Operation::Or), 2),
+                    _ => match c {
+                        b'+' => (Token::Op(Operation::Add), 1),
+                        b'-' => (Token::Op(Operation::Sub), 1),
+                        b'*' => (Token::Op(Operation::Mul), 1),
+                        b'/' => (Token::Op(Operation::Div), 1),
+                        b'%' => (Token::Op(Operation::Rem), 1),
+                        b'<' => (Token::Op(Operation::Lt), 1),
+                        b'>' => (Token::Op(Operation::Gt), 1),
+                        b'!' => (Token::Bang, 1),
+                        b'=' => (Token::Assign, 1),
+                        b',' => (Token::Comma, 1),
+                        b'(' => (Token::LParen, 1),
+                        b')' => (Token::RParen, 1),
+                        _ => {
+                            let ch = rest.chars().next().unwrap();
+                            let span = Span::new(start, start + ch.len_utf8());
+                            return Err(ParseError { message: format!("unexpected character '{ch}'"), span });
+                        }
+                    },
+                };
+                i += len;
+                token
+            }
+        };
+        tokens.push((token, Span::new(start, i)));
+    }
+    tokens.push((Token::End, Span::new(src.len(), src.len())));
+    Ok(tokens)
+}
+
+// A construct that is still being parsed, waiting for the expression being parsed inside it.
+enum Frame<'a> {
+    /// Operators binding at least as tightly as `min_precedence`, with the left operand and operator waiting for
+    /// a right operand, once there is one.
+    Operators { min_precedence: u8, left: Option<(Expression, Operation, Span)> },
+    Unary { op: UnaryOperation, span: Span },
+    /// A call to `callee`, which started at `start`, with the arguments so far.
+    Arguments { start: Span, callee: Expression, args: Vec<Expression> },
+    Parenthesized { start: Span },
+    If { span: Span, cond: Option<Expression>, then: Option<Expression> },
+    /// `recursive` if `name` is in scope in the value, because it is a function.
+    Let { span: Span, name: &'a str, value: Option<Expression>, recursive: bool },
+    Function { span: Span, params: Vec<&'a str> },
+}
+
+impl Frame<'_> {
+    fn operators(min_precedence: u8) -> Self {
+        Frame::Operators { min_precedence, left: None }
+    }
+}
+
+// A finished expression: an atom, which may still be called and started at the given span, or any other value.
+enum Parsed {
+    Atom(Expression, Span),
+    Value(Expression),
+}
+
+struct Parser<'a> {
+    tokens: Vec<(Token<'a>, Span)>,
+    pos: usize,
+    /// Whether to wrap nodes in `Expression::Spanned`.
+    spans: bool,
+    variables: &'a HashMap<String, i64>,
+    /// Names bound by enclosing `let`s and function parameters, which shadow `variables`.
+    bound: Vec<&'a str>,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Token<'a> {
+        self.tokens[self.pos].0
+    }
+
+    fn span(&self) -> Span {
+        self.tokens[self.pos].1
+    }
+
+    // The span of the token before the current one.
+    fn previous_span(&self) -> Span {
+        self.tokens[self.pos.saturating_sub(1)].1
+    }
+
+    fn spanned(&self, e: Expression, span: Span) -> Expression {
+        if self.spans { Expression::Spanned { span, inner: Box::new(e) } } else { e }
+    }
+
+    fn advance(&mut self) -> Token<'a> {
+        let token = self.peek();
+        if token != Token::End {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, ParseError> {
+        Err(ParseError { message: message.into(), span: self.span() })
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), ParseError> {
+        if self.peek() != Token::Ident(keyword) {
+            return self.error(format!("expected '{keyword}'"));
+        }
+        self.advance();
+        Ok(())
+    }
+
+    fn expect(&mut self, token: Token<'a>, text: &str) -> Result<(), ParseError> {
+        if self.peek() != token {
+            return self.error(format!("expected '{text}'"));
+        }
+        self.advance();
+        Ok(())
+    }
+
+    fn name(&mut self) -> Result<&'a str, ParseError> {
+        match self.peek() {
+            Token::Ident(name) if !KEYWORDS.contains(&name) => {
+                self.advance();
+                Ok(name)
+            }
+            _ => self.error("expected a name"),
+        }
+    }
+
+    // Parses an expression whose binary operators bind at least as tightly as `min_precedence`.
+    //
+    // Nested expressions are parsed on `frames` rather than by recursing, so that deeply nested input can't
+    // overflow the stack: a construct that contains expressions pushes a frame saying what to do with each one as
+    // it is finished, then starts on the first.
+    fn expression(&mut self, min_precedence: u8) -> Result<Expression, ParseError> {
+        let mut frames = vec![Frame::operators(min_precedence)];
+        'operand: loop {
+            let Some(mut parsed) = self.operand(&mut frames)? else {
+                continue;
+            };
+            loop {
+                parsed = match parsed {
+                    // calls bind tighter than any operator: `f(1)(2)` calls the result of `f(1)`
+                    Parsed::Atom(callee, start) if self.peek() == Token::LParen => {
+                        self.advance();
+                        match self.argument(&mut frames, start, callee, Vec::new())? {
+                            Some(parsed) => parsed,
+                            None => continue 'operand,
+                        }
+                    }
+                    Parsed::Atom(e, _) => Parsed::Value(e),
+                    Parsed::Value(e) => match frames.pop().expect("an expression is only parsed for an open frame") {
+                        // binary operators are parsed by precedence climbing; all but `**` are left-associative
+                        Frame::Operators { min_precedence, left } => {
+                            let left = match left {
+                                Some((left, op, span)) => {
+                                    self.spanned(Expression::Op { op, left: Box::new(left), right: Box::new(e) }, span)
+                                }
+                                None => e,
+                            };
+                            match self.peek() {
+                                Token::Op(op) if op.precedence() >= min_precedence => {
+                                    let span = self.span();
+                                    self.advance();
+                                    frames.push(Frame::Operators { min_precedence, left: Some((left, op, span)) });
+                                    let right = if op.is_right_associative() { op.precedence() } else { op.precedence() + 1 };
+                                    frames.push(Frame::operators(right));
+                                    continue 'operand;
+                                }
+                                _ if frames.is_empty() => return Ok(left),
+                                _ => Parsed::Value(left),
+                            }
+                        }
+                        Frame::Unary { op, span } => Parsed::Value(self.spanned(Expression::Unary { op, operand: Box::new(e) }, span)),
+                        Frame::Arguments { start, callee, mut args } => {
+                            args.push(e);
+                            match self.argument(&mut frames, start, callee, args)? {
+                                Some(parsed) => parsed,
+                                None => continue 'operand,
+                            }
+                        }
+                        Frame::Parenthesized { start } => {
+                            if self.peek() != Token::RParen {
+                                return self.error("expected ')'");
+                            }
+                            self.advance();
+                            Parsed::Atom(e, start)
+                        }
+                        Frame::If { span, cond: None, .. } => {
+                            self.expect_keyword("then")?;
+                            frames.extend([Frame::If { span, cond: Some(e), then: None }, Frame::operators(0)]);
+                            continue 'operand;
+                        }
+                        Frame::If { span, cond, then: None } => {
+                            self.expect_keyword("else")?;
+                            frames.extend([Frame::If { span, cond, then: Some(e) }, Frame::operators(0)]);
+                            continue 'operand;
+                        }
+                        Frame::If { span, cond: Some(cond), then: Some(then) } => {
+                            let e = Expression::If { cond: Box::new(cond), then: Box::new(then), otherwise: Box::new(e) };
+                            Parsed::Atom(self.spanned(e, span), span)
+                        }
+                        Frame::Let { span, name, value: None, recursive } => {
+                            if recursive {
+                                self.bound.pop();
+                            }
+                            self.expect_keyword("in")?;
+                            self.bound.push(name);
+                            frames.extend([Frame::Let { span, name, value: Some(e), recursive }, Frame::operators(0)]);
+                            continue 'operand;
+                        }
+                        Frame::Let { span, name, value: Some(value), .. } => {
+                            self.bound.pop();
+                            let e = Expression::Let { name: name.to_string(), value: Box::new(value), body: Box::new(e) };
+                            Parsed::Atom(self.spanned(e, span), span)
+                        }
+                        Frame::Function { span, params } => {
+                            self.bound.truncate(self.bound.len() - params.len());
+                            let params = params.into_iter().map(String::from).collect();
+                            Parsed::Atom(self.spanned(Expression::Function { params, body: Box::new(e) }, span), span)
+                        }
+                    },
+                };
+            }
+        }
+    }
+
+    // After a call's opening parenthesis or one of its arguments: either the call is complete, or the frame for the
+    // next argument is pushed and `None` returned.
+    fn argument(
+        &mut self,
+        frames: &mut Vec<Frame<'a>>,
+        start: Span,
+        callee: Expression,
+        args: Vec<Expression>,
+    ) -> Result<Option<Parsed>, ParseError> {
+        if self.peek() == Token::RParen {
+            self.advance();
+            let call = Expression::Call { callee: Box::new(callee), args };
+            return Ok(Some(Parsed::Atom(self.spanned(call, start.to(self.previous_span())), start)));
+        }
+        if !args.is_empty() {
+            self.expect(Token::Comma, ",")?;
+        }
+        frames.extend([Frame::Arguments { start, callee, args }, Frame::operators(0)]);
+        Ok(None)
+    }
+
+    // Unary operators bind looser than `**`, so `-2 ** 2` is `-(2 ** 2)`.
+    fn operand(&mut self, frames: &mut Vec<Frame<'a>>) -> Result<Option<Parsed>, ParseError> {
+        let op = match self.peek() {
+            Token::Op(Operation::Sub) => UnaryOperation::Neg,
+            Token::Bang => UnaryOperation::Not,
+            _ => return self.atom(frames),
+        };
+        let span = self.span();
+        self.advance();
+        if let (UnaryOperation::Neg, Token::Number(digits)) = (op, self.peek())
+            && self.tokens[self.pos + 1].0 != Token::Op(Operation::Pow)
+        {
+            return self.number(&format!("-{digits}"), span.to(self.span())).map(|e| Some(Parsed::Value(e)));
+        }
+        frames.extend([Frame::Unary { op, span }, Frame::operators(UNARY_PRECEDENCE + 1)]);
+        Ok(None)
+    }
+
+    fn number(&mut self, text: &str, span: Span) -> Result<Expression, ParseError> {
+        match text.parse() {
+            Ok(v) => {
+                self.advance();
+                Ok(self.spanned(Expression::Value(v), span))
+            }
+            Err(_) => Err(ParseError { message: String::from("integer literal out of range"), span }),
+        }
+    }
+
+    // A leaf, or `None` once the frame for a construct made of expressions has been pushed.
+    fn atom(&mut self, frames: &mut Vec<Frame<'a>>) -> Result<Option<Parsed>, ParseError> {
+        let span = self.span();
+        let leaf = match self.peek() {
+            Token::Number(digits) => self.number(digits, span)?,
+            Token::Ident("true") => {
+                self.advance();
+                self.spanned(Expression::Bool(true), span)
+            }
+            Token::Ident("false") => {
+                self.advance();
+                self.spanned(Expression::Bool(false), span)
+            }
+            Token::Ident("if") => {
+                self.advance();
+                frames.extend([Frame::If { span, cond: None, then: None }, Frame::operators(0)]);
+                return Ok(None);
+            }
+            Token::Ident("let") => {
+                self.advance();
+                let name = self.name()?;
+                self.expect(Token::Assign, "=")?;
+                // a function bound by `let` can call itself
+                let recursive = self.peek() == Token::Ident("fn");
+                if recursive {
+                    self.bound.push(name);
+                }
+                frames.extend([Frame::Let { span, name, value: None, recursive }, Frame::operators(0)]);
+                return Ok(None);
+            }
+            Token::Ident("fn") => {
+                self.advance();
+                self.expect(Token::LParen, "(")?;
+                let mut params = Vec::new();
+                while self.peek() != Token::RParen {
+                    if !params.is_empty() {
+                        self.expect(Token::Comma, ",")?;
+                    }
+                    params.push(self.name()?);
+                }
+                self.advance();
+                self.bound.extend_from_slice(&params);
+                frames.extend([Frame::Function { span, params }, Frame::operators(0)]);
+                return Ok(None);
+            }
+            Token::Ident(keyword) if KEYWORDS.contains(&keyword) => return self.error(format!("unexpected '{keyword}'")),
+            Token::Ident(name) => {
+                self.advance();
+                let e = match self.variables.get(name) {
+                    Some(&v) if !self.bound.contains(&name) => Expression::Value(v),
+                    _ => Expression::Var(name.to_string()),
+                };
+                self.spanned(e, span)
+            }
+            Token::LParen => {
+                self.advance();
+                frames.extend([Frame::Parenthesized { start: span }, Frame::operators(0)]);
+                return Ok(None);
+            }
+            Token::End => return self.error("unexpected end of input"),
+            Token::Op(op) => return self.error(format!("unexpected '{}'", op.symbol())),
+            Token::Bang => return self.error("unexpected '!'"),
+            Token::Assign => return self.error("unexpected '='"),
+            Token::Comma => return self.error("unexpected ','"),
+            Token::RParen => return self.error("unexpected ')'"),
+        };
+        Ok(Some(Parsed::Atom(leaf, span)))
+    }
+}
+
+pub fn parse(src: &str) -> Result<Expression, ParseError> {
+    parse_with_variables(src, &HashMap::new())
+}
+
+/// Parses `src`, replacing each free identifier with its value from `variables`.
+pub fn parse_with_variables(src: &str, variables: &HashMap<String, i64>) -> Result<Expression, ParseError> {
+    parse_inner(src, variables, false)
+}
+
+/// `parse`, wrapping nodes in `Expression::Spanned` so evaluation errors can point back into `src`.
+///
+/// Operators are spanned by their symbol, calls by the whole call, and `if`, `let` and `fn` by their keyword.
+pub fn parse_spanned(src: &str) -> Result<Expression, ParseError> {
+    parse_spanned_with_variables(src, &HashMap::new())
+}
+
+pub fn parse_spanned_with_variables(src: &str, variables: &HashMap<String, i64>) -> Result<Expression, ParseError> {
+    parse_inner(src, variables, true)
+}
+
+fn parse_inner(src: &str, variables: &HashMap<String, i64>, spans: bool) -> Result<Expression, ParseError> {
+    let mut parser = Parser { tokens: tokenize(src)?, pos: 0, spans, variables, bound: Vec::new() };
+    let expr = parser.expression(0)?;
+    if parser.peek() != Token::End {
+        return parser.error("expected an operator");
+    }
+    Ok(expr)
+}
+
+#[test]
+fn test_parse_precedence() {
+    let expr = parse("10 * 9 + (3 - 4) * 5").unwrap();
+    assert_eq!(
+        expr,
+        Expression::Op {
+            op: Operation::Add,
+            left: Box::new(Expression::Op {
+                op: Operation::Mul,
+                left: Box::new(Expression::Value(10)),
+                right: Box::new(Expression::Value(9)),
+            }),
+            right: Box::new(Expression::Op {
+                op: Operation::Mul,
+                left: Box::new(Expression::Op {
+                    op: Operation::Sub,
+                    left: Box::new(Expression::Value(3)),
+                    right: Box::new(Expression::Value(4)),
+                }),
+                right: Box::new(Expression::Value(5)),
+            }),
+        }
+    );
+    assert_eq!(crate::expression::eval(&expr), Ok(85));
+}
+
+#[test]
+fn test_parse_display_round_trip() {
+    for src in [
+        "1 - (2 - 3)",
+        "1 - 2 - 3",
+        "-9223372036854775808 / -1",
+        "(1 + 2) * -(3 * 4)",
+        "8 / (4 / 2)",
+        "2 ** 3 ** 2",
+        "(2 ** 3) ** 2",
+        "-2 ** 2",
+        "(-2) ** 2",
+        "-(2)",
+        "- -2",
+        "!(1 < 2) || 3 % 2 == 1 && true",
+        "if 1 > 2 then 3 else if false then 4 else 5 * 6",
+        "(if true then 1 else 2) + 3",
+        "let sq = fn(x) x * x in sq(3) + sq(-4)",
+        "(fn(a, b) a - b)(1, 2)(3)",
+        "f() + (let y = 1 in y)",
+    ] {
+        let expr = parse(src).unwrap();
+        assert_eq!(parse(&expr.to_string()).unwrap(), expr, "{src}");
+    }
+}
+
+#[test]
+fn test_parse_errors() {
+    assert_eq!(parse("1 +").unwrap_err().span, Span::new(3, 3));
+    assert_eq!(parse("(1 + 2").unwrap_err().message, "expected ')'");
+    assert_eq!(parse("1 $ 2").unwrap_err().span, Span::new(2, 3));
+    assert_eq!(parse("1 + 99999999999999999999").unwrap_err().span, Span::new(4, 24));
+    assert_eq!(parse("let 1 = 2 in 3").unwrap_err().message, "expected a name");
+    assert_eq!(parse("fn(x y) x").unwrap_err().message, "expected ','");
+    assert!(parse("99999999999999999999").is_err());
+}
+
+#[test]
+fn test_parse_variables() {
+    let variables = HashMap::from([(String::from("x"), 6)]);
+    assert_eq!(parse_with_variables("x * -x", &variables).unwrap().to_string(), "6 * -(6)");
+}
+
+#[test]
+fn test_parse_operators() {
+    let value = |src: &str| crate::expression::eval(&parse(src).unwrap());
+    assert_eq!(value("2 ** 3 ** 2"), Ok(512));
+    assert_eq!(value("-2 ** 2"), Ok(-4));
+    assert_eq!(value("7 % 4 * 2"), Ok(6));
+    assert_eq!(value("1 + 1 == 2 && !(3 < 2)"), Ok(1));
+    assert_eq!(value("if 2 >= 3 then 10 else 20 + 1"), Ok(21));
+    assert_eq!(parse("if 1 then 2").unwrap_err().message, "expected 'else'");
+}
+
+#[test]
+fn test_parse_scopes_shadow_variables() {
+    let variables = HashMap::from([(String::from("x"), 6), (String::from("y"), 7)]);
+    let expr = parse_with_variables("let x = x + 1 in fn(y) x + y + x", &variables).unwrap();
+    assert_eq!(expr.to_string(), "let x = 6 + 1 in fn(y) x + y + x");
+}
+
+#[test]
+fn test_parse_spanned() {
+    let src = "if x then -1 else f(2 * y)";
+    let expr = parse_spanned(src).unwrap();
+    assert_eq!(expr.to_string(), parse(src).unwrap().to_string());
+    let text = |e: &Expression| e.span().map(|span| &src[span.start..span.end]);
+    let Expression::If { cond, then, otherwise } = expr.unspanned() else { panic!("{expr:?}") };
+    assert_eq!(text(&expr), Some("if"));
+    assert_eq!(text(cond), Some("x"));
+    assert_eq!(text(then), Some("-1"));
+    assert_eq!(text(otherwise), Some("f(2 * y)"));
+    let Expression::Call { args, .. } = otherwise.unspanned() else { panic!("{otherwise:?}") };
+    assert_eq!(text(&args[0]), Some("*"));
+}
+
+#[test]
+fn test_deep_nesting() {
+    let nested = |open: &str, inner: &str, close: &str, depth| format!("{}{inner}{}", open.repeat(depth), close.repeat(depth));
+    let eval = |src: &str| crate::expression::eval(&parse(src).unwrap());
+    assert_eq!(eval(&nested("(", "1", ")", 1_000_000)), Ok(1));
+    // the last `-` makes the literal -1, leaving an odd number of negations
+    assert_eq!(eval(&nested("-", "1", "", 1_000_000)), Ok(1));
+    assert_eq!(eval(&nested("!", "0", "", 1_000_000)), Ok(0));
+    assert_eq!(eval(&nested("1 ** ", "2", "", 1_000_000)), Ok(1));
+    assert_eq!(eval(&nested("if true then ", "1", " else 0", 200_000)), Ok(1));
+    assert_eq!(eval(&nested("let x = 1 in ", "x", "", 200_000)), Ok(1));
+    let limit = crate::expression::DEFAULT_MAX_CALL_DEPTH;
+    assert_eq!(eval(&nested("(fn(y) ", "y", ")(2)", 200_000)), Err(crate::expression::EvalError::CallDepthExceeded(limit)));
+    let calls = parse_spanned(&nested("f(", "1", ")", 200_000)).unwrap();
+    assert_eq!(crate::expression::evaluate_located(&calls).unwrap_err().to_string(), "unbound variable 'f' at offset 0");
+    assert_eq!(parse(&nested("(", "1", "", 1_000_000)).unwrap_err().to_string(), "expected ')' at offset 1000001");
+    assert_eq!(parse(&nested("-(", "", ")", 1_000_000)).unwrap_err().to_string(), "unexpected ')' at offset 2000000");
+}
@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+
+use crate::bigint::BigInt;
+use crate::derivative::differentiate;
+use crate::diagnostic::{Diagnostic, Span};
+use crate::expression::{evaluate_located, Expression};
+use crate::parser::{parse_spanned_with_variables, parse_with_variables, KEYWORDS};
+use crate::simplify::simplify;
+use crate::trace::trace;
+use crate::types::check;
+
+pub const HELP: &str = "\
+<expr>             evaluate an expression, e.g. (3 - 4) * 5
+<name> = <expr>    evaluate and store the result in a variable
+let sq = fn(x) x * x in sq(3)
+                   local bindings and (recursive) functions, within one line
+:ast <expr>        show the parsed tree
+:simplify <expr>   show the expression after simplification
+:diff <var> <expr> differentiate with respect to a variable
+:type <expr>       show the type of an expression; ill-typed input is rejected before evaluating
+:trace <expr>      show every step of evaluating an expression
+:big <expr>        evaluate with integers that never overflow; literals must still fit in 64 bits,
+                   and powers may have at most 2^24 bits
+:vars              list variables
+:history           list previous inputs
+!<n>, !!           re-run history entry n, or the last entry
+:help              show this message
+:quit              leave the calculator";
+
+/// How deep a tree `:ast` will show.
+pub const MAX_AST_DEPTH: usize = 100;
+
+/// What the caller should do after a line has been handled.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Print(String),
+    /// A rendered diagnostic, as plain text or as JSON depending on the session.
+    Error(String),
+    Quit,
+}
+
+/// Calculator state that lives for one interactive session: variables and input history.
+#[derive(Debug, Default)]
+pub struct Session {
+    variables: HashMap<String, i64>,
+    history: Vec<String>,
+    json_errors: bool,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A session that reports errors as single-line JSON objects instead of caret diagnostics.
+    pub fn with_json_errors() -> Self {
+        Session { json_errors: true, ..Self::default() }
+    }
+
+    pub fn history(&self) -> &[String] {
+        &self.history
+    }
+
+    pub fn execute(&mut self, line: &str) -> Outcome {
+        let line = line.trim();
+        if line.is_empty() {
+            return Outcome::Print(String::new());
+        }
+        let line = match self.recall(line) {
+            Ok(line) => line,
+            Err(message) => return self.error(&[Diagnostic::new(message, None)], line),
+        };
+        let outcome = match self.dispatch(&line) {
+            Ok(outcome) => outcome,
+            Err(diagnostics) => self.error(&diagnostics, &line),
+        };
+        if outcome != Outcome::Quit {
+            self.history.push(line);
+        }
+        outcome
+    }
+
+    fn recall(&self, line: &str) -> Result<String, String> {
+        let Some(entry) = line.strip_prefix('!') else {
+            return Ok(line.to_string());
+        };
+        let index = if entry == "!" {
+            self.history.len().checked_sub(1)
+        } else {
+            entry.parse::<usize>().ok().and_then(|n| n.checked_sub(1))
+        };
+        index
+            .and_then(|i| self.history.get(i))
+            .cloned()
+            .ok_or_else(|| format!("no history entry '{entry}'"))
+    }
+
+    // One diagnostic per line of output.
+    fn error(&self, diagnostics: &[Diagnostic], line: &str) -> Outcome {
+        let rendered: Vec<_> = diagnostics
+            .iter()
+            .map(|d| if self.json_errors { d.to_json(line) } else { d.render(line) })
+            .collect();
+        Outcome::Error(rendered.join("\n"))
+    }
+
+    // Spans in the returned diagnostics are relative to `line`.
+    fn dispatch(&mut self, line: &str) -> Result<Outcome, Vec<Diagnostic>> {
+        if let Some(command) = line.strip_prefix(':') {
+            let (name, argument) = command.split_once(char::is_whitespace).unwrap_or((command, ""));
+            return Ok(match name {
+                "ast" => {
+                    let expr = self.parse(line, argument)?;
+                    // the tree is printed with the derived `Debug`, which recurses and indents once per level
+                    let depth = expr.depth();
+                    if depth > MAX_AST_DEPTH {
+                        let message = format!("the tree is {depth} levels deep, too deep to show (the limit is {MAX_AST_DEPTH})");
+                        return Err(vec![Diagnostic::new(message, None)]);
+                    }
+                    Outcome::Print(format!("{expr:#?}"))
+                }
+                "simplify" => Outcome::Print(simplify(&self.parse(line, argument)?).to_string()),
+                "type" => Outcome::Print(self.check(line, argument)?.1),
+                "trace" => Outcome::Print(trace(&self.check(line, argument)?.0).to_string()),
+                "big" => {
+                    let expr = self.check(line, argument)?.0.map_values(&|&v| BigInt::from(v));
+                    let v = evaluate_located(&expr).map_err(|e| vec![within(line, argument, e.diagnostic())])?;
+                    Outcome::Print(v.to_string())
+                }
+                "diff" => {
+                    let argument = argument.trim_start();
+                    let (var, src) = argument.split_once(char::is_whitespace).unwrap_or((argument, ""));
+                    // stored variables are constants here, except the one being differentiated by
+                    let mut variables = self.variables.clone();
+                    variables.remove(var);
+                    let expr = parse_with_variables(src, &variables).map_err(|e| vec![within(line, src, e.diagnostic())])?;
+                    let derivative = differentiate(&expr, var);
+                    Outcome::Print(derivative.map_err(|e| vec![Diagnostic::new(e.to_string(), None)])?.to_string())
+                }
+                "vars" => {
+                    let mut variables: Vec<_> = self.variables.iter().collect();
+                    variables.sort();
+                    let lines: Vec<_> = variables.iter().map(|(name, v)| format!("{name} = {v}")).collect();
+                    Outcome::Print(lines.join("\n"))
+                }
+                "history" => {
+                    let lines: Vec<_> =
+                        self.history.iter().enumerate().map(|(i, line)| format!("{:>3}  {line}", i + 1)).collect();
+                    Outcome::Print(lines.join("\n"))
+                }
+                "help" => Outcome::Print(HELP.to_string()),
+                "quit" | "q" => Outcome::Quit,
+                _ => return Err(vec![Diagnostic::new(format!("unknown command ':{name}', try :help"), None)]),
+            });
+        }
+        match assignment(line) {
+            Some((name, src)) => {
+                let v = self.evaluate(line, src)?;
+                self.variables.insert(name.to_string(), v);
+                Ok(Outcome::Print(format!("{name} = {v}")))
+            }
+            None => Ok(Outcome::Print(self.evaluate(line, line)?.to_string())),
+        }
+    }
+
+    // `src` is a suffix of `line`.
+    fn parse(&self, line: &str, src: &str) -> Result<Expression, Vec<Diagnostic>> {
+        parse_with_variables(src, &self.variables).map_err(|e| vec![within(line, src, e.diagnostic())])
+    }
+
+    // Parses with spans and type checks, returning the tree and its type.
+    fn check(&self, line: &str, src: &str) -> Result<(Expression, String), Vec<Diagnostic>> {
+        let expr =
+            parse_spanned_with_variables(src, &self.variables).map_err(|e| vec![within(line, src, e.diagnostic())])?;
+        match check(&expr) {
+            Ok(typing) => {
+                let t = typing.root().to_string();
+                Ok((expr, t))
+            }
+            Err(errors) => Err(errors.iter().map(|e| within(line, src, e.diagnostic())).collect()),
+        }
+    }
+
+    fn evaluate(&self, line: &str, src: &str) -> Result<i64, Vec<Diagnostic>> {
+        let (expr, _) = self.check(line, src)?;
+        evaluate_located(&expr).map_err(|e| vec![within(line, src, e.diagnostic())])
+    }
+}
+
+// Moves a diagnostic about `src` to the same place in `line`, which ends with `src`.
+fn within(line: &str, src: &str, mut diagnostic: Diagnostic) -> Diagnostic {
+    let offset = line.len() - src.len();
+    diagnostic.span = diagnostic.span.map(|span| Span::new(span.start + offset, span.end + offset));
+    diagnostic
+}
+
+// Splits `name = expr`; anything else, including `name == expr`, is treated as a plain expression.
+fn assignment(line: &str) -> Option<(&str, &str)> {
+    let (name, src) = line.split_once('=')?;
+    let name = name.trim();
+    let mut chars = name.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !KEYWORDS.contains(&name)
+        && !src.starts_with('=');
+    valid.then_some((name, src))
+}
+
+#[test]
+fn test_session_variables() {
+    let mut session = Session::new();
+    assert_eq!(session.execute("x = 3 - 4"), Outcome::Print(String::from("x = -1")));
+    assert_eq!(session.execute("x * 5"), Outcome::Print(String::from("-5")));
+    assert_eq!(
+        session.execute("y + 1"),
+        Outcome::Error(String::from("error: unbound variable 'y'\n --> 1:1\n  |\n1 | y + 1\n  | ^"))
+    );
+    assert_eq!(
+        session.execute("z = 1 / (x + 1)"),
+        Outcome::Error(String::from("error: division by zero\n --> 1:7\n  |\n1 | z = 1 / (x + 1)\n  |       ^"))
+    );
+    assert_eq!(session.execute("x == -1"), Outcome::Print(String::from("1")));
+    assert_eq!(session.execute(":vars"), Outcome::Print(String::from("x = -1")));
+}
+
+#[test]
+fn test_session_commands() {
+    let mut session = Session::new();
+    assert_eq!(session.execute(":simplify (1 + 2) * 3"), Outcome::Print(String::from("9")));
+    assert_eq!(session.execute(":diff x x ** 2 + 3 * x"), Outcome::Print(String::from("2 * x + 3")));
+    assert_eq!(session.execute(":trace (3 - 4) * 5"), Outcome::Print(String::from("(3 - 4) * 5\n→ -1 * 5\n→ -5")));
+    assert_eq!(session.execute(":big 2 ** 64 * -3"), Outcome::Print(String::from("-55340232221128654848")));
+    assert_eq!(
+        session.execute(":big 3 ** 100000000"),
+        Outcome::Error(String::from("error: result would be larger than 16777216 bits\n --> 1:8\n  |\n1 | :big 3 ** 100000000\n  |        ^^"))
+    );
+    assert_eq!(session.execute(":ast 7"), Outcome::Print(String::from("Value(\n    7,\n)")));
+    assert_eq!(session.execute(":nope"), Outcome::Error(String::from("error: unknown command ':nope', try :help")));
+    assert_eq!(session.execute(":quit"), Outcome::Quit);
+}
+
+#[test]
+fn test_session_history() {
+    let mut session = Session::new();
+    session.execute("1 + 1");
+    session.execute("a = 2");
+    assert_eq!(session.execute("!1"), Outcome::Print(String::from("2")));
+    assert_eq!(session.execute("!!"), Outcome::Print(String::from("2")));
+    assert_eq!(session.execute("!9"), Outcome::Error(String::from("error: no history entry '9'")));
+    assert_eq!(session.history(), &["1 + 1", "a = 2", "1 + 1", "1 + 1"]);
+}
+
+#[test]
+fn test_session_json_errors() {
+    let mut session = Session::with_json_errors();
+    assert_eq!(
+        session.execute(":simplify 1 +"),
+        Outcome::Error(String::from(
+            r#"{"message":"unexpected end of input","span":{"start":13,"end":13,"line":1,"column":14},"source_line":":simplify 1 +"}"#
+        ))
+    );
+    assert_eq!(session.execute("2 * 3"), Outcome::Print(String::from("6")));
+}
+
+#[test]
+fn test_session_type_checks() {
+    let mut session = Session::new();
+    assert_eq!(session.execute(":type let sq = fn(x) x * x in sq"), Outcome::Print(String::from("fn(number) -> number")));
+    assert_eq!(
+        session.execute("true + -false"),
+        Outcome::Error(String::from(
+            "error: expected number, found bool\n --> 1:1\n  |\n1 | true + -false\n  | ^^^^\n\
+             error: expected number, found bool\n --> 1:9\n  |\n1 | true + -false\n  |         ^^^^^"
+        ))
+    );
+}
+
+#[test]
+fn test_session_deep_input() {
+    let mut session = Session::new();
+    let chain = format!("{}1", "1 + ".repeat(200_000));
+    assert_eq!(session.execute(&chain), Outcome::Print(String::from("200001")));
+    assert_eq!(session.execute(&format!(":type {chain}")), Outcome::Print(String::from("number")));
+    assert_eq!(session.execute(&format!(":simplify {chain}")), Outcome::Print(String::from("200001")));
+    assert_eq!(session.execute(&format!(":big {chain}")), Outcome::Print(String::from("200001")));
+    let Outcome::Print(traced) = session.execute(&format!(":trace {chain}")) else {
+        panic!("tracing a deep expression is not an error");
+    };
+    assert!(traced.ends_with("\nstopped at 200001 levels deep, past the limit of 200"));
+    assert_eq!(session.execute(&format!(":diff x x * {chain}")), Outcome::Print(String::from("1")));
+    assert_eq!(session.execute(&format!(":simplify x + {chain}")), Outcome::Print(format!("x{}", " + 1".repeat(200_001))));
+    assert_eq!(
+        session.execute(&format!(":ast {chain}")),
+        Outcome::Error(String::from("error: the tree is 200001 levels deep, too deep to show (the limit is 100)"))
+    );
+}
@@ -0,0 +1,939 @@
+use std::fmt::Display;
+use std::str::FromStr;
+
+use crate::diagnostic::{json_string, Span};
+use crate::expression::{Expression, Operation, UnaryOperation};
+use crate::parser::ParseError;
+
+// Stable encodings of `Expression` for storage, which unlike the infix syntax can represent every tree, spans and
+// all. Both parsers reject anything that is not exactly the output of the matching serializer, give or take
+// whitespace, `(var ...)` and `(num ...)` in S-expressions, and key order in JSON.
+
+fn operation(symbol: &str) -> Option<Operation> {
+    Some(match symbol {
+        "+" => Operation::Add,
+        "-" => Operation::Sub,
+        "*" => Operation::Mul,
+        "/" => Operation::Div,
+        "%" => Operation::Rem,
+        "**" => Operation::Pow,
+        "==" => Operation::Eq,
+        "!=" => Operation::Ne,
+        "<" => Operation::Lt,
+        "<=" => Operation::Le,
+        ">" => Operation::Gt,
+        ">=" => Operation::Ge,
+        "&&" => Operation::And,
+        "||" => Operation::Or,
+        _ => return None,
+    })
+}
+
+fn unary_operation(symbol: &str) -> Option<UnaryOperation> {
+    match symbol {
+        "-" => Some(UnaryOperation::Neg),
+        "!" => Some(UnaryOperation::Not),
+        _ => None,
+    }
+}
+
+fn error<T>(message: impl Into<String>, span: Span) -> Result<T, ParseError> {
+    Err(ParseError { message: message.into(), span })
+}
+
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_') && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Whether a number's text can be written as a bare S-expression atom: it must read back as a number, not a name.
+fn is_numeric(s: &str) -> bool {
+    let digits = s.strip_prefix('-').unwrap_or(s);
+    digits.starts_with(|c: char| c.is_ascii_digit()) && !s.contains(|c: char| c.is_whitespace() || "()\"".contains(c))
+}
+
+/// The tree as an S-expression, such as `(+ (* 10 9) (* (- 3 4) 5))`.
+///
+/// Operators are written as in the infix syntax, with `(- x)` and `(! x)` for the unary ones. The other forms are
+/// `(if c t e)`, `(let x value body)`, `(fn (a b) body)`, `(call f arg...)` and `(span start end e)`. Variables are
+/// bare names and numbers bare literals, except that a name or number that would read back as something else is
+/// written `(var "name")` or `(num "text")`.
+pub fn to_sexpr<N: Display>(e: &Expression<N>) -> String {
+    write_pieces(e, sexpr_pieces)
+}
+
+// Output still to be written: text as it is, or a subtree to be serialized in its place.
+enum Piece<'a, N> {
+    Text(String),
+    Tree(&'a Expression<N>),
+}
+
+// Serializes a tree without recursing, by having `expand` replace each subtree with the pieces it is written as.
+fn write_pieces<'a, N>(e: &'a Expression<N>, mut expand: impl FnMut(&'a Expression<N>, &mut Vec<Piece<'a, N>>)) -> String {
+    let mut out = String::new();
+    let mut pending = vec![Piece::Tree(e)];
+    let mut pieces = Vec::new();
+    while let Some(piece) = pending.pop() {
+        match piece {
+            Piece::Text(text) => out.push_str(&text),
+            Piece::Tree(e) => {
+                expand(e, &mut pieces);
+                pending.extend(pieces.drain(..).rev());
+            }
+        }
+    }
+    out
+}
+
+fn sexpr_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n"))
+}
+
+fn sexpr_name(name: &str) -> String {
+    if is_identifier(name) { name.to_string() } else { sexpr_string(name) }
+}
+
+fn sexpr_pieces<'a, N: Display>(e: &'a Expression<N>, pieces: &mut Vec<Piece<'a, N>>) {
+    let mut list = |head: &str, children: &[&'a Expression<N>]| {
+        pieces.push(Piece::Text(format!("({head}")));
+        for child in children {
+            pieces.push(Piece::Text(String::from(" ")));
+            pieces.push(Piece::Tree(child));
+        }
+        pieces.push(Piece::Text(String::from(")")));
+    };
+    match e {
+        Expression::Op { op, left, right } => list(op.symbol(), &[left, right]),
+        Expression::Unary { op, operand } => list(op.symbol(), &[operand]),
+        Expression::If { cond, then, otherwise } => list("if", &[cond, then, otherwise]),
+        Expression::Let { name, value, body } => list(&format!("let {}", sexpr_name(name)), &[value, body]),
+        Expression::Function { params, body } => {
+            let params: Vec<_> = params.iter().map(|p| sexpr_name(p)).collect();
+            list(&format!("fn ({})", params.join(" ")), &[body])
+        }
+        Expression::Call { callee, args } => {
+            let children: Vec<_> = std::iter::once(&**callee).chain(args).collect();
+            list("call", &children)
+        }
+        Expression::Var(name) if is_identifier(name) && name != "true" && name != "false" => {
+            pieces.push(Piece::Text(name.clone()))
+        }
+        Expression::Var(name) => pieces.push(Piece::Text(format!("(var {})", sexpr_string(name)))),
+        Expression::Value(v) => {
+            let text = v.to_string();
+            pieces.push(Piece::Text(if is_numeric(&text) { text } else { format!("(num {})", sexpr_string(&text)) }))
+        }
+        Expression::Bool(b) => pieces.push(Piece::Text(b.to_string())),
+        Expression::Spanned { span, inner } => list(&format!("span {} {}", span.start, span.end), &[inner]),
+    }
+}
+
+#[derive(Debug)]
+enum SExpr {
+    Atom(String, Span),
+    Str(String, Span),
+    List(Vec<SExpr>, Span),
+}
+
+impl SExpr {
+    fn span(&self) -> Span {
+        match self {
+            SExpr::Atom(_, span) | SExpr::Str(_, span) | SExpr::List(_, span) => *span,
+        }
+    }
+}
+
+// As with `Expression`, nested lists are dropped from a heap stack so that deep input can't overflow the stack.
+impl Drop for SExpr {
+    fn drop(&mut self) {
+        let SExpr::List(items, _) = self else { return };
+        let mut pending = std::mem::take(items);
+        while let Some(mut item) = pending.pop() {
+            if let SExpr::List(items, _) = &mut item {
+                pending.append(items);
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn skip_whitespace(&mut self) {
+        let rest = &self.src[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src[self.pos..].chars().next()
+    }
+
+    fn here(&self) -> Span {
+        let len = self.peek().map_or(0, char::len_utf8);
+        Span::new(self.pos, self.pos + len)
+    }
+
+    // Reads one S-expression, keeping the lists still open on a heap stack rather than recursing into them.
+    fn read(&mut self) -> Result<SExpr, ParseError> {
+        let mut open: Vec<(Vec<SExpr>, usize)> = Vec::new();
+        loop {
+            self.skip_whitespace();
+            let start = self.pos;
+            let item = match self.peek() {
+                None if open.is_empty() => return error("unexpected end of input", self.here()),
+                None => return error("expected ')'", self.here()),
+                Some(')') => {
+                    let Some((items, start)) = open.pop() else {
+                        return error("unexpected ')'", self.here());
+                    };
+                    self.pos += 1;
+                    SExpr::List(items, Span::new(start, self.pos))
+                }
+                Some('(') => {
+                    self.pos += 1;
+                    open.push((Vec::new(), start));
+                    continue;
+                }
+                Some('"') => SExpr::Str(self.sexpr_string()?, Span::new(start, self.pos)),
+                Some(_) => {
+                    let rest = &self.src[start..];
+                    let len = rest.find(|c: char| c.is_whitespace() || "()\"".contains(c)).unwrap_or(rest.len());
+                    self.pos += len;
+                    SExpr::Atom(rest[..len].to_string(), Span::new(start, self.pos))
+                }
+            };
+            match open.last_mut() {
+                Some((items, _)) => items.push(item),
+                None => return Ok(item),
+            }
+        }
+    }
+
+    fn sexpr_string(&mut self) -> Result<String, ParseError> {
+        let start = self.pos;
+        self.pos += 1;
+        let mut s = String::new();
+        loop {
+            let c = match self.peek() {
+                Some(c) => c,
+                None => return error("unterminated string", Span::new(start, self.pos)),
+            };
+            self.pos += c.len_utf8();
+            match c {
+                '"' => return Ok(s),
+                '\\' => {
+                    let escape = self.here();
+                    match self.peek() {
+                        Some('"') => s.push('"'),
+                        Some('\\') => s.push('\\'),
+                        Some('n') => s.push('\n'),
+                        _ => return error("invalid escape in string", Span::new(escape.start - 1, escape.end)),
+                    }
+                    self.pos = escape.end;
+                }
+                c => s.push(c),
+            }
+        }
+    }
+}
+
+/// Reads the output of `to_sexpr` back into exactly the same tree.
+pub fn parse_sexpr<N: FromStr>(src: &str) -> Result<Expression<N>, ParseError> {
+    let mut reader = Reader { src, pos: 0 };
+    let sexpr = reader.read()?;
+    reader.skip_whitespace();
+    if reader.pos < src.len() {
+        return error("expected end of input", reader.here());
+    }
+    build(&sexpr, sexpr_node)
+}
+
+// A placeholder for a child that `build` fills in later.
+fn hole<N>() -> Box<Expression<N>> {
+    Box::new(Expression::Bool(false))
+}
+
+// A node whose children are still holes, with the inputs to build them from.
+type Shell<N, I> = Result<(Expression<N>, Vec<I>), ParseError>;
+
+// Builds a tree bottom-up on a heap stack. `node` turns each input into an expression whose children are still
+// holes, along with the inputs for those children, in the order `children_mut` lists them.
+fn build<I: Copy, N>(root: I, mut node: impl FnMut(I) -> Shell<N, I>) -> Result<Expression<N>, ParseError> {
+    enum Step<I, N> {
+        Enter(I),
+        Leave(Expression<N>, usize),
+    }
+    let mut steps = vec![Step::Enter(root)];
+    let mut done = Vec::new();
+    while let Some(step) = steps.pop() {
+        match step {
+            Step::Enter(input) => {
+                let (e, children) = node(input)?;
+                steps.push(Step::Leave(e, children.len()));
+                steps.extend(children.into_iter().rev().map(Step::Enter));
+            }
+            Step::Leave(mut e, count) => {
+                let children = done.split_off(done.len() - count);
+                for (slot, child) in e.children_mut().into_iter().zip(children) {
+                    *slot = child;
+                }
+                done.push(e);
+            }
+        }
+    }
+    Ok(done.pop().expect("the root is built last"))
+}
+
+fn name(sexpr: &SExpr) -> Result<String, ParseError> {
+    match sexpr {
+        SExpr::Atom(name, _) if is_identifier(name) => Ok(name.clone()),
+        SExpr::Str(name, _) => Ok(name.clone()),
+        _ => error("expected a name", sexpr.span()),
+    }
+}
+
+fn number<N: FromStr>(text: &str, span: Span) -> Result<N, ParseError> {
+    text.parse().or_else(|_| error(format!("invalid number '{text}'"), span))
+}
+
+fn offset(sexpr: &SExpr) -> Result<usize, ParseError> {
+    match sexpr {
+        SExpr::Atom(text, span) => text.parse().or_else(|_| error("expected an offset", *span)),
+        _ => error("expected an offset", sexpr.span()),
+    }
+}
+
+// One node of the tree from its S-expression, leaving holes for `build` to fill with its children.
+fn sexpr_node<N: FromStr>(sexpr: &SExpr) -> Shell<N, &SExpr> {
+    let (items, span) = match sexpr {
+        SExpr::Atom(text, span) => {
+            let leaf = match text.as_str() {
+                "true" => Expression::Bool(true),
+                "false" => Expression::Bool(false),
+                text if is_numeric(text) => Expression::Value(number(text, *span)?),
+                text if is_identifier(text) => Expression::Var(text.to_string()),
+                text => return error(format!("unexpected '{text}'"), *span),
+            };
+            return Ok((leaf, Vec::new()));
+        }
+        SExpr::Str(_, span) => return error("expected an expression, found a string", *span),
+        SExpr::List(items, span) => (items, *span),
+    };
+    let Some(SExpr::Atom(head, head_span)) = items.first() else {
+        return error("expected an operator or form name", items.first().map_or(span, SExpr::span));
+    };
+    let args = &items[1..];
+    let arity = |n: usize| {
+        if args.len() == n {
+            return Ok(());
+        }
+        let s = if n == 1 { "" } else { "s" };
+        error(format!("'{head}' takes {n} argument{s}, found {}", args.len()), span)
+    };
+    let mut children = Vec::new();
+    let mut expr = |i: usize| {
+        children.push(&args[i]);
+        hole()
+    };
+    let e = match head.as_str() {
+        "if" => {
+            arity(3)?;
+            Expression::If { cond: expr(0), then: expr(1), otherwise: expr(2) }
+        }
+        "let" => {
+            arity(3)?;
+            Expression::Let { name: name(&args[0])?, value: expr(1), body: expr(2) }
+        }
+        "fn" => {
+            arity(2)?;
+            let SExpr::List(params, _) = &args[0] else {
+                return error("expected a parameter list", args[0].span());
+            };
+            Expression::Function { params: params.iter().map(name).collect::<Result<_, _>>()?, body: expr(1) }
+        }
+        "call" => {
+            if args.is_empty() {
+                return error("'call' needs a function", span);
+            }
+            let callee = expr(0);
+            Expression::Call { callee, args: (1..args.len()).map(|i| *expr(i)).collect() }
+        }
+        "var" => {
+            arity(1)?;
+            match &args[0] {
+                SExpr::Str(name, _) => Expression::Var(name.clone()),
+                other => return error("expected a quoted name", other.span()),
+            }
+        }
+        "num" => {
+            arity(1)?;
+            match &args[0] {
+                SExpr::Str(text, span) => Expression::Value(number(text, *span)?),
+                other => return error("expected a quoted number", other.span()),
+            }
+        }
+        "span" => {
+            arity(3)?;
+            let (start, end) = (offset(&args[0])?, offset(&args[1])?);
+            if start > end {
+                return error("a span cannot end before it starts", args[1].span());
+            }
+            Expression::Spanned { span: Span::new(start, end), inner: expr(2) }
+        }
+        symbol => match (operation(symbol), unary_operation(symbol), args.len()) {
+            (Some(op), _, 2) => Expression::Op { op, left: expr(0), right: expr(1) },
+            (_, Some(op), 1) => Expression::Unary { op, operand: expr(0) },
+            (Some(_), _, _) | (_, Some(_), _) => {
+                let counts = if unary_operation(symbol).is_some() { "1 or 2" } else { "2" };
+                return error(format!("'{symbol}' takes {counts} arguments, found {}", args.len()), span);
+            }
+            (None, None, _) => return error(format!("unknown operator or form '{symbol}'"), *head_span),
+        },
+    };
+    Ok((e, children))
+}
+
+// Whether a number's text is also valid as a JSON number, so it can be written without quotes.
+fn is_json_number(s: &str) -> bool {
+    let s = s.strip_prefix('-').unwrap_or(s);
+    let (int, rest) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let rest = match rest.strip_prefix('.') {
+        Some(fraction) => {
+            let digits = fraction.find(|c: char| !c.is_ascii_digit()).unwrap_or(fraction.len());
+            if digits == 0 {
+                return false;
+            }
+            &fraction[digits..]
+        }
+        None => rest,
+    };
+    let exponent_ok = match rest.strip_prefix(['e', 'E']) {
+        Some(exponent) => {
+            let digits = exponent.strip_prefix(['+', '-']).unwrap_or(exponent);
+            !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit())
+        }
+        None => rest.is_empty(),
+    };
+    !int.is_empty() && (int == "0" || !int.starts_with('0')) && exponent_ok
+}
+
+/// The tree as JSON, one object per node:
+///
+/// ```text
+/// {"op":"+","left":{"value":1},"right":{"unary":"-","operand":{"var":"x"}}}
+/// ```
+///
+/// The other nodes are `{"if":..,"then":..,"else":..}`, `{"let":"x","value":..,"body":..}`,
+/// `{"fn":["a","b"],"body":..}`, `{"call":..,"args":[..]}`, `{"bool":true}` and `{"span":[start,end],"inner":..}`.
+/// Numbers are JSON numbers with all their digits, or strings if they are not valid JSON numbers, like `"NaN"`.
+pub fn to_json<N: Display>(e: &Expression<N>) -> String {
+    write_pieces(e, json_pieces)
+}
+
+fn json_pieces<'a, N: Display>(e: &'a Expression<N>, pieces: &mut Vec<Piece<'a, N>>) {
+    let mut object = |fields: &[(&str, Result<String, &'a Expression<N>>)]| {
+        for (i, (key, value)) in fields.iter().enumerate() {
+            pieces.push(Piece::Text(format!("{}\"{key}\":", if i == 0 { "{" } else { "," })));
+            pieces.push(match value {
+                Ok(json) => Piece::Text(json.clone()),
+                Err(e) => Piece::Tree(e),
+            });
+        }
+        pieces.push(Piece::Text(String::from("}")));
+    };
+    match e {
+        Expression::Op { op, left, right } => {
+            object(&[("op", Ok(json_string(op.symbol()))), ("left", Err(left)), ("right", Err(right))])
+        }
+        Expression::Unary { op, operand } => object(&[("unary", Ok(json_string(op.symbol()))), ("operand", Err(operand))]),
+        Expression::If { cond, then, otherwise } => object(&[("if", Err(cond)), ("then", Err(then)), ("else", Err(otherwise))]),
+        Expression::Let { name, value, body } => object(&[("let", Ok(json_string(name))), ("value", Err(value)), ("body", Err(body))]),
+        Expression::Function { params, body } => {
+            let params: Vec<_> = params.iter().map(|p| json_string(p)).collect();
+            object(&[("fn", Ok(format!("[{}]", params.join(",")))), ("body", Err(body))])
+        }
+        Expression::Call { callee, args } => {
+            pieces.push(Piece::Text(String::from("{\"call\":")));
+            pieces.push(Piece::Tree(callee));
+            pieces.push(Piece::Text(String::from(",\"args\":[")));
+            for (i, arg) in args.iter().enumerate() {
+                if i > 0 {
+                    pieces.push(Piece::Text(String::from(",")));
+                }
+                pieces.push(Piece::Tree(arg));
+            }
+            pieces.push(Piece::Text(String::from("]}")));
+        }
+        Expression::Var(name) => object(&[("var", Ok(json_string(name)))]),
+        Expression::Value(v) => {
+            let text = v.to_string();
+            object(&[("value", Ok(if is_json_number(&text) { text } else { json_string(&text) }))])
+        }
+        Expression::Bool(b) => object(&[("bool", Ok(b.to_string()))]),
+        Expression::Spanned { span, inner } => {
+            object(&[("span", Ok(format!("[{},{}]", span.start, span.end))), ("inner", Err(inner))])
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Json {
+    Null,
+    Bool(bool),
+    /// The number's text, unconverted.
+    Number(String),
+    String(String),
+    Array(Vec<(Json, Span)>),
+    Object(Vec<(String, Json, Span)>),
+}
+
+impl Json {
+    fn kind(&self) -> &'static str {
+        match self {
+            Json::Null => "null",
+            Json::Bool(_) => "a boolean",
+            Json::Number(_) => "a number",
+            Json::String(_) => "a string",
+            Json::Array(_) => "an array",
+            Json::Object(_) => "an object",
+        }
+    }
+
+    fn take_nested(&mut self, out: &mut Vec<Json>) {
+        match self {
+            Json::Array(items) => out.extend(items.drain(..).map(|(item, _)| item)),
+            Json::Object(fields) => out.extend(fields.drain(..).map(|(_, value, _)| value)),
+            _ => {}
+        }
+    }
+}
+
+// Nested values are dropped from a heap stack too.
+impl Drop for Json {
+    fn drop(&mut self) {
+        let mut pending = Vec::new();
+        self.take_nested(&mut pending);
+        while let Some(mut json) = pending.pop() {
+            json.take_nested(&mut pending);
+        }
+    }
+}
+
+impl Reader<'_> {
+    fn expect(&mut self, c: char) -> Result<(), ParseError> {
+        self.skip_whitespace();
+        if self.peek() != Some(c) {
+            return error(format!("expected '{c}'"), self.here());
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    // Reads one JSON value, keeping the arrays and objects still open on a heap stack rather than recursing into
+    // them. An open object also holds the key of the value being read.
+    fn json(&mut self) -> Result<(Json, Span), ParseError> {
+        enum Open {
+            Array(Vec<(Json, Span)>),
+            Object(Vec<(String, Json, Span)>, String),
+        }
+        let mut open: Vec<(Open, usize)> = Vec::new();
+        loop {
+            self.skip_whitespace();
+            let start = self.pos;
+            let mut value = match self.peek() {
+                Some('{') => {
+                    self.pos += 1;
+                    self.skip_whitespace();
+                    if self.peek() == Some('}') {
+                        self.pos += 1;
+                        Json::Object(Vec::new())
+                    } else {
+                        let key = self.json_key(&[])?;
+                        open.push((Open::Object(Vec::new(), key), start));
+                        continue;
+                    }
+                }
+                Some('[') => {
+                    self.pos += 1;
+                    self.skip_whitespace();
+                    if self.peek() == Some(']') {
+                        self.pos += 1;
+                        Json::Array(Vec::new())
+                    } else {
+                        open.push((Open::Array(Vec::new()), start));
+                        continue;
+                    }
+                }
+                _ => self.json_scalar()?,
+            };
+            let mut span = Span::new(start, self.pos);
+            // add the value to the innermost open array or object, closing as many of them as end here
+            loop {
+                let Some((container, container_start)) = open.last_mut() else {
+                    return Ok((value, span));
+                };
+                self.skip_whitespace();
+                let close = match container {
+                    Open::Array(items) => {
+                        items.push((value, span));
+                        match self.peek() {
+                            Some(',') => None,
+                            Some(']') => Some(Json::Array(std::mem::take(items))),
+                            _ => return error("expected ',' or ']'", self.here()),
+                        }
+                    }
+                    Open::Object(fields, key) => {
+                        fields.push((std::mem::take(key), value, span));
+                        match self.peek() {
+                            Some(',') => {
+                                self.pos += 1;
+                                *key = self.json_key(fields)?;
+                                break;
+                            }
+                            Some('}') => Some(Json::Object(std::mem::take(fields))),
+                            _ => return error("expected ',' or '}'", self.here()),
+                        }
+                    }
+                };
+                self.pos += 1;
+                let Some(closed) = close else { break };
+                span = Span::new(*container_start, self.pos);
+                value = closed;
+                open.pop();
+            }
+        }
+    }
+
+    // An object's next key and the ':' after it, which must not repeat any of the keys in `fields`.
+    fn json_key(&mut self, fields: &[(String, Json, Span)]) -> Result<String, ParseError> {
+        self.skip_whitespace();
+        let key_span = self.here();
+        let key = match self.peek() {
+            Some('"') => self.json_string()?,
+            Some('{') => return error("expected a key, found an object", key_span),
+            Some('[') => return error("expected a key, found an array", key_span),
+            _ => {
+                let value = self.json_scalar()?;
+                return error(format!("expected a key, found {}", value.kind()), Span::new(key_span.start, self.pos));
+            }
+        };
+        if fields.iter().any(|(k, _, _)| *k == key) {
+            return error(format!("duplicate key '{key}'"), key_span);
+        }
+        self.expect(':')?;
+        Ok(key)
+    }
+
+    // A string, number, boolean or null.
+    fn json_scalar(&mut self) -> Result<Json, ParseError> {
+        let start = self.pos;
+        let rest = &self.src[start..];
+        Ok(match self.peek() {
+            None => return error("unexpected end of input", self.here()),
+            Some('"') => Json::String(self.json_string()?),
+            Some('-' | '0'..='9') => {
+                let len = rest.find(|c: char| !(c.is_ascii_alphanumeric() || "+-.".contains(c))).unwrap_or(rest.len());
+                self.pos += len;
+                if !is_json_number(&rest[..len]) {
+                    return error(format!("invalid number '{}'", &rest[..len]), Span::new(start, self.pos));
+                }
+                Json::Number(rest[..len].to_string())
+            }
+            Some(_) => {
+                let len = rest.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(rest.len()).max(1);
+                let word = rest.get(..len).unwrap_or(&rest[..rest.chars().next().map_or(0, char::len_utf8)]);
+                self.pos += word.len();
+                match word {
+                    "true" => Json::Bool(true),
+                    "false" => Json::Bool(false),
+                    "null" => Json::Null,
+                    _ => return error(format!("unexpected '{word}'"), Span::new(start, self.pos)),
+                }
+            }
+        })
+    }
+
+    fn json_string(&mut self) -> Result<String, ParseError> {
+        let start = self.pos;
+        self.pos += 1;
+        let mut s = String::new();
+        loop {
+            let Some(c) = self.peek() else {
+                return error("unterminated string", Span::new(start, self.pos));
+            };
+            let at = self.pos;
+            self.pos += c.len_utf8();
+            match c {
+                '"' => return Ok(s),
+                '\\' => {
+                    let escaped = match self.peek() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('/') => '/',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('u') => {
+                            self.pos += 1;
+                            let mut unit = self.hex4(at)?;
+                            if (0xd800..0xdc00).contains(&unit) && self.src[self.pos..].starts_with("\\u") {
+                                self.pos += 2;
+                                let low = self.hex4(at)?;
+                                if !(0xdc00..0xe000).contains(&low) {
+                                    return error("invalid surrogate pair", Span::new(at, self.pos));
+                                }
+                                unit = 0x10000 + ((unit - 0xd800) << 10) + (low - 0xdc00);
+                            }
+                            s.push(char::from_u32(unit).ok_or(ParseError {
+                                message: String::from("invalid unicode escape"),
+                                span: Span::new(at, self.pos),
+                            })?);
+                            continue;
+                        }
+                        _ => return error("invalid escape in string", Span::new(at, self.pos + 1)),
+                    };
+                    self.pos += 1;
+                    s.push(escaped);
+                }
+                c if (c as u32) < 0x20 => return error("control character in string", Span::new(at, self.pos)),
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn hex4(&mut self, escape: usize) -> Result<u32, ParseError> {
+        let digits = self.src.get(self.pos..self.pos + 4).filter(|d| d.bytes().all(|b| b.is_ascii_hexdigit()));
+        let Some(digits) = digits else {
+            return error("invalid unicode escape", Span::new(escape, self.pos));
+        };
+        self.pos += 4;
+        Ok(u32::from_str_radix(digits, 16).expect("four hex digits"))
+    }
+}
+
+/// Reads the output of `to_json` back into exactly the same tree. Keys may come in any order.
+pub fn parse_json<N: FromStr>(src: &str) -> Result<Expression<N>, ParseError> {
+    let mut reader = Reader { src, pos: 0 };
+    let (json, span) = reader.json()?;
+    reader.skip_whitespace();
+    if reader.pos < src.len() {
+        return error("expected end of input", reader.here());
+    }
+    build((&json, span), json_node)
+}
+
+// One node of the tree from its JSON object, leaving holes for `build` to fill with its children.
+fn json_node<N: FromStr>((json, span): (&Json, Span)) -> Shell<N, (&Json, Span)> {
+    let Json::Object(fields) = json else {
+        return error(format!("expected an object, found {}", json.kind()), span);
+    };
+    const KINDS: [&str; 10] = ["op", "unary", "if", "let", "fn", "call", "var", "value", "bool", "span"];
+    let Some(kind) = KINDS.iter().find(|kind| fields.iter().any(|(key, _, _)| key == *kind)) else {
+        return error("expected an object with one of the keys 'op', 'unary', 'if', 'let', 'fn', 'call', 'var', 'value', 'bool' or 'span'", span);
+    };
+    let keys: &[&str] = match *kind {
+        "op" => &["op", "left", "right"],
+        "unary" => &["unary", "operand"],
+        "if" => &["if", "then", "else"],
+        "let" => &["let", "value", "body"],
+        "fn" => &["fn", "body"],
+        "call" => &["call", "args"],
+        "span" => &["span", "inner"],
+        "var" => &["var"],
+        "value" => &["value"],
+        _ => &["bool"],
+    };
+    if let Some((key, _, value_span)) = fields.iter().find(|(key, _, _)| !keys.contains(&key.as_str())) {
+        return error(format!("unexpected key '{key}' alongside '{kind}'"), *value_span);
+    }
+    let field = |key: &str| match fields.iter().find(|(k, _, _)| k == key) {
+        Some((_, value, span)) => Ok((value, *span)),
+        None => error(format!("missing key '{key}' alongside '{kind}'"), span),
+    };
+    let mut children = Vec::new();
+    let mut expr = |key: &str| {
+        children.push(field(key)?);
+        Ok(hole())
+    };
+    let symbol = |key: &str| match field(key)? {
+        (Json::String(s), span) => Ok((s.clone(), span)),
+        (other, span) => error(format!("expected a string for '{key}', found {}", other.kind()), span),
+    };
+    let string = |key: &str| symbol(key).map(|(s, _)| s);
+    let e = match *kind {
+        "op" => {
+            let (symbol, symbol_span) = symbol("op")?;
+            let Some(op) = operation(&symbol) else {
+                return error(format!("unknown operator '{symbol}'"), symbol_span);
+            };
+            Expression::Op { op, left: expr("left")?, right: expr("right")? }
+        }
+        "unary" => {
+            let (symbol, symbol_span) = symbol("unary")?;
+            let Some(op) = unary_operation(&symbol) else {
+                return error(format!("unknown unary operator '{symbol}'"), symbol_span);
+            };
+            Expression::Unary { op, operand: expr("operand")? }
+        }
+        "if" => Expression::If { cond: expr("if")?, then: expr("then")?, otherwise: expr("else")? },
+        "let" => Expression::Let { name: string("let")?, value: expr("value")?, body: expr("body")? },
+        "fn" => {
+            let params = match field("fn")? {
+                (Json::Array(items), _) => items
+                    .iter()
+                    .map(|(item, span)| match item {
+                        Json::String(s) => Ok(s.clone()),
+                        other => error(format!("expected a parameter name, found {}", other.kind()), *span),
+                    })
+                    .collect::<Result<_, _>>()?,
+                (other, span) => return error(format!("expected an array of parameters, found {}", other.kind()), span),
+            };
+            Expression::Function { params, body: expr("body")? }
+        }
+        "call" => {
+            let callee = expr("call")?;
+            let args = match field("args")? {
+                (Json::Array(items), _) => items.iter().map(|(item, span)| (item, *span)).collect::<Vec<_>>(),
+                (other, span) => return error(format!("expected an array of arguments, found {}", other.kind()), span),
+            };
+            let holes = args.iter().map(|_| Expression::Bool(false)).collect();
+            children.extend(args);
+            Expression::Call { callee, args: holes }
+        }
+        "var" => Expression::Var(string("var")?),
+        "value" => match field("value")? {
+            (Json::Number(text) | Json::String(text), span) => Expression::Value(number(text, span)?),
+            (other, span) => return error(format!("expected a number, found {}", other.kind()), span),
+        },
+        "bool" => match field("bool")? {
+            (Json::Bool(b), _) => Expression::Bool(*b),
+            (other, span) => return error(format!("expected a boolean, found {}", other.kind()), span),
+        },
+        _ => {
+            let (offsets, value_span) = match field("span")? {
+                (Json::Array(items), span) => (items, span),
+                (other, span) => return error(format!("expected [start, end], found {}", other.kind()), span),
+            };
+            let offset = |(item, span): &(Json, Span)| match item {
+                Json::Number(text) => text.parse::<usize>().or_else(|_| error("expected an offset", *span)),
+                _ => error("expected an offset", *span),
+            };
+            let [start, end] = &offsets[..] else {
+                return error("expected [start, end]", value_span);
+            };
+            let (start, end) = (offset(start)?, offset(end)?);
+            if start > end {
+                return error("a span cannot end before it starts", value_span);
+            }
+            Expression::Spanned { span: Span::new(start, end), inner: expr("inner")? }
+        }
+    };
+    Ok((e, children))
+}
+
+#[cfg(test)]
+fn round_trip<N: Display + FromStr + PartialEq + std::fmt::Debug>(e: &Expression<N>) {
+    let sexpr = to_sexpr(e);
+    assert_eq!(parse_sexpr::<N>(&sexpr).as_ref(), Ok(e), "{sexpr}");
+    let json = to_json(e);
+    assert_eq!(parse_json::<N>(&json).as_ref(), Ok(e), "{json}");
+}
+
+#[test]
+fn test_sexpr() {
+    let e = crate::parser::parse("10 * 9 + (3 - 4) * 5").unwrap();
+    assert_eq!(to_sexpr(&e), "(+ (* 10 9) (* (- 3 4) 5))");
+    let e = crate::parser::parse("let f = fn(x, y) if x < y then -x else f(y, x) in !(f(1, -2) == 3) || true").unwrap();
+    assert_eq!(
+        to_sexpr(&e),
+        "(let f (fn (x y) (if (< x y) (- x) (call f y x))) (|| (! (== (call f 1 -2) 3)) true))"
+    );
+    round_trip(&e);
+    let odd = Expression::Let {
+        name: String::from("a \"b\""),
+        value: Box::new(Expression::Var(String::from("true"))),
+        body: Box::new(Expression::Function { params: vec![String::from("if"), String::new()], body: Box::new(Expression::Value(-7)) }),
+    };
+    assert_eq!(to_sexpr(&odd), r#"(let "a \"b\"" (var "true") (fn (if "") -7))"#);
+    round_trip(&odd);
+    let floats = Expression::Op { op: Operation::Div, left: Box::new(Expression::Value(f64::NAN)), right: Box::new(Expression::Value(-0.5)) };
+    assert_eq!(to_sexpr(&floats), r#"(/ (num "NaN") -0.5)"#);
+    assert!(matches!(parse_sexpr::<f64>(&to_sexpr(&floats)), Ok(Expression::Op { ref left, .. }) if matches!(**left, Expression::Value(v) if v.is_nan())));
+    round_trip(&Expression::Value(&crate::bigint::BigInt::from(i128::MIN) * &crate::bigint::BigInt::from(i128::MAX)));
+    assert_eq!(parse_sexpr::<i64>(" ( +\n1\t(var \"x\") ) "), Ok(Expression::Op {
+        op: Operation::Add,
+        left: Box::new(Expression::Value(1)),
+        right: Box::new(Expression::Var(String::from("x"))),
+    }));
+}
+
+#[test]
+fn test_json() {
+    let e = crate::parser::parse("let x = 9007199254740993 in (fn(a) a % x)(-1)").unwrap();
+    assert_eq!(
+        to_json(&e),
+        r#"{"let":"x","value":{"value":9007199254740993},"body":{"call":{"fn":["a"],"body":{"op":"%","left":{"var":"a"},"right":{"var":"x"}}},"args":[{"value":-1}]}}"#
+    );
+    round_trip(&e);
+    let reordered = r#" { "right" : {"bool": false}, "left": {"value": "12"}, "op": "&&" } "#;
+    assert_eq!(parse_json::<i64>(reordered), Ok(Expression::Op {
+        op: Operation::And,
+        left: Box::new(Expression::Value(12)),
+        right: Box::new(Expression::Bool(false)),
+    }));
+    assert_eq!(parse_json::<i64>(r#"{"var":"\u00e9\ud83d\ude00\n"}"#), Ok(Expression::Var(String::from("é😀\n"))));
+    round_trip(&Expression::<i64>::Var(String::from("tab\there \"quoted\" \\ \u{1}")));
+    round_trip(&Expression::Value(f64::INFINITY));
+}
+
+#[test]
+fn test_round_trip_every_tree() {
+    use crate::fuzz::{Generator, Grammar};
+    let mut generator = Generator::new(7).with_grammar(Grammar::Full);
+    for _ in 0..2000 {
+        round_trip(&generator.generate());
+    }
+    let spanned = crate::parser::parse_spanned("if 1 < 2 then (fn(x) x)(3) else let y = 4 in -y").unwrap();
+    assert!(to_sexpr(&spanned).starts_with("(span 0 "));
+    round_trip(&spanned);
+}
+
+#[test]
+fn test_malformed() {
+    let sexpr = |src| parse_sexpr::<i64>(src).unwrap_err().to_string();
+    assert_eq!(sexpr(""), "unexpected end of input at offset 0");
+    assert_eq!(sexpr("(+ 1 2"), "expected ')' at offset 6");
+    assert_eq!(sexpr("(+ 1 2))"), "expected end of input at offset 7");
+    assert_eq!(sexpr("(+ 1 2 3)"), "'+' takes 2 arguments, found 3 at offset 0");
+    assert_eq!(sexpr("(- 1 2 3)"), "'-' takes 1 or 2 arguments, found 3 at offset 0");
+    assert_eq!(sexpr("(^ 1 2)"), "unknown operator or form '^' at offset 1");
+    assert_eq!(sexpr("(if 1 2)"), "'if' takes 3 arguments, found 2 at offset 0");
+    assert_eq!(sexpr("(let 1 2 3)"), "expected a name at offset 5");
+    assert_eq!(sexpr("(fn x x)"), "expected a parameter list at offset 4");
+    assert_eq!(sexpr("(* 2 12x)"), "invalid number '12x' at offset 5");
+    assert_eq!(sexpr("(var \"x)"), "unterminated string at offset 5");
+    assert_eq!(sexpr("(var \"\\q\")"), "invalid escape in string at offset 6");
+    assert_eq!(sexpr("()"), "expected an operator or form name at offset 0");
+    assert_eq!(sexpr("(span 5 2 x)"), "a span cannot end before it starts at offset 8");
+    assert_eq!(sexpr("a.b"), "unexpected 'a.b' at offset 0");
+
+    let json = |src| parse_json::<i64>(src).unwrap_err().to_string();
+    assert_eq!(json(r#"{"value":1"#), "expected ',' or '}' at offset 10");
+    assert_eq!(json(r#"{"value":1} x"#), "expected end of input at offset 12");
+    assert_eq!(json(r#"[1]"#), "expected an object, found an array at offset 0");
+    assert_eq!(json(r#"{"op":"+","left":{"value":1}}"#), "missing key 'right' alongside 'op' at offset 0");
+    assert_eq!(json(r#"{"var":"x","value":1}"#), "unexpected key 'value' alongside 'var' at offset 19");
+    assert_eq!(json(r#"{"var":"x","var":"y"}"#), "duplicate key 'var' at offset 11");
+    assert_eq!(json(r#"{"op":"^","left":{"value":1},"right":{"value":2}}"#), "unknown operator '^' at offset 6");
+    assert_eq!(json(r#"{"value":01}"#), "invalid number '01' at offset 9");
+    assert_eq!(json(r#"{"value":1.5}"#), "invalid number '1.5' at offset 9");
+    assert_eq!(json(r#"{"bool":nul}"#), "unexpected 'nul' at offset 8");
+    assert_eq!(json(r#"{"fn":["a",1],"body":{"var":"a"}}"#), "expected a parameter name, found a number at offset 11");
+    assert_eq!(json(r#"{"span":[1],"inner":{"value":1}}"#), "expected [start, end] at offset 8");
+    assert_eq!(json("{\"var\":\"a\nb\"}"), "control character in string at offset 9");
+}
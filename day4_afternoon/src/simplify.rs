@@ -0,0 +1,204 @@
+use crate::expression::{apply, apply_unary, truth, Expression, Operation, UnaryOperation};
+use crate::number::Number;
+
+/// Folds constant subtrees and removes identity operations such as `x + 0` and `x * 1`.
+///
+/// Subtrees that would fail to evaluate are kept as they are, so simplifying never hides an error. That includes
+/// the error from using a function as a number, so identities are only removed around operands that are certainly
+/// numbers: `x * 1` is simplified for a free `x`, which is unbound if it is not a number, but not for a function
+/// parameter `x`, which might be passed a function.
+pub fn simplify<N: Number>(e: &Expression<N>) -> Expression<N> {
+    // Runs on a heap stack of tasks rather than recursing, so deep trees can't overflow the stack. `scope` holds the
+    // variables bound around the expression being simplified, innermost last, and whether each is certainly a number.
+    enum Task<'a, N> {
+        Simplify(&'a Expression<N>),
+        Op(Operation),
+        Unary(UnaryOperation),
+        // picks a branch if the simplified condition, the last result, is constant
+        Branch { then: &'a Expression<N>, otherwise: &'a Expression<N> },
+        If,
+        // brings the simplified value, the last result, into scope for the body
+        Bind(&'a str),
+        Let(&'a str),
+        Function(&'a [String]),
+        Call(usize),
+    }
+    let mut tasks = vec![Task::Simplify(e)];
+    let mut results: Vec<Expression<N>> = Vec::new();
+    let mut scope: Vec<(&str, bool)> = Vec::new();
+    let pop = |results: &mut Vec<Expression<N>>| Box::new(results.pop().expect("each task leaves a result"));
+    while let Some(task) = tasks.pop() {
+        match task {
+            Task::Simplify(e) => match e {
+                Expression::Op { op, left, right } => tasks.extend([Task::Op(*op), Task::Simplify(right), Task::Simplify(left)]),
+                Expression::Unary { op, operand } => tasks.extend([Task::Unary(*op), Task::Simplify(operand)]),
+                Expression::If { cond, then, otherwise } => tasks.extend([Task::Branch { then, otherwise }, Task::Simplify(cond)]),
+                Expression::Let { name, value, body } => {
+                    tasks.extend([Task::Let(name), Task::Simplify(body), Task::Bind(name), Task::Simplify(value)]);
+                }
+                Expression::Function { params, body } => {
+                    scope.extend(params.iter().map(|param| (param.as_str(), false)));
+                    tasks.extend([Task::Function(params), Task::Simplify(body)]);
+                }
+                Expression::Call { callee, args } => {
+                    tasks.push(Task::Call(args.len()));
+                    tasks.extend(args.iter().rev().map(Task::Simplify));
+                    tasks.push(Task::Simplify(callee));
+                }
+                Expression::Var(name) => results.push(Expression::Var(name.clone())),
+                Expression::Value(v) => results.push(Expression::Value(v.clone())),
+                Expression::Bool(b) => results.push(Expression::Bool(*b)),
+                // spans describe the original text, which a simplified tree no longer matches
+                Expression::Spanned { inner, .. } => tasks.push(Task::Simplify(inner)),
+            },
+            Task::Op(op) => {
+                let right = pop(&mut results);
+                let left = pop(&mut results);
+                results.push(operation(op, *left, *right, &scope));
+            }
+            Task::Unary(op) => {
+                let operand = pop(&mut results);
+                results.push(match constant(&operand).map(|v| apply_unary(op, v)) {
+                    Some(Ok(v)) => result(v, op == UnaryOperation::Not),
+                    _ => Expression::Unary { op, operand },
+                });
+            }
+            Task::Branch { then, otherwise } => {
+                let cond = results.last().expect("the condition was simplified");
+                match constant(cond) {
+                    Some(c) => {
+                        results.pop();
+                        tasks.push(Task::Simplify(if truth(&c) { then } else { otherwise }));
+                    }
+                    None => tasks.extend([Task::If, Task::Simplify(otherwise), Task::Simplify(then)]),
+                }
+            }
+            Task::If => {
+                let otherwise = pop(&mut results);
+                let then = pop(&mut results);
+                let cond = pop(&mut results);
+                results.push(Expression::If { cond, then, otherwise });
+            }
+            Task::Bind(name) => {
+                let number = is_number(results.last().expect("the value was simplified"), &scope);
+                scope.push((name, number));
+            }
+            Task::Let(name) => {
+                scope.pop();
+                let body = pop(&mut results);
+                let value = pop(&mut results);
+                results.push(Expression::Let { name: name.to_string(), value, body });
+            }
+            Task::Function(params) => {
+                scope.truncate(scope.len() - params.len());
+                let body = pop(&mut results);
+                results.push(Expression::Function { params: params.to_vec(), body });
+            }
+            Task::Call(count) => {
+                let args = results.split_off(results.len() - count);
+                let callee = pop(&mut results);
+                results.push(Expression::Call { callee, args });
+            }
+        }
+    }
+    results.pop().expect("the root is simplified last")
+}
+
+// An operation on operands that have been simplified already.
+fn operation<N: Number>(op: Operation, left: Expression<N>, right: Expression<N>, scope: &[(&str, bool)]) -> Expression<N> {
+    match (op, constant(&left), constant(&right)) {
+        (op, Some(l), Some(r)) => match apply(op, l, r) {
+            Ok(v) => return result(v, op.is_boolean()),
+            Err(_) => return Expression::Op { op, left: Box::new(left), right: Box::new(right) },
+        },
+        // the right side is never evaluated, so dropping it keeps any error it has unobserved
+        (Operation::And, Some(l), None) if !truth(&l) => return Expression::Bool(false),
+        (Operation::Or, Some(l), None) if truth(&l) => return Expression::Bool(true),
+        _ => {}
+    }
+    let is = |e: &Expression<N>, n: N| matches!(e, Expression::Value(v) if *v == n);
+    match op {
+        Operation::Add if is(&left, N::zero()) && is_number(&right, scope) => right,
+        Operation::Mul if is(&left, N::one()) && is_number(&right, scope) => right,
+        Operation::Add | Operation::Sub if is(&right, N::zero()) && is_number(&left, scope) => left,
+        Operation::Mul | Operation::Div | Operation::Pow if is(&right, N::one()) && is_number(&left, scope) => left,
+        op => Expression::Op { op, left: Box::new(left), right: Box::new(right) },
+    }
+}
+
+// Whether `e` evaluates to a number if it evaluates at all, in a scope where a free variable is always unbound.
+fn is_number<N>(e: &Expression<N>, scope: &[(&str, bool)]) -> bool {
+    match e {
+        Expression::Op { .. } | Expression::Unary { .. } | Expression::Value(_) | Expression::Bool(_) => true,
+        Expression::Var(name) => scope.iter().rev().find(|(n, _)| n == name).is_none_or(|&(_, number)| number),
+        Expression::If { .. } | Expression::Let { .. } | Expression::Function { .. } | Expression::Call { .. } => false,
+        Expression::Spanned { inner, .. } => is_number(inner, scope),
+    }
+}
+
+fn constant<N: Number>(e: &Expression<N>) -> Option<N> {
+    match e {
+        Expression::Value(v) => v.clone().check().ok(),
+        Expression::Bool(b) => Some(N::from_bool(*b)),
+        _ => None,
+    }
+}
+
+fn result<N: Number>(v: N, boolean: bool) -> Expression<N> {
+    if boolean {
+        Expression::Bool(truth(&v))
+    } else {
+        Expression::Value(v)
+    }
+}
+
+#[test]
+fn test_simplify_folds_constants() {
+    let expr = crate::parser::parse("10 * 9 + (3 - 4) * 5").unwrap();
+    assert_eq!(simplify(&expr), Expression::Value(85));
+    let expr = crate::parser::parse("2 ** 3 > 7 && !false").unwrap();
+    assert_eq!(simplify(&expr), Expression::Bool(true));
+}
+
+#[test]
+fn test_simplify_keeps_errors() {
+    let expr = crate::parser::parse("(2 + 3) / (1 - 1) * 1").unwrap();
+    assert_eq!(simplify(&expr).to_string(), "5 / 0");
+    let expr = crate::parser::parse("if 1 == 1 then -(2 ** -1) else 1 / 0").unwrap();
+    assert_eq!(simplify(&expr).to_string(), "-2 ** -1");
+    let expr = crate::parser::parse("false && 1 / 0 == 0").unwrap();
+    assert_eq!(simplify(&expr), Expression::Bool(false));
+}
+
+#[test]
+fn test_simplify_rationals() {
+    let expr = crate::parser::parse("1 / 3 + 1 / 6").unwrap().map_values(&|v| crate::number::Rational::integer(*v));
+    assert_eq!(simplify(&expr), Expression::Value(crate::number::Rational::new(1, 2).unwrap()));
+}
+
+#[test]
+fn test_simplify_inside_functions() {
+    let expr = crate::parser::parse("let f = fn(x) x * (2 - 1) + 0 in f(3 * 4)").unwrap();
+    assert_eq!(simplify(&expr).to_string(), "let f = fn(x) x * 1 in f(12)");
+    let expr = crate::parser::parse("let y = 2 * 3 in let f = fn(x) x in y * 1 + (f - 0)").unwrap();
+    assert_eq!(simplify(&expr).to_string(), "let y = 6 in let f = fn(x) x in y + (f - 0)");
+}
+
+#[test]
+fn test_simplify_deep_trees() {
+    let chain = crate::expression::left_chain(200_000);
+    assert_eq!(simplify(&chain), Expression::Value(crate::expression::eval(&chain).unwrap()));
+    let src = format!("x{}", " + 1".repeat(200_000));
+    let simplified = simplify(&crate::parser::parse(&src).unwrap());
+    assert_eq!(simplified.depth(), 200_001);
+    let mut e = crate::parser::parse("x").unwrap();
+    for i in 0..200_000 {
+        e = match i % 3 {
+            0 => Expression::Let { name: String::from("y"), value: Box::new(Expression::Value(2)), body: Box::new(e) },
+            1 => Expression::If { cond: Box::new(Expression::Bool(true)), then: Box::new(e), otherwise: Box::new(Expression::Value(0)) },
+            _ => Expression::Op { op: Operation::Mul, left: Box::new(e), right: Box::new(Expression::Value(1)) },
+        };
+    }
+    // every `if` goes, and every `* 1` stays since its operand is a `let`, which might not be a number
+    assert_eq!(simplify(&e).depth(), 1 + 66_666 * 2 + 1);
+}
@@ -0,0 +1,419 @@
+use std::collections::BTreeSet;
+use std::fmt::{self, Write};
+
+use crate::diagnostic::json_string;
+use crate::expression::{apply, apply_unary, truth, EvalError, Expression, Operation, UnaryOperation};
+use crate::number::Number;
+
+/// How many steps `trace` takes before giving up, since a recursive function may never stop.
+pub const DEFAULT_MAX_STEPS: usize = 10_000;
+
+/// How deep an expression `trace` will follow. Finding each step and substituting recurse once per level, and every
+/// step copies the whole expression anyway.
+pub const MAX_DEPTH: usize = 200;
+
+/// One reduction: the subexpression at `path` was replaced by `after`, giving `expression`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step<N> {
+    /// Child indices from the root to the reduced subexpression, in the order `Layer` lists children.
+    pub path: Vec<usize>,
+    pub before: Expression<N>,
+    pub after: Expression<N>,
+    /// The whole expression after this step.
+    pub expression: Expression<N>,
+}
+
+/// How a trace ended.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome<N> {
+    Value(N),
+    /// Reducing the subexpression at `path` failed.
+    Error { path: Vec<usize>, redex: Expression<N>, error: EvalError },
+    /// The step limit was reached first.
+    Stopped,
+    /// The expression was, or grew, deeper than `MAX_DEPTH`, so tracing stopped there.
+    TooDeep(usize),
+}
+
+/// Every step of evaluating an expression, from `trace`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trace<N> {
+    pub start: Expression<N>,
+    pub steps: Vec<Step<N>>,
+    pub outcome: Outcome<N>,
+}
+
+impl<N: Number> Trace<N> {
+    /// The result, as `evaluate` would return it, or `None` if the trace was stopped.
+    pub fn result(&self) -> Option<Result<N, EvalError>> {
+        match &self.outcome {
+            Outcome::Value(v) => Some(Ok(v.clone())),
+            Outcome::Error { error, .. } => Some(Err(error.clone())),
+            Outcome::Stopped | Outcome::TooDeep(_) => None,
+        }
+    }
+
+    /// The trace as one JSON object:
+    ///
+    /// ```text
+    /// {"start":"(3 - 4) * 5","steps":[{"path":[0],"before":"3 - 4","after":"-1","expression":"-1 * 5"},...],
+    ///  "value":"-5"}
+    /// ```
+    ///
+    /// with `"value"` replaced by `"error":{"message":...,"path":[...],"redex":...}`, `"stopped":true` or
+    /// `"too_deep":depth`.
+    /// Expressions and values are strings in calculator syntax, so no precision is lost.
+    pub fn to_json(&self) -> String {
+        let path = |path: &[usize]| format!("[{}]", path.iter().map(usize::to_string).collect::<Vec<_>>().join(","));
+        let mut out = format!("{{\"start\":{},\"steps\":[", json_string(&self.start.to_string()));
+        for (i, step) in self.steps.iter().enumerate() {
+            let _ = write!(
+                out,
+                "{}{{\"path\":{},\"before\":{},\"after\":{},\"expression\":{}}}",
+                if i == 0 { "" } else { "," },
+                path(&step.path),
+                json_string(&step.before.to_string()),
+                json_string(&step.after.to_string()),
+                json_string(&step.expression.to_string()),
+            );
+        }
+        let _ = match &self.outcome {
+            Outcome::Value(v) => write!(out, "],\"value\":{}}}", json_string(&v.to_string())),
+            Outcome::Error { path: p, redex, error } => write!(
+                out,
+                "],\"error\":{{\"message\":{},\"path\":{},\"redex\":{}}}}}",
+                json_string(&error.to_string()),
+                path(p),
+                json_string(&redex.to_string()),
+            ),
+            Outcome::Stopped => write!(out, "],\"stopped\":true}}"),
+            Outcome::TooDeep(depth) => write!(out, "],\"too_deep\":{depth}}}"),
+        };
+        out
+    }
+}
+
+/// One expression per line, each step prefixed with an arrow, then how it ended if not with a value:
+///
+/// ```text
+/// 1 + 2 / (3 - 3)
+/// → 1 + 2 / 0
+/// error: division by zero in 2 / 0
+/// ```
+impl<N: Number> fmt::Display for Trace<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.start)?;
+        for step in &self.steps {
+            write!(f, "\n→ {}", step.expression)?;
+        }
+        match &self.outcome {
+            Outcome::Value(_) => Ok(()),
+            Outcome::Error { redex, error, .. } => write!(f, "\nerror: {error} in {redex}"),
+            Outcome::Stopped => write!(f, "\nstopped after {} steps", self.steps.len()),
+            Outcome::TooDeep(depth) => write!(f, "\nstopped at {depth} levels deep, past the limit of {MAX_DEPTH}"),
+        }
+    }
+}
+
+/// `trace_with_step_limit` with `DEFAULT_MAX_STEPS`.
+pub fn trace<N: Number>(e: &Expression<N>) -> Trace<N> {
+    trace_with_step_limit(e, DEFAULT_MAX_STEPS)
+}
+
+/// Evaluates `e` by rewriting it one reduction at a time, recording every intermediate expression.
+///
+/// Operands are reduced left to right, innermost first, in the same order `evaluate` computes them, so the trace
+/// ends with the same value or error. Variables are replaced by their values when they are bound, renaming inner
+/// bindings where a name would otherwise be captured, and a recursive function is replaced by a copy that refers to
+/// itself through a `let`. Unlike `evaluate`, the trace has no call depth limit: it stops after `max_steps`
+/// steps instead, or once the expression is more than `MAX_DEPTH` levels deep. Spans are dropped before the first
+/// step.
+///
+/// Every step copies the whole expression, so this is meant for formulas a person will read.
+pub fn trace_with_step_limit<N: Number>(e: &Expression<N>, max_steps: usize) -> Trace<N> {
+    let start = e.clone().transform_up(|e| match e {
+        Expression::Spanned { .. } => e.into_children().pop().expect("a span wraps an expression"),
+        e => e,
+    });
+    let mut current = start.clone();
+    let mut steps = Vec::new();
+    let outcome = loop {
+        let depth = current.depth();
+        if depth > MAX_DEPTH {
+            break Outcome::TooDeep(depth);
+        }
+        let mut path = Vec::new();
+        let reduced = match reduce(&current, &mut path) {
+            Some(reduced) => reduced,
+            None => match number(&current) {
+                Ok(v) => break Outcome::Value(v),
+                Err(error) => break Outcome::Error { path, redex: current, error },
+            },
+        };
+        let before = at(&mut current, &path);
+        let after = match reduced {
+            Ok(after) => after,
+            Err(error) => break Outcome::Error { redex: before.clone(), path, error },
+        };
+        if steps.len() == max_steps {
+            break Outcome::Stopped;
+        }
+        let before = std::mem::replace(before, after.clone());
+        steps.push(Step { path, before, after, expression: current.clone() });
+    };
+    Trace { start, steps, outcome }
+}
+
+fn at<'e, N>(mut e: &'e mut Expression<N>, path: &[usize]) -> &'e mut Expression<N> {
+    for &i in path {
+        e = e.children_mut().swap_remove(i);
+    }
+    e
+}
+
+fn is_value<N: Number>(e: &Expression<N>) -> bool {
+    match e {
+        Expression::Value(v) => v.clone().check().is_ok(),
+        Expression::Bool(_) | Expression::Function { .. } => true,
+        _ => false,
+    }
+}
+
+// The number a value stands for, as `evaluate` would treat it.
+fn number<N: Number>(e: &Expression<N>) -> Result<N, EvalError> {
+    match e {
+        Expression::Value(v) => v.clone().check(),
+        Expression::Bool(b) => Ok(N::from_bool(*b)),
+        _ => Err(EvalError::ExpectedNumber),
+    }
+}
+
+// Comparisons and boolean operators reduce to `true` or `false`, so traces read like the formula.
+fn literal<N: Number>(v: N, boolean: bool) -> Expression<N> {
+    if boolean { Expression::Bool(truth(&v)) } else { Expression::Value(v) }
+}
+
+/// Finds the next subexpression to reduce, extending `path` to it, and returns what it reduces to. `None` if `e` is
+/// a value already.
+fn reduce<N: Number>(e: &Expression<N>, path: &mut Vec<usize>) -> Option<Result<Expression<N>, EvalError>> {
+    if is_value(e) {
+        return None;
+    }
+    // the first of these children that is not a value yet is reduced before anything else
+    fn first<N: Number>(children: &[&Expression<N>], path: &mut Vec<usize>) -> Option<Result<Expression<N>, EvalError>> {
+        let i = children.iter().position(|child| !is_value(child))?;
+        path.push(i);
+        reduce(children[i], path)
+    }
+    Some(match e {
+        Expression::Op { op: op @ (Operation::And | Operation::Or), left, right } => {
+            if let Some(reduced) = first(&[left], path) {
+                return Some(reduced);
+            }
+            let truth_of_left = match number(left) {
+                Ok(left) => truth(&left),
+                Err(error) => return Some(Err(error)),
+            };
+            if truth_of_left == (*op == Operation::Or) {
+                return Some(Ok(Expression::Bool(truth_of_left)));
+            }
+            if let Some(reduced) = first(&[left, right], path) {
+                return Some(reduced);
+            }
+            number(right).map(|right| Expression::Bool(truth(&right)))
+        }
+        Expression::Op { op, left, right } => {
+            if let Some(reduced) = first(&[left, right], path) {
+                return Some(reduced);
+            }
+            number(right).and_then(|right| apply(*op, number(left)?, right)).map(|v| literal(v, op.is_boolean()))
+        }
+        Expression::Unary { op, operand } => {
+            if let Some(reduced) = first(&[operand], path) {
+                return Some(reduced);
+            }
+            number(operand).and_then(|v| apply_unary(*op, v)).map(|v| literal(v, *op == UnaryOperation::Not))
+        }
+        Expression::If { cond, then, otherwise } => {
+            if let Some(reduced) = first(&[cond], path) {
+                return Some(reduced);
+            }
+            number(cond).map(|cond| if truth(&cond) { (**then).clone() } else { (**otherwise).clone() })
+        }
+        // A function bound by `let` can call itself by name: inside it, the name stands for the same `let`.
+        Expression::Let { name, value, body } if matches!(**value, Expression::Function { .. }) => {
+            let function = if value.free_variables().contains(name.as_str()) {
+                let itself = Expression::Let { name: name.clone(), value: value.clone(), body: Box::new(Expression::Var(name.clone())) };
+                substitute(value, &[(name, &itself)])
+            } else {
+                (**value).clone()
+            };
+            Ok(substitute(body, &[(name, &function)]))
+        }
+        Expression::Let { name, value, body } => {
+            if let Some(reduced) = first(&[value], path) {
+                return Some(reduced);
+            }
+            Ok(substitute(body, &[(name, value)]))
+        }
+        Expression::Call { callee, args } => {
+            let children: Vec<&Expression<N>> = std::iter::once(&**callee).chain(args).collect();
+            if let Some(reduced) = first(&children, path) {
+                return Some(reduced);
+            }
+            match &**callee {
+                Expression::Function { params, .. } if params.len() != args.len() => {
+                    Err(EvalError::ArityMismatch { expected: params.len(), found: args.len() })
+                }
+                Expression::Function { params, body } => {
+                    let bindings: Vec<_> = params.iter().map(String::as_str).zip(args).collect();
+                    Ok(substitute(body, &bindings))
+                }
+                _ => Err(EvalError::NotAFunction),
+            }
+        }
+        Expression::Var(name) => Err(EvalError::UnboundVariable(name.clone())),
+        Expression::Value(v) => v.clone().check().map(Expression::Value),
+        Expression::Function { .. } | Expression::Bool(_) | Expression::Spanned { .. } => {
+            unreachable!("values are never reduced, and spans are removed before tracing")
+        }
+    })
+}
+
+/// Replaces the free occurrences of each name in `e` by its expression, the last binding of a name winning.
+fn substitute<N: Number>(e: &Expression<N>, bindings: &[(&str, &Expression<N>)]) -> Expression<N> {
+    let bound = |name: &str| bindings.iter().rev().find(|(n, _)| *n == name).map(|(_, v)| *v);
+    match e {
+        Expression::Var(name) => bound(name).cloned().unwrap_or_else(|| e.clone()),
+        Expression::Let { name, value, body } => {
+            let value = Box::new(substitute(value, bindings));
+            let (names, body) = under_binders(std::slice::from_ref(name), body, bindings);
+            Expression::Let { name: names.into_iter().next().expect("one name"), value, body: Box::new(body) }
+        }
+        Expression::Function { params, body } => {
+            let (params, body) = under_binders(params, body, bindings);
+            Expression::Function { params, body: Box::new(body) }
+        }
+        _ => {
+            let mut e = e.clone();
+            for child in e.children_mut() {
+                *child = substitute(child, bindings);
+            }
+            e
+        }
+    }
+}
+
+// Substitutes into `body`, which is in the scope of `names`. Those names hide any bindings of the same name, and
+// are renamed where they would capture a free variable of a substituted expression.
+fn under_binders<N: Number>(
+    names: &[String],
+    body: &Expression<N>,
+    bindings: &[(&str, &Expression<N>)],
+) -> (Vec<String>, Expression<N>) {
+    let visible: Vec<_> = bindings.iter().filter(|(n, _)| !names.iter().any(|name| name == n)).copied().collect();
+    if visible.is_empty() {
+        return (names.to_vec(), body.clone());
+    }
+    let mut taken: BTreeSet<String> = body.free_variables().into_iter().map(String::from).collect();
+    for (_, v) in &visible {
+        taken.extend(v.free_variables().into_iter().map(String::from));
+    }
+    let captured = |name: &String| visible.iter().any(|(_, v)| v.free_variables().contains(name.as_str()));
+    let mut renamed = Vec::new();
+    let mut new_names = Vec::new();
+    for name in names {
+        if !captured(name) {
+            new_names.push(name.clone());
+            continue;
+        }
+        let mut n = 2;
+        while taken.contains(&format!("{name}_{n}")) || names.contains(&format!("{name}_{n}")) {
+            n += 1;
+        }
+        let fresh = format!("{name}_{n}");
+        taken.insert(fresh.clone());
+        renamed.push((name.as_str(), Expression::Var(fresh.clone())));
+        new_names.push(fresh);
+    }
+    let body = if renamed.is_empty() {
+        body.clone()
+    } else {
+        substitute(body, &renamed.iter().map(|(n, v)| (*n, v)).collect::<Vec<_>>())
+    };
+    (new_names, substitute(&body, &visible))
+}
+
+#[cfg(test)]
+fn steps(src: &str) -> String {
+    trace(&crate::parser::parse(src).unwrap()).to_string()
+}
+
+#[test]
+fn test_trace_arithmetic() {
+    assert_eq!(steps("(3 - 4) * 5"), "(3 - 4) * 5\n→ -1 * 5\n→ -5");
+    assert_eq!(steps("1 + 2 / (3 - 3)"), "1 + 2 / (3 - 3)\n→ 1 + 2 / 0\nerror: division by zero in 2 / 0");
+    assert_eq!(
+        steps("if 2 > 1 && !false then -2 ** 3 else 1 / 0"),
+        "if 2 > 1 && !false then -2 ** 3 else 1 / 0\n\
+         → if true && !false then -2 ** 3 else 1 / 0\n\
+         → if true && true then -2 ** 3 else 1 / 0\n\
+         → if true then -2 ** 3 else 1 / 0\n\
+         → -2 ** 3\n\
+         → -(8)\n\
+         → -8"
+    );
+    assert_eq!(steps("7"), "7");
+}
+
+#[test]
+fn test_trace_bindings() {
+    assert_eq!(steps("let x = 2 + 1 in x * x"), "let x = 2 + 1 in x * x\n→ let x = 3 in x * x\n→ 3 * 3\n→ 9");
+    assert_eq!(steps("(fn(a, b) a - b)(4, 1)"), "(fn(a, b) a - b)(4, 1)\n→ 4 - 1\n→ 3");
+    // the function's `y` is the outer one, so the parameter `y` is renamed rather than capture it
+    assert_eq!(
+        steps("(fn(y) let f = fn(x) x + y in (fn(y) f(y))(10))(1)"),
+        "(fn(y) let f = fn(x) x + y in (fn(y) f(y))(10))(1)\n\
+         → let f = fn(x) x + 1 in (fn(y) f(y))(10)\n\
+         → (fn(y) (fn(x) x + 1)(y))(10)\n\
+         → (fn(x) x + 1)(10)\n\
+         → 10 + 1\n\
+         → 11"
+    );
+    let fact = trace(&crate::parser::parse("let fact = fn(n) if n < 1 then 1 else n * fact(n - 1) in fact(5)").unwrap());
+    assert_eq!(fact.result(), Some(Ok(120)));
+    assert_eq!(fact.steps[0].expression.to_string(), "(fn(n) if n < 1 then 1 else n * (let fact = fn(n) if n < 1 then 1 else n * fact(n - 1) in fact)(n - 1))(5)");
+    assert_eq!(steps("(fn(a) a)(1, 2)"), "(fn(a) a)(1, 2)\nerror: function takes 1 argument(s) but 2 were given in (fn(a) a)(1, 2)");
+    let forever = trace_with_step_limit(&crate::parser::parse("let f = fn(x) f(x) in f(1)").unwrap(), 50);
+    assert_eq!((forever.steps.len(), forever.outcome), (50, Outcome::Stopped));
+}
+
+#[test]
+fn test_trace_deep_expressions() {
+    let chain = crate::expression::left_chain(200_000);
+    let traced = trace(&chain);
+    assert_eq!((traced.steps.len(), &traced.outcome), (0, &Outcome::TooDeep(200_001)));
+    assert!(traced.to_string().ends_with("\nstopped at 200001 levels deep, past the limit of 200"));
+    assert!(trace(&crate::parser::parse_spanned(&chain.to_string()).unwrap()).to_json().ends_with("],\"too_deep\":200001}"));
+    // as deep as allowed, with a substitution all the way down
+    let src = format!("let x = 1 in {}x{}", "-(".repeat(MAX_DEPTH - 2), ")".repeat(MAX_DEPTH - 2));
+    let traced = trace(&crate::parser::parse(&src).unwrap());
+    assert_eq!((traced.steps.len(), traced.result()), (MAX_DEPTH - 1, Some(Ok(1))));
+    // a recursion that deepens the expression with every call
+    let deepening = trace(&crate::parser::parse("let f = fn(n) if n < 1 then 0 else 1 + f(n - 1) in f(100000)").unwrap());
+    assert_eq!(deepening.outcome, Outcome::TooDeep(MAX_DEPTH + 1));
+}
+
+#[test]
+fn test_trace_json() {
+    let json = trace(&crate::parser::parse("(3 - 4) * 5").unwrap()).to_json();
+    assert_eq!(
+        json,
+        r#"{"start":"(3 - 4) * 5","steps":[{"path":[0],"before":"3 - 4","after":"-1","expression":"-1 * 5"},{"path":[],"before":"-1 * 5","after":"-5","expression":"-5"}],"value":"-5"}"#
+    );
+    let json = trace(&crate::parser::parse_spanned("x + 1").unwrap()).to_json();
+    assert_eq!(
+        json,
+        r#"{"start":"x + 1","steps":[],"error":{"message":"unbound variable 'x'","path":[0],"redex":"x"}}"#
+    );
+}
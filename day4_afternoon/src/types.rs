@@ -0,0 +1,540 @@
+use std::fmt;
+
+use crate::diagnostic::{Diagnostic, Span};
+use crate::expression::{Expression, Operation, UnaryOperation};
+
+/// The static type of an expression. Unlike the evaluator, the checker keeps numbers and booleans apart.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Type {
+    Number,
+    Bool,
+    Function(Vec<Type>, Box<Type>),
+    /// A type that is not known yet, or that could be anything, such as the parameter of `fn(x) x`.
+    Var(usize),
+}
+
+impl Type {
+    fn write(&self, f: &mut fmt::Formatter, names: &mut Vec<usize>) -> fmt::Result {
+        match self {
+            Type::Number => write!(f, "number"),
+            Type::Bool => write!(f, "bool"),
+            Type::Function(params, result) => {
+                write!(f, "fn(")?;
+                for (i, param) in params.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    param.write(f, names)?;
+                }
+                write!(f, ") -> ")?;
+                result.write(f, names)
+            }
+            Type::Var(id) => {
+                let index = names.iter().position(|n| n == id).unwrap_or_else(|| {
+                    names.push(*id);
+                    names.len() - 1
+                });
+                match u8::try_from(index) {
+                    Ok(i) if i < 26 => write!(f, "'{}", (b'a' + i) as char),
+                    _ => write!(f, "'t{index}"),
+                }
+            }
+        }
+    }
+}
+
+// Type variables are named 'a, 'b, ... in order of appearance, so equal types print the same.
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, &mut Vec::new())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TypeErrorKind {
+    Mismatch { expected: Type, found: Type },
+    UnboundVariable(String),
+    NotAFunction(Type),
+    ArityMismatch { expected: usize, found: usize },
+    /// `==` and `!=` only compare numbers and booleans.
+    NotComparable(Type),
+}
+
+impl fmt::Display for TypeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TypeErrorKind::Mismatch { expected, found } => write!(f, "expected {expected}, found {found}"),
+            TypeErrorKind::UnboundVariable(name) => write!(f, "unbound variable '{name}'"),
+            TypeErrorKind::NotAFunction(found) => write!(f, "called a value of type {found}, which is not a function"),
+            TypeErrorKind::ArityMismatch { expected, found } => {
+                write!(f, "function takes {expected} argument(s) but {found} were given")
+            }
+            TypeErrorKind::NotComparable(found) => write!(f, "values of type {found} cannot be compared"),
+        }
+    }
+}
+
+/// A type error at the node reached by following `path` from the root.
+///
+/// Each step of a path picks a child: `left` then `right`, `cond`, `then` then `otherwise`, `value` then `body`,
+/// a function's `body`, and a call's `callee` followed by its arguments. `Spanned` wrappers are not steps.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypeError {
+    pub kind: TypeErrorKind,
+    pub path: Vec<usize>,
+    /// Where the node is in the source, for trees from `parse_spanned`.
+    pub span: Option<Span>,
+}
+
+impl TypeError {
+    pub fn diagnostic(&self) -> Diagnostic {
+        Diagnostic::new(self.kind.to_string(), self.span)
+    }
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.span {
+            Some(span) => write!(f, "{} at offset {}", self.kind, span.start),
+            None => write!(f, "{} at path {:?}", self.kind, self.path),
+        }
+    }
+}
+
+impl std::error::Error for TypeError {}
+
+/// The type of every node of a well-typed expression, by path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Typing {
+    /// Each node's type and the indices of its children here, with the root first. Deep trees would make a map
+    /// from whole paths quadratic in size.
+    nodes: Vec<(Type, Vec<usize>)>,
+}
+
+impl Typing {
+    pub fn root(&self) -> &Type {
+        &self.nodes[0].0
+    }
+
+    pub fn at(&self, path: &[usize]) -> Option<&Type> {
+        let mut node = 0;
+        for &step in path {
+            node = *self.nodes[node].1.get(step)?;
+        }
+        Some(&self.nodes[node].0)
+    }
+
+    /// How many nodes were typed, not counting `Spanned` wrappers.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+}
+
+/// Infers a type for every node of `e`, or reports every type error in it.
+///
+/// Functions are monomorphic: a `let`-bound function used at two different types is an error.
+pub fn check<N>(e: &Expression<N>) -> Result<Typing, Vec<TypeError>> {
+    let mut checker = Checker { bindings: Vec::new(), scope: Vec::new(), nodes: Vec::new(), path: Vec::new(), errors: Vec::new() };
+    checker.infer(e);
+    if !checker.errors.is_empty() {
+        return Err(checker.errors);
+    }
+    let nodes = std::mem::take(&mut checker.nodes);
+    let nodes = nodes.into_iter().map(|node| (checker.resolve(&node.t.expect("every node is typed")), node.children)).collect();
+    Ok(Typing { nodes })
+}
+
+struct Node {
+    /// Which child of its parent it is.
+    index: usize,
+    children: Vec<usize>,
+    t: Option<Type>,
+}
+
+struct Checker<'a> {
+    /// What each type variable has been unified with so far.
+    bindings: Vec<Option<Type>>,
+    scope: Vec<(&'a str, Type)>,
+    nodes: Vec<Node>,
+    /// The nodes from the root down to the one being checked.
+    path: Vec<usize>,
+    errors: Vec<TypeError>,
+}
+
+impl<'a> Checker<'a> {
+    fn fresh(&mut self) -> Type {
+        self.bindings.push(None);
+        Type::Var(self.bindings.len() - 1)
+    }
+
+    // Follows bound variables until reaching a type constructor or an unbound variable.
+    fn shallow(&self, t: &Type) -> Type {
+        let mut t = t.clone();
+        while let Type::Var(id) = t {
+            match &self.bindings[id] {
+                Some(bound) => t = bound.clone(),
+                None => break,
+            }
+        }
+        t
+    }
+
+    fn resolve(&self, t: &Type) -> Type {
+        match self.shallow(t) {
+            Type::Function(params, result) => {
+                Type::Function(params.iter().map(|p| self.resolve(p)).collect(), Box::new(self.resolve(&result)))
+            }
+            t => t,
+        }
+    }
+
+    fn occurs(&self, id: usize, t: &Type) -> bool {
+        match self.shallow(t) {
+            Type::Var(other) => other == id,
+            Type::Function(params, result) => params.iter().any(|p| self.occurs(id, p)) || self.occurs(id, &result),
+            Type::Number | Type::Bool => false,
+        }
+    }
+
+    // Leaves the bindings untouched when the types do not unify.
+    fn unify(&mut self, a: &Type, b: &Type) -> bool {
+        let mut bound = Vec::new();
+        let unified = self.unify_inner(a, b, &mut bound);
+        if !unified {
+            for id in bound {
+                self.bindings[id] = None;
+            }
+        }
+        unified
+    }
+
+    // Records each variable it binds in `bound`, so a failed unification can be undone.
+    fn unify_inner(&mut self, a: &Type, b: &Type, bound: &mut Vec<usize>) -> bool {
+        match (self.shallow(a), self.shallow(b)) {
+            (Type::Var(x), Type::Var(y)) if x == y => true,
+            (Type::Var(id), t) | (t, Type::Var(id)) => {
+                if self.occurs(id, &t) {
+                    return false;
+                }
+                self.bindings[id] = Some(t);
+                bound.push(id);
+                true
+            }
+            (Type::Number, Type::Number) | (Type::Bool, Type::Bool) => true,
+            (Type::Function(p, r), Type::Function(q, s)) if p.len() == q.len() => {
+                p.iter().zip(&q).all(|(p, q)| self.unify_inner(p, q, bound)) && self.unify_inner(&r, &s, bound)
+            }
+            _ => false,
+        }
+    }
+
+    fn error(&mut self, kind: TypeErrorKind, span: Option<Span>) {
+        let path = self.path[1..].iter().map(|&node| self.nodes[node].index).collect();
+        self.errors.push(TypeError { kind, path, span });
+    }
+
+    // Records the type of the node being checked, as the result of its task.
+    fn finish(&mut self, results: &mut Vec<Type>, t: Type) {
+        let node = *self.path.last().expect("a node is being checked");
+        self.nodes[node].t = Some(t.clone());
+        results.push(t);
+    }
+
+    fn infer<N>(&mut self, e: &'a Expression<N>) -> Type {
+        let mut tasks = vec![Task::Infer(e, 0)];
+        let mut results = Vec::new();
+        while let Some(task) = tasks.pop() {
+            // the tasks to run next, in order
+            let mut next = Vec::new();
+            match task {
+                Task::Infer(e, index) => {
+                    let node = self.nodes.len();
+                    self.nodes.push(Node { index, children: Vec::new(), t: None });
+                    if let Some(&parent) = self.path.last() {
+                        self.nodes[parent].children.push(node);
+                    }
+                    self.path.push(node);
+                    self.infer_node(e, &mut next, &mut results);
+                }
+                Task::Leave => {
+                    self.path.pop();
+                }
+                Task::Expect(expected, span) => {
+                    let found = results.last().expect("the child's type");
+                    if !self.unify(&expected, found) {
+                        let kind = TypeErrorKind::Mismatch { expected: self.resolve(&expected), found: self.resolve(found) };
+                        self.error(kind, span);
+                    }
+                }
+                Task::CompareRight(right) => {
+                    let left_type = results.last().expect("the left side's type").clone();
+                    next.extend(child(1, right, Some(left_type)));
+                }
+                Task::Compared(span) => {
+                    let left_type = &results[results.len() - 2];
+                    if let t @ Type::Function(..) = self.resolve(left_type) {
+                        self.error(TypeErrorKind::NotComparable(t), span);
+                    }
+                    next.push(Task::Finish(2, Some(Type::Bool)));
+                }
+                Task::Otherwise(otherwise) => {
+                    let t = results.last().expect("the then branch's type").clone();
+                    next.extend(child(2, otherwise, Some(t.clone())));
+                    next.push(Task::Finish(3, Some(t)));
+                }
+                Task::LetBody(name, body, bound) => {
+                    let t = match bound {
+                        Some(t) => {
+                            self.scope.pop();
+                            t
+                        }
+                        None => results.last().expect("the value's type").clone(),
+                    };
+                    self.scope.push((name, t));
+                    next.extend(child(1, body, None));
+                    next.extend([Task::Unscope(self.scope.len() - 1), Task::Finish(2, None)]);
+                }
+                Task::CallArgs(args, span) => {
+                    let callee_type = results.last().expect("the callee's type").clone();
+                    match self.shallow(&callee_type) {
+                        Type::Function(params, result) => {
+                            if params.len() != args.len() {
+                                self.error(TypeErrorKind::ArityMismatch { expected: params.len(), found: args.len() }, span);
+                            }
+                            for (i, arg) in args.iter().enumerate() {
+                                next.extend(child(i + 1, arg, params.get(i).cloned()));
+                            }
+                            next.push(Task::Finish(args.len() + 1, Some(*result)));
+                        }
+                        Type::Var(_) => {
+                            for (i, arg) in args.iter().enumerate() {
+                                next.extend(child(i + 1, arg, None));
+                            }
+                            next.push(Task::CallUnknown(args.len(), span));
+                        }
+                        t => {
+                            self.error(TypeErrorKind::NotAFunction(self.resolve(&t)), span);
+                            for (i, arg) in args.iter().enumerate() {
+                                next.extend(child(i + 1, arg, None));
+                            }
+                            let result = self.fresh();
+                            next.push(Task::Finish(args.len() + 1, Some(result)));
+                        }
+                    }
+                }
+                Task::CallUnknown(arg_count, span) => {
+                    let params = results[results.len() - arg_count..].to_vec();
+                    let callee_type = &results[results.len() - arg_count - 1];
+                    let result = self.fresh();
+                    let expected = Type::Function(params, Box::new(result.clone()));
+                    if !self.unify(callee_type, &expected) {
+                        let kind = TypeErrorKind::Mismatch { expected: self.resolve(&expected), found: self.resolve(callee_type) };
+                        self.error(kind, span);
+                    }
+                    next.push(Task::Finish(arg_count + 1, Some(result)));
+                }
+                Task::Unscope(depth) => self.scope.truncate(depth),
+                Task::Finish(count, t) => {
+                    let children = results.split_off(results.len() - count);
+                    let t = t.unwrap_or_else(|| children.last().expect("the last child's type").clone());
+                    self.finish(&mut results, t);
+                }
+                Task::FinishFunction(params) => {
+                    let result = results.pop().expect("the body's type");
+                    self.finish(&mut results, Type::Function(params, Box::new(result)));
+                }
+            }
+            tasks.extend(next.into_iter().rev());
+        }
+        results.pop().expect("the root's type")
+    }
+
+    // Starts on a node: leaves get their type at once, and other nodes schedule their children and what follows.
+    fn infer_node<N>(&mut self, e: &'a Expression<N>, next: &mut Vec<Task<'a, N>>, results: &mut Vec<Type>) {
+        let span = e.span();
+        match e.unspanned() {
+            Expression::Op { op: Operation::Eq | Operation::Ne, left, right } => {
+                next.extend(child(0, left, None));
+                next.extend([Task::CompareRight(right), Task::Compared(span)]);
+            }
+            Expression::Op { op, left, right } => {
+                let operand = if matches!(op, Operation::And | Operation::Or) { Type::Bool } else { Type::Number };
+                next.extend(child(0, left, Some(operand.clone())));
+                next.extend(child(1, right, Some(operand)));
+                next.push(Task::Finish(2, Some(if op.is_boolean() { Type::Bool } else { Type::Number })));
+            }
+            Expression::Unary { op, operand } => {
+                let t = if *op == UnaryOperation::Not { Type::Bool } else { Type::Number };
+                next.extend(child(0, operand, Some(t.clone())));
+                next.push(Task::Finish(1, Some(t)));
+            }
+            Expression::If { cond, then, otherwise } => {
+                next.extend(child(0, cond, Some(Type::Bool)));
+                next.extend(child(1, then, None));
+                next.push(Task::Otherwise(otherwise));
+            }
+            Expression::Let { name, value, body } => {
+                // a function bound by `let` can call itself, so its name is in scope inside it
+                if matches!(value.unspanned(), Expression::Function { .. }) {
+                    let t = self.fresh();
+                    self.scope.push((name, t.clone()));
+                    next.extend(child(0, value, Some(t.clone())));
+                    next.push(Task::LetBody(name, body, Some(t)));
+                } else {
+                    next.extend(child(0, value, None));
+                    next.push(Task::LetBody(name, body, None));
+                }
+            }
+            Expression::Function { params, body } => {
+                let params: Vec<_> = params.iter().map(|p| (p.as_str(), self.fresh())).collect();
+                let types = params.iter().map(|(_, t)| t.clone()).collect();
+                let depth = self.scope.len();
+                self.scope.extend(params);
+                next.extend(child(0, body, None));
+                next.extend([Task::Unscope(depth), Task::FinishFunction(types)]);
+            }
+            Expression::Call { callee, args } => {
+                next.extend(child(0, callee, None));
+                next.push(Task::CallArgs(args, span));
+            }
+            Expression::Var(name) => {
+                let t = match self.scope.iter().rev().find(|(n, _)| n == name) {
+                    Some((_, t)) => t.clone(),
+                    None => {
+                        self.error(TypeErrorKind::UnboundVariable(name.clone()), span);
+                        self.fresh()
+                    }
+                };
+                self.finish(results, t);
+            }
+            Expression::Value(_) => self.finish(results, Type::Number),
+            Expression::Bool(_) => self.finish(results, Type::Bool),
+            Expression::Spanned { .. } => unreachable!("`unspanned` removes every wrapper"),
+        }
+    }
+}
+
+// Pending work for `Checker::infer`, which keeps its own stack instead of recursing, as `evaluate` does. Every node
+// leaves its type on a stack of results, and the tasks scheduled after its children replace theirs with it.
+enum Task<'a, N> {
+    /// Steps down into a node, the child of the one being checked with the given index, and starts on it.
+    Infer(&'a Expression<N>, usize),
+    Leave,
+    /// Checks the child's type against the type its parent needs, reporting the child if it does not fit.
+    Expect(Type, Option<Span>),
+    /// Checks the right side of `==` or `!=` against the type of the left.
+    CompareRight(&'a Expression<N>),
+    Compared(Option<Span>),
+    /// Checks the `else` branch against the type of the `then` branch.
+    Otherwise(&'a Expression<N>),
+    /// Brings the name of a `let` into scope for its body, with the type it was bound to beforehand, if any.
+    LetBody(&'a str, &'a Expression<N>, Option<Type>),
+    CallArgs(&'a [Expression<N>], Option<Span>),
+    /// Unifies a callee whose type was not known with a function taking the arguments' types.
+    CallUnknown(usize, Option<Span>),
+    Unscope(usize),
+    /// Replaces the types of a node's children with its own: the given type, or else that of its last child.
+    Finish(usize, Option<Type>),
+    FinishFunction(Vec<Type>),
+}
+
+// The tasks for a child, checking it against `expected` if its parent needs a particular type.
+fn child<N>(index: usize, e: &Expression<N>, expected: Option<Type>) -> Vec<Task<'_, N>> {
+    let mut tasks = vec![Task::Infer(e, index)];
+    tasks.extend(expected.map(|t| Task::Expect(t, e.span())));
+    tasks.push(Task::Leave);
+    tasks
+}
+
+#[cfg(test)]
+fn type_of(src: &str) -> String {
+    check(&crate::parser::parse(src).unwrap()).unwrap().root().to_string()
+}
+
+#[test]
+fn test_infer_types() {
+    assert_eq!(type_of("1 + 2 * 3"), "number");
+    assert_eq!(type_of("1 < 2 && !false"), "bool");
+    assert_eq!(type_of("if true then 1 else 2"), "number");
+    assert_eq!(type_of("fn(x) x"), "fn('a) -> 'a");
+    assert_eq!(type_of("fn(f, x) f(f(x) + 1)"), "fn(fn(number) -> number, number) -> number");
+    assert_eq!(type_of("let fact = fn(n) if n <= 1 then 1 else n * fact(n - 1) in fact"), "fn(number) -> number");
+    assert_eq!(type_of("let adder = fn(n) fn(x) x + n in adder(5)"), "fn(number) -> number");
+}
+
+#[test]
+fn test_every_node_is_typed() {
+    let typing = check(&crate::parser::parse_spanned("let f = fn(b) if b then 1 else 0 in f(true) == 1").unwrap()).unwrap();
+    assert_eq!(typing.len(), 11);
+    assert_eq!(typing.at(&[0]), Some(&Type::Function(vec![Type::Bool], Box::new(Type::Number))));
+    assert_eq!(typing.at(&[0, 0, 0]), Some(&Type::Bool));
+    assert_eq!(typing.at(&[1, 0]), Some(&Type::Number));
+    assert_eq!(typing.root(), &Type::Bool);
+}
+
+#[test]
+fn test_reports_all_errors_with_spans() {
+    let src = "(1 + true) * (if 2 then false else 3)";
+    let errors = check(&crate::parser::parse_spanned(src).unwrap()).unwrap_err();
+    let found: Vec<_> = errors.iter().map(|e| (e.to_string(), &src[e.span.unwrap().start..e.span.unwrap().end])).collect();
+    assert_eq!(
+        found,
+        [
+            (String::from("expected number, found bool at offset 5"), "true"),
+            (String::from("expected bool, found number at offset 17"), "2"),
+            (String::from("expected bool, found number at offset 35"), "3"),
+            (String::from("expected number, found bool at offset 14"), "if"),
+        ]
+    );
+}
+
+#[test]
+fn test_hand_built_trees() {
+    let expr: Expression = Expression::Op {
+        op: Operation::Add,
+        left: Box::new(Expression::Value(1)),
+        right: Box::new(Expression::Unary { op: UnaryOperation::Not, operand: Box::new(Expression::Var(String::from("x"))) }),
+    };
+    let errors = check(&expr).unwrap_err();
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[0].kind, TypeErrorKind::UnboundVariable(String::from("x")));
+    assert_eq!(errors[0].path, [1, 0]);
+    assert_eq!(errors[1].kind, TypeErrorKind::Mismatch { expected: Type::Number, found: Type::Bool });
+    assert_eq!(errors[1].to_string(), "expected number, found bool at path [1]");
+    assert!(check(&expr.map_values(&|v| *v as f64)).is_err());
+}
+
+#[test]
+fn test_function_errors() {
+    let kinds = |src: &str| check(&crate::parser::parse(src).unwrap()).unwrap_err().into_iter().map(|e| e.kind.to_string()).collect::<Vec<_>>();
+    assert_eq!(kinds("3(4)"), ["called a value of type number, which is not a function"]);
+    assert_eq!(kinds("(fn(a, b) a)(1)"), ["function takes 2 argument(s) but 1 were given"]);
+    assert_eq!(kinds("let id = fn(x) x in id(1) + id(true)"), ["expected number, found bool"]);
+    assert_eq!(kinds("fn(f) f(f)"), ["expected fn('a) -> 'b, found 'a"]);
+    assert_eq!(kinds("fn(x) x == fn(y) y"), ["values of type fn('a) -> 'a cannot be compared"]);
+}
+
+#[test]
+fn test_deep_trees() {
+    let mut expr = crate::expression::left_chain(300_000);
+    let typing = check(&expr).unwrap();
+    assert_eq!((typing.root(), typing.len()), (&Type::Number, 600_001));
+    assert_eq!(typing.at(&[0; 299_999]), Some(&Type::Number));
+    for _ in 0..50_000 {
+        let function = Expression::Function { params: vec![String::from("x")], body: Box::new(Expression::Var(String::from("x"))) };
+        expr = Expression::Let {
+            name: String::from("id"),
+            value: Box::new(function),
+            body: Box::new(Expression::Call { callee: Box::new(Expression::Var(String::from("id"))), args: vec![expr] }),
+        };
+    }
+    expr = Expression::Unary { op: UnaryOperation::Not, operand: Box::new(expr) };
+    let errors = check(&expr).unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].kind, TypeErrorKind::Mismatch { expected: Type::Bool, found: Type::Number });
+    assert_eq!(errors[0].path, [0]);
+}
@@ -0,0 +1,521 @@
+use std::collections::BTreeSet;
+
+use crate::expression::{Expression, Operation, UnaryOperation};
+
+/// One level of an `Expression`, with its children replaced by `T`.
+///
+/// Children are always listed in the same order: `left` then `right`, `cond`, `then` then `otherwise`,
+/// `value` then `body`, and a call's `callee` followed by its arguments.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Layer<'a, N, T> {
+    Op { op: Operation, left: T, right: T },
+    Unary { op: UnaryOperation, operand: T },
+    If { cond: T, then: T, otherwise: T },
+    Let { name: &'a str, value: T, body: T },
+    Function { params: &'a [String], body: T },
+    Call { callee: T, args: Vec<T> },
+    Var(&'a str),
+    Value(&'a N),
+    Bool(bool),
+}
+
+impl<'a, N, T> Layer<'a, N, T> {
+    /// Replaces each child, in order.
+    pub fn map<U>(self, mut f: impl FnMut(T) -> U) -> Layer<'a, N, U> {
+        match self {
+            Layer::Op { op, left, right } => {
+                let left = f(left);
+                Layer::Op { op, left, right: f(right) }
+            }
+            Layer::Unary { op, operand } => Layer::Unary { op, operand: f(operand) },
+            Layer::If { cond, then, otherwise } => {
+                let cond = f(cond);
+                let then = f(then);
+                Layer::If { cond, then, otherwise: f(otherwise) }
+            }
+            Layer::Let { name, value, body } => {
+                let value = f(value);
+                Layer::Let { name, value, body: f(body) }
+            }
+            Layer::Function { params, body } => Layer::Function { params, body: f(body) },
+            Layer::Call { callee, args } => {
+                let callee = f(callee);
+                Layer::Call { callee, args: args.into_iter().map(f).collect() }
+            }
+            Layer::Var(name) => Layer::Var(name),
+            Layer::Value(v) => Layer::Value(v),
+            Layer::Bool(b) => Layer::Bool(b),
+        }
+    }
+
+    pub fn into_children(self) -> Vec<T> {
+        match self {
+            Layer::Op { left, right, .. } => vec![left, right],
+            Layer::Unary { operand, .. } => vec![operand],
+            Layer::If { cond, then, otherwise } => vec![cond, then, otherwise],
+            Layer::Let { value, body, .. } => vec![value, body],
+            Layer::Function { body, .. } => vec![body],
+            Layer::Call { callee, mut args } => {
+                args.insert(0, callee);
+                args
+            }
+            Layer::Var(_) | Layer::Value(_) | Layer::Bool(_) => Vec::new(),
+        }
+    }
+}
+
+/// One level of an `Expression` taken apart by `Expression::into_fold`, owning its names and value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OwnedLayer<N, T> {
+    Op { op: Operation, left: T, right: T },
+    Unary { op: UnaryOperation, operand: T },
+    If { cond: T, then: T, otherwise: T },
+    Let { name: String, value: T, body: T },
+    Function { params: Vec<String>, body: T },
+    Call { callee: T, args: Vec<T> },
+    Var(String),
+    Value(N),
+    Bool(bool),
+}
+
+impl<N, T> OwnedLayer<N, T> {
+    /// Replaces each child, in order.
+    pub fn map<U>(self, mut f: impl FnMut(T) -> U) -> OwnedLayer<N, U> {
+        match self {
+            OwnedLayer::Op { op, left, right } => {
+                let left = f(left);
+                OwnedLayer::Op { op, left, right: f(right) }
+            }
+            OwnedLayer::Unary { op, operand } => OwnedLayer::Unary { op, operand: f(operand) },
+            OwnedLayer::If { cond, then, otherwise } => {
+                let cond = f(cond);
+                let then = f(then);
+                OwnedLayer::If { cond, then, otherwise: f(otherwise) }
+            }
+            OwnedLayer::Let { name, value, body } => {
+                let value = f(value);
+                OwnedLayer::Let { name, value, body: f(body) }
+            }
+            OwnedLayer::Function { params, body } => OwnedLayer::Function { params, body: f(body) },
+            OwnedLayer::Call { callee, args } => {
+                let callee = f(callee);
+                OwnedLayer::Call { callee, args: args.into_iter().map(f).collect() }
+            }
+            OwnedLayer::Var(name) => OwnedLayer::Var(name),
+            OwnedLayer::Value(v) => OwnedLayer::Value(v),
+            OwnedLayer::Bool(b) => OwnedLayer::Bool(b),
+        }
+    }
+
+    pub fn into_children(self) -> Vec<T> {
+        match self {
+            OwnedLayer::Op { left, right, .. } => vec![left, right],
+            OwnedLayer::Unary { operand, .. } => vec![operand],
+            OwnedLayer::If { cond, then, otherwise } => vec![cond, then, otherwise],
+            OwnedLayer::Let { value, body, .. } => vec![value, body],
+            OwnedLayer::Function { body, .. } => vec![body],
+            OwnedLayer::Call { callee, mut args } => {
+                args.insert(0, callee);
+                args
+            }
+            OwnedLayer::Var(_) | OwnedLayer::Value(_) | OwnedLayer::Bool(_) => Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    /// Each node before its children.
+    Pre,
+    /// Each node after its children.
+    Post,
+}
+
+/// What a `Visitor` wants to happen after entering a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Walk {
+    Continue,
+    /// Do not visit this node's children; its `leave` is still called.
+    SkipChildren,
+    /// End the walk immediately.
+    Stop,
+}
+
+/// Callbacks for `walk`, before (`enter`) and after (`leave`) each node's children.
+pub trait Visitor<N> {
+    fn enter(&mut self, _e: &Expression<N>) -> Walk {
+        Walk::Continue
+    }
+
+    fn leave(&mut self, _e: &Expression<N>) {}
+}
+
+/// Walks `e` depth-first without recursing, returning false if the visitor stopped it early.
+///
+/// `Spanned` wrappers are not visited separately: the visitor sees the wrapper and can ask it for its `span`.
+pub fn walk<N>(e: &Expression<N>, visitor: &mut impl Visitor<N>) -> bool {
+    let mut pending = vec![(e, false)];
+    while let Some((e, left)) = pending.pop() {
+        if left {
+            visitor.leave(e);
+            continue;
+        }
+        let walk = visitor.enter(e);
+        if walk == Walk::Stop {
+            return false;
+        }
+        pending.push((e, true));
+        if walk == Walk::Continue {
+            pending.extend(e.children().into_iter().rev().map(|child| (child, false)));
+        }
+    }
+    true
+}
+
+/// The nodes of an expression in pre- or post-order, from `Expression::nodes`.
+pub struct Nodes<'a, N> {
+    order: Order,
+    /// Nodes still to be yielded, with whether their children have been pushed already.
+    pending: Vec<(&'a Expression<N>, bool)>,
+}
+
+impl<'a, N> Iterator for Nodes<'a, N> {
+    type Item = &'a Expression<N>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (e, expanded) = self.pending.pop()?;
+            match self.order {
+                Order::Pre => {
+                    self.pending.extend(e.children().into_iter().rev().map(|child| (child, false)));
+                    return Some(e);
+                }
+                Order::Post if expanded => return Some(e),
+                Order::Post => {
+                    self.pending.push((e, true));
+                    self.pending.extend(e.children().into_iter().rev().map(|child| (child, false)));
+                }
+            }
+        }
+    }
+}
+
+impl<N> Expression<N> {
+    /// This node, under any `Spanned` wrappers, with references to its children.
+    pub fn layer(&self) -> Layer<'_, N, &Expression<N>> {
+        match self.unspanned() {
+            Expression::Op { op, left, right } => Layer::Op { op: *op, left, right },
+            Expression::Unary { op, operand } => Layer::Unary { op: *op, operand },
+            Expression::If { cond, then, otherwise } => Layer::If { cond, then, otherwise },
+            Expression::Let { name, value, body } => Layer::Let { name, value, body },
+            Expression::Function { params, body } => Layer::Function { params, body },
+            Expression::Call { callee, args } => Layer::Call { callee, args: args.iter().collect() },
+            Expression::Var(name) => Layer::Var(name),
+            Expression::Value(v) => Layer::Value(v),
+            Expression::Bool(b) => Layer::Bool(*b),
+            Expression::Spanned { .. } => unreachable!("`unspanned` removes every wrapper"),
+        }
+    }
+
+    pub fn children(&self) -> Vec<&Expression<N>> {
+        self.layer().into_children()
+    }
+
+    /// The direct children, where a `Spanned` wrapper's only child is the expression it wraps.
+    pub fn children_mut(&mut self) -> Vec<&mut Expression<N>> {
+        match self {
+            Expression::Op { left, right, .. } => vec![left, right],
+            Expression::Unary { operand, .. } => vec![operand],
+            Expression::If { cond, then, otherwise } => vec![cond, then, otherwise],
+            Expression::Let { value, body, .. } => vec![value, body],
+            Expression::Function { body, .. } | Expression::Spanned { inner: body, .. } => vec![body],
+            Expression::Call { callee, args } => std::iter::once(&mut **callee).chain(args.iter_mut()).collect(),
+            Expression::Var(_) | Expression::Value(_) | Expression::Bool(_) => Vec::new(),
+        }
+    }
+
+    pub fn nodes(&self, order: Order) -> Nodes<'_, N> {
+        Nodes { order, pending: vec![(self, false)] }
+    }
+
+    /// Combines the results for each node's children into a result for the node, bottom-up.
+    pub fn fold<'a, T>(&'a self, mut f: impl FnMut(Layer<'a, N, T>) -> T) -> T {
+        let mut results = Vec::new();
+        for e in self.nodes(Order::Post) {
+            let count = e.children().len();
+            let mut children = results.split_off(results.len() - count).into_iter();
+            results.push(f(e.layer().map(|_| children.next().expect("one result per child"))));
+        }
+        results.pop().expect("the root has a result")
+    }
+
+    /// Like `fold`, but consumes the tree and hands `f` each node's names and value rather than references to them.
+    /// `Spanned` wrappers are skipped, as in `fold`.
+    ///
+    /// `Expression`'s `Drop` impl means nothing can be moved out of a node, so children are swapped for
+    /// `Bool(false)` and names for empty ones, and values are read out of leaves that are then never dropped.
+    pub fn into_fold<T>(self, mut f: impl FnMut(OwnedLayer<N, T>) -> T) -> T {
+        enum Step<N> {
+            Enter(Expression<N>),
+            Leave(OwnedLayer<N, ()>, usize),
+        }
+        let mut steps = vec![Step::Enter(self)];
+        let mut done = Vec::new();
+        while let Some(step) = steps.pop() {
+            match step {
+                Step::Enter(mut e) => {
+                    let children: Vec<_> =
+                        e.children_mut().into_iter().map(|c| std::mem::replace(c, Expression::Bool(false))).collect();
+                    let layer = match &mut e {
+                        Expression::Op { op, .. } => OwnedLayer::Op { op: *op, left: (), right: () },
+                        Expression::Unary { op, .. } => OwnedLayer::Unary { op: *op, operand: () },
+                        Expression::If { .. } => OwnedLayer::If { cond: (), then: (), otherwise: () },
+                        Expression::Let { name, .. } => OwnedLayer::Let { name: std::mem::take(name), value: (), body: () },
+                        Expression::Function { params, .. } => OwnedLayer::Function { params: std::mem::take(params), body: () },
+                        Expression::Call { args, .. } => OwnedLayer::Call { callee: (), args: vec![(); args.len()] },
+                        Expression::Var(name) => OwnedLayer::Var(std::mem::take(name)),
+                        Expression::Value(_) => {
+                            let leaf = std::mem::ManuallyDrop::new(e);
+                            let Expression::Value(v) = &*leaf else { unreachable!("matched a value") };
+                            // SAFETY: a `Value` node owns nothing but `v`, and `leaf` is never dropped or used
+                            // again, so `v` is moved out exactly once.
+                            OwnedLayer::Value(unsafe { std::ptr::read(v) })
+                        }
+                        Expression::Bool(b) => OwnedLayer::Bool(*b),
+                        Expression::Spanned { .. } => {
+                            steps.extend(children.into_iter().map(Step::Enter));
+                            continue;
+                        }
+                    };
+                    steps.push(Step::Leave(layer, children.len()));
+                    steps.extend(children.into_iter().rev().map(Step::Enter));
+                }
+                Step::Leave(layer, count) => {
+                    let mut children = done.split_off(done.len() - count).into_iter();
+                    done.push(f(layer.map(|()| children.next().expect("one result per child"))));
+                }
+            }
+        }
+        done.pop().expect("the root is folded last")
+    }
+
+    /// Rebuilds the tree bottom-up, replacing every node, `Spanned` wrappers included, with `f` of it.
+    /// Each node is passed to `f` with its children already replaced.
+    pub fn transform_up(self, mut f: impl FnMut(Expression<N>) -> Expression<N>) -> Expression<N> {
+        self.transform(&mut |e| e, &mut f)
+    }
+
+    /// Rebuilds the tree top-down: `f` sees each node before its children, and the children of whatever it
+    /// returns are transformed next.
+    pub fn transform_down(self, mut f: impl FnMut(Expression<N>) -> Expression<N>) -> Expression<N> {
+        self.transform(&mut f, &mut |e| e)
+    }
+
+    /// Applies `rule` bottom-up, repeating it at each node until it returns `None`.
+    ///
+    /// The children of a node produced by `rule` are not revisited.
+    pub fn rewrite(self, mut rule: impl FnMut(&Expression<N>) -> Option<Expression<N>>) -> Expression<N> {
+        self.transform_up(|mut e| {
+            while let Some(next) = rule(&e) {
+                e = next;
+            }
+            e
+        })
+    }
+
+    // Detaches each node's children, transforms them, and puts them back, all on a heap stack.
+    fn transform(
+        self,
+        pre: &mut impl FnMut(Expression<N>) -> Expression<N>,
+        post: &mut impl FnMut(Expression<N>) -> Expression<N>,
+    ) -> Expression<N> {
+        enum Step<N> {
+            Enter(Expression<N>),
+            Leave(Expression<N>, usize),
+        }
+        let mut steps = vec![Step::Enter(self)];
+        let mut done = Vec::new();
+        while let Some(step) = steps.pop() {
+            match step {
+                Step::Enter(e) => {
+                    let mut e = pre(e);
+                    let children: Vec<_> =
+                        e.children_mut().into_iter().map(|c| std::mem::replace(c, Expression::Bool(false))).collect();
+                    steps.push(Step::Leave(e, children.len()));
+                    steps.extend(children.into_iter().rev().map(Step::Enter));
+                }
+                Step::Leave(mut e, count) => {
+                    let children = done.split_off(done.len() - count);
+                    for (slot, child) in e.children_mut().into_iter().zip(children) {
+                        *slot = child;
+                    }
+                    done.push(post(e));
+                }
+            }
+        }
+        done.pop().expect("the root is transformed last")
+    }
+
+    /// Detaches and returns the direct children, as listed by `children_mut`.
+    pub fn into_children(mut self) -> Vec<Expression<N>> {
+        self.children_mut().into_iter().map(|c| std::mem::replace(c, Expression::Bool(false))).collect()
+    }
+
+    /// How many nodes the tree has, not counting `Spanned` wrappers.
+    pub fn node_count(&self) -> usize {
+        self.nodes(Order::Pre).count()
+    }
+
+    /// The number of nodes on the longest path from the root to a leaf.
+    pub fn depth(&self) -> usize {
+        self.fold(|layer| layer.into_children().into_iter().max().unwrap_or(0) + 1)
+    }
+
+    /// The variables used but not bound inside the expression.
+    pub fn free_variables(&self) -> BTreeSet<&str> {
+        self.fold(|layer: Layer<'_, N, BTreeSet<&str>>| match layer {
+            Layer::Var(name) => BTreeSet::from([name]),
+            Layer::Let { name, value, mut body } => {
+                body.remove(name);
+                body.extend(value);
+                body
+            }
+            Layer::Function { params, mut body } => {
+                body.retain(|name| !params.iter().any(|p| p == name));
+                body
+            }
+            layer => layer.into_children().into_iter().flatten().collect(),
+        })
+    }
+}
+
+#[test]
+fn test_traversal_orders() {
+    let expr = crate::parser::parse("1 + 2 * -3").unwrap();
+    let show = |order| expr.nodes(order).map(|e| match e.layer() {
+        Layer::Op { op, .. } => op.symbol().to_string(),
+        Layer::Unary { op, .. } => format!("u{}", op.symbol()),
+        _ => e.to_string(),
+    }).collect::<Vec<_>>();
+    assert_eq!(show(Order::Pre), ["+", "1", "*", "2", "-3"]);
+    assert_eq!(show(Order::Post), ["1", "2", "-3", "*", "+"]);
+    let expr = crate::parser::parse_spanned("f(x, -y)").unwrap();
+    assert_eq!(expr.nodes(Order::Post).map(|e| e.to_string()).collect::<Vec<_>>(), ["f", "x", "y", "-y", "f(x, -y)"]);
+}
+
+#[test]
+fn test_analyses() {
+    let expr = crate::parser::parse("let f = fn(a) a + b * c in f(d) + a").unwrap();
+    assert_eq!(expr.node_count(), 12);
+    assert_eq!(expr.depth(), 5);
+    assert_eq!(expr.free_variables(), BTreeSet::from(["a", "b", "c", "d"]));
+    assert_eq!(crate::parser::parse_spanned("x * (y - x)").unwrap().free_variables(), BTreeSet::from(["x", "y"]));
+}
+
+#[test]
+fn test_into_fold() {
+    use crate::bigint::BigInt;
+    // moves every name and value into the rebuilt tree
+    fn rebuild<N>(layer: OwnedLayer<N, Expression<N>>) -> Expression<N> {
+        match layer {
+            OwnedLayer::Op { op, left, right } => Expression::Op { op, left: Box::new(left), right: Box::new(right) },
+            OwnedLayer::Unary { op, operand } => Expression::Unary { op, operand: Box::new(operand) },
+            OwnedLayer::If { cond, then, otherwise } => Expression::If { cond: Box::new(cond), then: Box::new(then), otherwise: Box::new(otherwise) },
+            OwnedLayer::Let { name, value, body } => Expression::Let { name, value: Box::new(value), body: Box::new(body) },
+            OwnedLayer::Function { params, body } => Expression::Function { params, body: Box::new(body) },
+            OwnedLayer::Call { callee, args } => Expression::Call { callee: Box::new(callee), args },
+            OwnedLayer::Var(name) => Expression::Var(name),
+            OwnedLayer::Value(v) => Expression::Value(v),
+            OwnedLayer::Bool(b) => Expression::Bool(b),
+        }
+    }
+    let source = "let f = fn(a, b) if a < b then -a else b in f(x, 2) * (true || 3)";
+    assert_eq!(crate::parser::parse_spanned(source).unwrap().into_fold(rebuild), crate::parser::parse(source).unwrap());
+
+    let expr = crate::parser::parse("1000000000000 + 2000000000000 * (3000000000000 - 4000000000000)").unwrap().map_values(&|&v| BigInt::from(v));
+    let mut values = Vec::new();
+    expr.into_fold(|layer| {
+        if let OwnedLayer::Value(v) = layer {
+            values.push(v);
+        }
+    });
+    assert_eq!(values.iter().map(|v| v.to_string()).collect::<Vec<_>>(), ["1000000000000", "2000000000000", "3000000000000", "4000000000000"]);
+
+    // values need no default
+    let expr = crate::parser::parse("1 + 2 * x").unwrap().map_values(&|&v| crate::number::Rational::new(v, 3).unwrap());
+    assert_eq!(expr.into_fold(rebuild).to_string(), "1/3 + 2/3 * x");
+}
+
+#[test]
+fn test_visitor() {
+    // counts operators outside of function bodies, stopping at the first call
+    struct Counter(usize);
+    impl Visitor<i64> for Counter {
+        fn enter(&mut self, e: &Expression) -> Walk {
+            match e.layer() {
+                Layer::Function { .. } => Walk::SkipChildren,
+                Layer::Call { .. } => Walk::Stop,
+                Layer::Op { .. } => {
+                    self.0 += 1;
+                    Walk::Continue
+                }
+                _ => Walk::Continue,
+            }
+        }
+    }
+    let mut counter = Counter(0);
+    assert!(walk(&crate::parser::parse("1 + (fn(x) x * x) - 2 * 3").unwrap(), &mut counter));
+    assert_eq!(counter.0, 3);
+    let mut counter = Counter(0);
+    assert!(!walk(&crate::parser::parse("1 + 2 - f(3 + 4)").unwrap(), &mut counter));
+    assert_eq!(counter.0, 2);
+}
+
+#[test]
+fn test_rewrites() {
+    let expr = crate::parser::parse("(x + 0) * (0 + (y + 0))").unwrap();
+    let rewritten = expr.rewrite(|e| match e {
+        Expression::Op { op: Operation::Add, left, right } if **right == Expression::Value(0) => Some((**left).clone()),
+        Expression::Op { op: Operation::Add, left, right } if **left == Expression::Value(0) => Some((**right).clone()),
+        _ => None,
+    });
+    assert_eq!(rewritten.to_string(), "x * y");
+
+    let renamed = crate::parser::parse_spanned("x + f(x)").unwrap().transform_up(|e| match e {
+        Expression::Var(ref name) if name == "x" => Expression::Var(String::from("z")),
+        Expression::Spanned { .. } => e.into_children().remove(0),
+        e => e,
+    });
+    assert_eq!(renamed, crate::parser::parse("z + f(z)").unwrap());
+
+    let expr = crate::parser::parse("1 + 2 * 3").unwrap();
+    let mut seen = Vec::new();
+    let expr = expr.transform_down(|e| {
+        seen.push(e.to_string());
+        e
+    });
+    assert_eq!(seen, ["1 + 2 * 3", "1", "2 * 3", "2", "3"]);
+    seen.clear();
+    expr.transform_up(|e| {
+        seen.push(e.to_string());
+        e
+    });
+    assert_eq!(seen, ["1", "2", "3", "2 * 3", "1 + 2 * 3"]);
+}
+
+#[test]
+fn test_deep_traversals() {
+    let mut expr = Expression::Value(1);
+    for _ in 0..1_000_000 {
+        expr = Expression::Unary { op: UnaryOperation::Neg, operand: Box::new(expr) };
+    }
+    assert_eq!(expr.depth(), 1_000_001);
+    let expr = expr.transform_up(|e| match e {
+        Expression::Unary { op: UnaryOperation::Neg, .. } => {
+            Expression::Unary { op: UnaryOperation::Not, operand: Box::new(e.into_children().remove(0)) }
+        }
+        e => e,
+    });
+    assert_eq!(expr.nodes(Order::Post).filter(|e| matches!(e.layer(), Layer::Unary { op: UnaryOperation::Not, .. })).count(), 1_000_000);
+    assert_eq!(expr.into_fold(|layer| layer.map(|depth| depth + 1).into_children().into_iter().max().unwrap_or(1)), 1_000_001);
+}